@@ -0,0 +1,242 @@
+// Buckets particles into a uniform grid:
+// `clear_counts` -> `count_cells` -> `prefix_sum` give every cell its range,
+// then a radix sort orders the particles by cell. Afterwards
+// `sorted_indices[cell_start[c]..cell_start[c + 1]]` holds the particles in
+// cell `c` by ascending index, which `compute.wgsl` walks instead of all
+// particles. Worlds of a single cell skip all of it.
+
+struct GridUniforms {
+    cells_x: u32,
+    cells_y: u32,
+    cell_size_x: f32,
+    cell_size_y: f32,
+}
+
+@group(0) @binding(0) var<storage, read> positions: array<vec2f>;
+@group(0) @binding(1) var<storage, read_write> cell_counts: array<atomic<u32>>;
+@group(0) @binding(2) var<storage, read_write> cell_start: array<u32>;
+@group(0) @binding(3) var<storage, read_write> particle_cells: array<u32>;
+@group(0) @binding(4) var<storage, read_write> digit_offsets: array<u32>;
+
+@group(1) @binding(0) var<uniform> grid: GridUniforms;
+
+// The sort passes alternate between two index buffers, so that the last one
+// writes `sorted_indices`.
+@group(2) @binding(0) var<storage, read> from_indices: array<u32>;
+@group(2) @binding(1) var<storage, read_write> to_indices: array<u32>;
+
+// Lowest bit of the digit a sort pass orders by.
+struct RadixUniforms {
+    shift: u32,
+}
+@group(3) @binding(0) var<uniform> radix: RadixUniforms;
+
+// Absorbed particles are parked here and left out of every cell. Has to match
+// `ABSORBED` in compute.wgsl.
+const ABSORBED: f32 = -1.0e30;
+// Has to match `RADIX_BITS` in wgpu_scene.rs.
+const RADIX_BITS: u32 = 4;
+const RADIX: u32 = 1u << RADIX_BITS;
+const BLOCK_SIZE: u32 = 64;
+
+// Dispatches too large for one dimension continue in further rows of y.
+fn flat_index(id: vec3<u32>, groups: vec3<u32>) -> u32 {
+    return id.y * groups.x * 64u + id.x;
+}
+
+fn cell_of(pos: vec2f) -> u32 {
+    let x = min(u32(max(floor(pos.x / grid.cell_size_x), 0.0)), grid.cells_x - 1);
+    let y = min(u32(max(floor(pos.y / grid.cell_size_y), 0.0)), grid.cells_y - 1);
+    return y * grid.cells_x + x;
+}
+
+@compute
+@workgroup_size(64, 1, 1)
+fn clear_counts(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(num_workgroups) groups: vec3<u32>,
+) {
+    let cell = flat_index(id, groups);
+    if cell < grid.cells_x * grid.cells_y {
+        atomicStore(&cell_counts[cell], 0u);
+    }
+}
+
+// Absorbed particles get the key one past the last cell, which sorts them
+// behind every cell.
+@compute
+@workgroup_size(64, 1, 1)
+fn count_cells(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(num_workgroups) groups: vec3<u32>,
+) {
+    let index = flat_index(id, groups);
+    if index >= arrayLength(&positions) {
+        return;
+    }
+    if positions[index].x <= ABSORBED {
+        particle_cells[index] = grid.cells_x * grid.cells_y;
+        return;
+    }
+    let cell = cell_of(positions[index]);
+    particle_cells[index] = cell;
+    atomicAdd(&cell_counts[cell], 1u);
+}
+
+const SCAN_THREADS: u32 = 256;
+var<workgroup> chunk_sums: array<u32, SCAN_THREADS>;
+
+// Turns the `sum` of every thread's chunk into the total of the chunks before
+// it. Every thread of the workgroup has to call it.
+fn scan_chunk_sums(thread: u32, sum: u32) -> u32 {
+    chunk_sums[thread] = sum;
+    workgroupBarrier();
+
+    // Hillis-Steele inclusive scan of the chunk totals.
+    for (var offset = 1u; offset < SCAN_THREADS; offset *= 2u) {
+        var value = chunk_sums[thread];
+        if thread >= offset {
+            value += chunk_sums[thread - offset];
+        }
+        workgroupBarrier();
+        chunk_sums[thread] = value;
+        workgroupBarrier();
+    }
+    return chunk_sums[thread] - sum;
+}
+
+// Exclusive scan of `cell_counts` into `cell_start` by a single workgroup.
+// Every thread scans a contiguous chunk of cells, the chunk totals are scanned
+// in workgroup memory, then each chunk is offset.
+@compute
+@workgroup_size(SCAN_THREADS, 1, 1)
+fn prefix_sum(@builtin(local_invocation_index) thread: u32) {
+    let cell_total = grid.cells_x * grid.cells_y;
+    let chunk = (cell_total + SCAN_THREADS - 1) / SCAN_THREADS;
+    let begin = min(thread * chunk, cell_total);
+    let end = min(begin + chunk, cell_total);
+
+    var sum = 0u;
+    for (var c = begin; c < end; c++) {
+        sum += atomicLoad(&cell_counts[c]);
+    }
+    var running = scan_chunk_sums(thread, sum);
+    for (var c = begin; c < end; c++) {
+        cell_start[c] = running;
+        running += atomicLoad(&cell_counts[c]);
+    }
+    if thread == SCAN_THREADS - 1 {
+        cell_start[cell_total] = running;
+    }
+}
+
+// The rest is a least significant digit radix sort of the particle indexes by
+// `particle_cells`, `RADIX_BITS` at a time:
+// `count_digits` -> `scan_digits` -> `scatter_digits` per digit.
+// Every pass is stable and the first one starts from index order, so each
+// cell ends up holding its particles by ascending index. `compute.wgsl` then
+// sums the forces in the same order on every run.
+
+fn block_count() -> u32 {
+    return (arrayLength(&positions) + BLOCK_SIZE - 1) / BLOCK_SIZE;
+}
+
+// Particle at `position` in the order of the previous pass.
+fn particle_at(position: u32) -> u32 {
+    if radix.shift == 0u {
+        return position;
+    }
+    return from_indices[position];
+}
+
+fn digit_of(particle: u32) -> u32 {
+    return (particle_cells[particle] >> radix.shift) & (RADIX - 1u);
+}
+
+var<workgroup> block_digit_counts: array<atomic<u32>, RADIX>;
+
+// Counts the digits in every block of `BLOCK_SIZE` particles into
+// `digit_offsets[digit * block_count() + block]`.
+@compute
+@workgroup_size(BLOCK_SIZE, 1, 1)
+fn count_digits(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(num_workgroups) groups: vec3<u32>,
+    @builtin(local_invocation_index) thread: u32,
+) {
+    if thread < RADIX {
+        atomicStore(&block_digit_counts[thread], 0u);
+    }
+    workgroupBarrier();
+    let position = flat_index(id, groups);
+    if position < arrayLength(&positions) {
+        atomicAdd(&block_digit_counts[digit_of(particle_at(position))], 1u);
+    }
+    workgroupBarrier();
+    let block = position / BLOCK_SIZE;
+    let blocks = block_count();
+    if thread < RADIX && block < blocks {
+        digit_offsets[thread * blocks + block] = atomicLoad(&block_digit_counts[thread]);
+    }
+}
+
+// Exclusive scan of `digit_offsets` in place by a single workgroup. Being
+// digit-major, each entry becomes the slot of the first particle of its block
+// with that digit.
+@compute
+@workgroup_size(SCAN_THREADS, 1, 1)
+fn scan_digits(@builtin(local_invocation_index) thread: u32) {
+    let total = RADIX * block_count();
+    let chunk = (total + SCAN_THREADS - 1) / SCAN_THREADS;
+    let begin = min(thread * chunk, total);
+    let end = min(begin + chunk, total);
+
+    var sum = 0u;
+    for (var i = begin; i < end; i++) {
+        sum += digit_offsets[i];
+    }
+    var running = scan_chunk_sums(thread, sum);
+    for (var i = begin; i < end; i++) {
+        let count = digit_offsets[i];
+        digit_offsets[i] = running;
+        running += count;
+    }
+}
+
+// Bit `t % 32` of word `digit * 2 + t / 32` is set when thread `t` of the
+// block holds `digit`.
+var<workgroup> digit_masks: array<atomic<u32>, RADIX * 2>;
+
+// Writes every particle behind the ones of its block with the same digit that
+// come before it, keeping the order of the previous pass.
+@compute
+@workgroup_size(BLOCK_SIZE, 1, 1)
+fn scatter_digits(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(num_workgroups) groups: vec3<u32>,
+    @builtin(local_invocation_index) thread: u32,
+) {
+    if thread < RADIX * 2 {
+        atomicStore(&digit_masks[thread], 0u);
+    }
+    workgroupBarrier();
+    let position = flat_index(id, groups);
+    let in_range = position < arrayLength(&positions);
+    var particle = 0u;
+    var digit = 0u;
+    if in_range {
+        particle = particle_at(position);
+        digit = digit_of(particle);
+        atomicOr(&digit_masks[digit * 2u + thread / 32u], 1u << (thread % 32u));
+    }
+    workgroupBarrier();
+    if in_range {
+        let earlier = (1u << (thread % 32u)) - 1u;
+        var rank = countOneBits(atomicLoad(&digit_masks[digit * 2u + thread / 32u]) & earlier);
+        if thread >= 32u {
+            rank += countOneBits(atomicLoad(&digit_masks[digit * 2u]));
+        }
+        let block = position / BLOCK_SIZE;
+        to_indices[digit_offsets[digit * block_count() + block] + rank] = particle;
+    }
+}
@@ -0,0 +1,125 @@
+//! What happens to particles at the edges of the world.
+
+use clap::ValueEnum;
+use graphics::math::Vec2d;
+use serde::{Deserialize, Serialize};
+
+use crate::vector::{minimum_image, wrap};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Boundary {
+    /// The world is a torus: particles leaving one edge enter at the
+    /// opposite one, and forces act across the edges.
+    #[default]
+    Periodic,
+    /// Particles bounce off the walls, keeping `restitution` of their normal
+    /// velocity.
+    Reflective,
+    /// Particles leaving the world are removed from the scene.
+    Absorbing,
+    /// No walls. The world size only places the initial particles.
+    Open,
+}
+
+impl Boundary {
+    /// Turns a difference of two positions into the one forces act along.
+    /// Only periodic worlds wrap it.
+    #[inline(always)]
+    pub fn minimum_image<'a>(&self, v: &'a mut Vec2d, screen_size: &Vec2d) -> &'a mut Vec2d {
+        if *self == Boundary::Periodic {
+            minimum_image(v, screen_size);
+        }
+        return v;
+    }
+
+    /// Moves a particle that may have left the world back in: wrapped for
+    /// periodic worlds, mirrored at the wall for reflective ones. Absorbed
+    /// particles are left outside, see [`Self::absorbs`].
+    pub fn confine(&self, pos: &mut Vec2d, vel: &mut Vec2d, screen_size: &Vec2d, restitution: f64) {
+        match self {
+            Boundary::Periodic => {
+                wrap(pos, screen_size);
+            }
+            Boundary::Reflective => {
+                for axis in 0..2 {
+                    if pos[axis] < 0.0 {
+                        pos[axis] = -pos[axis];
+                        vel[axis] = -vel[axis] * restitution;
+                    } else if pos[axis] > screen_size[axis] {
+                        pos[axis] = 2.0 * screen_size[axis] - pos[axis];
+                        vel[axis] = -vel[axis] * restitution;
+                    }
+                    // Fast particles can overshoot the opposite wall.
+                    pos[axis] = pos[axis].clamp(0.0, screen_size[axis]);
+                }
+            }
+            Boundary::Absorbing | Boundary::Open => {}
+        }
+    }
+
+    /// Whether a particle at `pos` has to be removed from the scene.
+    pub fn absorbs(&self, pos: &Vec2d, screen_size: &Vec2d) -> bool {
+        return *self == Boundary::Absorbing
+            && (0..2).any(|axis| !(0.0..screen_size[axis]).contains(&pos[axis]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: Vec2d = [400.0, 300.0];
+
+    fn confined(boundary: Boundary, pos: Vec2d, vel: Vec2d) -> (Vec2d, Vec2d) {
+        let (mut pos, mut vel) = (pos, vel);
+        boundary.confine(&mut pos, &mut vel, &SIZE, 0.5);
+        return (pos, vel);
+    }
+
+    #[test]
+    fn periodic_worlds_wrap() {
+        assert_eq!(
+            confined(Boundary::Periodic, [-10.0, 310.0], [-1.0, 1.0]),
+            ([390.0, 10.0], [-1.0, 1.0])
+        );
+        // More than a world away still lands inside.
+        assert_eq!(
+            confined(Boundary::Periodic, [-410.0, 610.0], [0.0, 0.0]).0,
+            [390.0, 10.0]
+        );
+        let mut difference = [390.0, -290.0];
+        Boundary::Periodic.minimum_image(&mut difference, &SIZE);
+        assert_eq!(difference, [-10.0, 10.0]);
+    }
+
+    #[test]
+    fn reflective_walls_mirror_and_damp() {
+        assert_eq!(
+            confined(Boundary::Reflective, [-10.0, 310.0], [-2.0, 4.0]),
+            ([10.0, 290.0], [1.0, -2.0])
+        );
+        // Overshooting the opposite wall stops at it.
+        assert_eq!(
+            confined(Boundary::Reflective, [-500.0, 150.0], [-2.0, 0.0]).0,
+            [400.0, 150.0]
+        );
+        let mut difference = [390.0, -290.0];
+        Boundary::Reflective.minimum_image(&mut difference, &SIZE);
+        assert_eq!(difference, [390.0, -290.0]);
+    }
+
+    #[test]
+    fn only_absorbing_worlds_remove_particles() {
+        for boundary in [Boundary::Absorbing, Boundary::Open] {
+            assert_eq!(
+                confined(boundary, [-10.0, 310.0], [-1.0, 1.0]),
+                ([-10.0, 310.0], [-1.0, 1.0])
+            );
+        }
+        assert!(Boundary::Absorbing.absorbs(&[-0.1, 150.0], &SIZE));
+        assert!(Boundary::Absorbing.absorbs(&[200.0, 300.0], &SIZE));
+        assert!(!Boundary::Absorbing.absorbs(&[0.0, 299.9], &SIZE));
+        assert!(!Boundary::Open.absorbs(&[-10.0, 150.0], &SIZE));
+    }
+}
@@ -0,0 +1,171 @@
+//! The viewer's view onto the world, which can be larger or smaller than the
+//! window.
+
+use graphics::{math::Vec2d, Context, Transformed};
+use particle_simulation::{
+    vector::{add, div_scalar, sub},
+    Particle,
+};
+
+const MIN_ZOOM: f64 = 0.01;
+const MAX_ZOOM: f64 = 100.0;
+
+pub struct Camera {
+    world_size: Vec2d,
+    /// World position shown at the middle of the window.
+    center: Vec2d,
+    /// Window pixels per world unit.
+    zoom: f64,
+    /// Index of the particle kept in the middle of the window, if any. The
+    /// viewer stops following when particles are removed, since that shifts
+    /// the indexes.
+    following: Option<usize>,
+}
+
+impl Camera {
+    /// A camera showing the whole world.
+    pub fn fitted(world_size: [u32; 2], window_size: Vec2d) -> Camera {
+        let mut camera = Camera {
+            world_size: world_size.map(|v| v as f64),
+            center: [0.0, 0.0],
+            zoom: 1.0,
+            following: None,
+        };
+        camera.fit(window_size);
+        return camera;
+    }
+
+    pub fn center(&self) -> Vec2d {
+        return self.center;
+    }
+
+    pub fn zoom(&self) -> f64 {
+        return self.zoom;
+    }
+
+    pub fn following(&self) -> Option<usize> {
+        return self.following;
+    }
+
+    /// Centers the world and zooms until it just fits the window.
+    pub fn fit(&mut self, window_size: Vec2d) {
+        self.following = None;
+        self.center = self.world_size.map(|v| 0.5 * v);
+        self.zoom = (window_size[0] / self.world_size[0])
+            .min(window_size[1] / self.world_size[1])
+            .clamp(MIN_ZOOM, MAX_ZOOM);
+    }
+
+    /// `c` with world coordinates mapped onto the window.
+    pub fn transform(&self, c: Context, window_size: Vec2d) -> Context {
+        return c
+            .trans(0.5 * window_size[0], 0.5 * window_size[1])
+            .zoom(self.zoom)
+            .trans(-self.center[0], -self.center[1]);
+    }
+
+    pub fn to_world(&self, window_pos: Vec2d, window_size: Vec2d) -> Vec2d {
+        return [0, 1].map(|axis| {
+            self.center[axis] + (window_pos[axis] - 0.5 * window_size[axis]) / self.zoom
+        });
+    }
+
+    /// Zooms in for positive `steps` and out for negative ones, keeping the
+    /// world position under `window_pos` in place.
+    pub fn zoom_at(&mut self, window_pos: Vec2d, window_size: Vec2d, steps: f64) {
+        let mut anchor = self.to_world(window_pos, window_size);
+        self.zoom = (self.zoom * 1.2_f64.powf(steps)).clamp(MIN_ZOOM, MAX_ZOOM);
+        if self.following.is_some() {
+            return;
+        }
+        let moved = self.to_world(window_pos, window_size);
+        add(&mut self.center, sub(&mut anchor, &moved));
+    }
+
+    /// Moves the view along with a drag of `delta` window pixels. Stops
+    /// following.
+    pub fn pan(&mut self, delta: Vec2d) {
+        self.following = None;
+        let mut shift = delta;
+        sub(&mut self.center, div_scalar(&mut shift, self.zoom));
+    }
+
+    pub fn follow(&mut self, index: Option<usize>) {
+        self.following = index;
+    }
+
+    /// Moves to the followed particle. Stops following once it is gone.
+    pub fn track(&mut self, particles: &[Particle]) {
+        let Some(index) = self.following else {
+            return;
+        };
+        match particles.get(index) {
+            Some(particle) => self.center = particle.pos,
+            None => self.following = None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Vec2d = [800.0, 400.0];
+
+    #[test]
+    fn fitting_keeps_the_aspect_ratio() {
+        let camera = Camera::fitted([400, 100], WINDOW);
+        assert_eq!(camera.zoom(), 2.0);
+        assert_eq!(camera.to_world([400.0, 200.0], WINDOW), [200.0, 50.0]);
+        assert_eq!(camera.to_world([0.0, 200.0], WINDOW), [0.0, 50.0]);
+        assert_eq!(camera.to_world([0.0, 0.0], WINDOW), [0.0, -50.0]);
+    }
+
+    #[test]
+    fn transform_and_to_world_agree() {
+        let mut camera = Camera::fitted([400, 300], WINDOW);
+        camera.zoom_at([100.0, 50.0], WINDOW, 3.0);
+        camera.pan([30.0, -20.0]);
+        let transform = camera
+            .transform(Context::new_abs(WINDOW[0], WINDOW[1]), WINDOW)
+            .transform;
+        let world = [123.0, 45.0];
+        // The context maps onto normalized device coordinates from -1 to 1.
+        let window = [0, 1].map(|row| {
+            let ndc =
+                transform[row][0] * world[0] + transform[row][1] * world[1] + transform[row][2];
+            return match row {
+                0 => (ndc + 1.0) * 0.5 * WINDOW[0],
+                _ => (1.0 - ndc) * 0.5 * WINDOW[1],
+            };
+        });
+        let back = camera.to_world(window, WINDOW);
+        assert!((back[0] - world[0]).abs() < 1e-9 && (back[1] - world[1]).abs() < 1e-9);
+    }
+
+    #[test]
+    fn zooming_keeps_the_point_under_the_cursor() {
+        let mut camera = Camera::fitted([400, 300], WINDOW);
+        let cursor = [600.0, 100.0];
+        let before = camera.to_world(cursor, WINDOW);
+        camera.zoom_at(cursor, WINDOW, 2.0);
+        let after = camera.to_world(cursor, WINDOW);
+        assert!((before[0] - after[0]).abs() < 1e-9 && (before[1] - after[1]).abs() < 1e-9);
+        assert!((camera.zoom() - 1.44 * Camera::fitted([400, 300], WINDOW).zoom()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn following_stops_when_the_particle_is_gone() {
+        let mut camera = Camera::fitted([400, 300], WINDOW);
+        camera.follow(Some(1));
+        let mut particles = vec![Particle::new(); 2];
+        particles[1].pos = [10.0, 20.0];
+        camera.track(&particles);
+        assert_eq!(camera.to_world([400.0, 200.0], WINDOW), [10.0, 20.0]);
+        camera.track(&particles[..1]);
+        assert_eq!(camera.following(), None);
+        camera.follow(Some(0));
+        camera.pan([1.0, 0.0]);
+        assert_eq!(camera.following(), None);
+    }
+}
@@ -0,0 +1,250 @@
+use std::path::PathBuf;
+
+use clap::{builder::RangedU64ValueParser, Parser, Subcommand, ValueEnum};
+use particle_simulation::{
+    boundary::Boundary,
+    conformance::Tolerances,
+    constants::{DEFAULT_DT, DEFAULT_RESTITUTION, DEFAULT_SUBSTEPS},
+    force_law::ForceLawKind,
+    integrator::Integrator,
+    neighbor_grid::NeighborSearch,
+    SceneSettings,
+};
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Particle life simulation")]
+pub struct Cli {
+    /// Simulation backend to run
+    #[arg(short, long, value_enum, default_value_t = Backend::Wgpu)]
+    pub backend: Backend,
+
+    /// Number of simulated particles
+    #[arg(short = 'n', long, default_value_t = 5_000)]
+    pub particles: usize,
+
+    /// Number of particle types
+    #[arg(
+        short,
+        long,
+        default_value_t = 5,
+        value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub types: usize,
+
+    /// Seed for the particle type rules
+    #[arg(short, long, default_value_t = 6)]
+    pub seed: u64,
+
+    /// Size of the simulated world, as WIDTHxHEIGHT
+    #[arg(long, value_parser = parse_screen_size, default_value = "2320x1280")]
+    pub screen_size: [u32; 2],
+
+    /// Pairwise force law
+    #[arg(long, value_enum, default_value_t = ForceLawKind::default())]
+    pub force_law: ForceLawKind,
+
+    /// How backends find interaction partners
+    #[arg(long, value_enum, default_value_t = NeighborSearch::default())]
+    pub neighbor_search: NeighborSearch,
+
+    /// Simulation steps per update, i.e. per frame in the window
+    #[arg(
+        long,
+        default_value_t = DEFAULT_SUBSTEPS,
+        value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub substeps: usize,
+
+    /// Length of one simulation step
+    #[arg(long, default_value_t = DEFAULT_DT, value_parser = parse_dt)]
+    pub dt: f64,
+
+    /// Time integration scheme
+    #[arg(long, value_enum, default_value_t = Integrator::default())]
+    pub integrator: Integrator,
+
+    /// World edges
+    #[arg(long, value_enum, default_value_t = Boundary::default())]
+    pub boundary: Boundary,
+
+    /// Fraction of the normal velocity kept by particles bouncing off
+    /// reflective walls
+    #[arg(long, default_value_t = DEFAULT_RESTITUTION, value_parser = parse_restitution)]
+    pub restitution: f64,
+
+    /// Load the particle type rules from a TOML rule-set file instead of
+    /// generating them from the seed
+    #[arg(short, long)]
+    pub rules: Option<PathBuf>,
+
+    /// Load static obstacles from a TOML obstacle file
+    #[arg(long)]
+    pub obstacles: Option<PathBuf>,
+
+    /// Load gravity, attractors, vortices and flow images from a TOML field
+    /// file
+    #[arg(long)]
+    pub fields: Option<PathBuf>,
+
+    /// Load dt, substeps, integrator, boundary and restitution from a TOML
+    /// scene config file, overriding the options above
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// Continue from a snapshot file. Its settings, rules, obstacles and
+    /// fields replace the scene options above
+    #[arg(long, conflicts_with_all = ["rules", "obstacles", "fields"])]
+    pub resume: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub mode: Option<Mode>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Backend {
+    /// Array-of-structs CPU scene (`MultithreadedScene`)
+    Multithreaded,
+    /// Struct-of-arrays CPU scene (`MultithreadedSceneV2`)
+    MultithreadedV2,
+    /// Compute shader scene (`WgpuScene`)
+    Wgpu,
+}
+
+#[cfg(feature = "viewer")]
+impl Backend {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Backend::Multithreaded => "multithreaded",
+            Backend::MultithreadedV2 => "multithreaded-v2",
+            Backend::Wgpu => "wgpu",
+        };
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Mode {
+    /// Show the simulation in a window (default)
+    Window {
+        /// Draw straight from the GPU buffers instead of reading the
+        /// particles back every frame. Only for the wgpu backend
+        #[arg(long)]
+        direct: bool,
+        /// Size of the window, as WIDTHxHEIGHT. Defaults to the world size
+        #[arg(long, value_parser = parse_screen_size)]
+        window_size: Option<[u32; 2]>,
+    },
+    /// Step the simulation without opening a window and write the results to files
+    Headless {
+        /// Number of steps to simulate
+        #[arg(long, required_unless_present = "time_budget")]
+        steps: Option<usize>,
+        /// Stop after this many seconds, even if `--steps` were not all simulated
+        #[arg(long)]
+        time_budget: Option<f64>,
+        /// Directory for `particles.csv`, `timings.csv` and the snapshots
+        #[arg(short, long, default_value = "output")]
+        output: PathBuf,
+        /// Save `checkpoint.psnap` in the output directory every N steps
+        #[arg(
+            long,
+            value_name = "N",
+            value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        checkpoint_every: Option<usize>,
+        /// Record a trajectory file for the `playback` mode
+        #[arg(long)]
+        record: Option<PathBuf>,
+        /// Record only every K-th step
+        #[arg(
+            long,
+            value_name = "K",
+            default_value_t = 1,
+            value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        record_every: usize,
+    },
+    /// Replay a recorded trajectory file in a window
+    Playback {
+        /// Trajectory recorded by `headless --record`
+        file: PathBuf,
+    },
+    /// Write the particle type rules to a TOML rule-set file and exit
+    ExportRules {
+        /// Destination of the rule set
+        #[arg(short, long, default_value = "rules.toml")]
+        output: PathBuf,
+    },
+    /// Step every backend from the same state and compare them against a
+    /// reference implementation
+    Conformance {
+        /// Number of steps to compare
+        #[arg(long, default_value_t = 10)]
+        steps: usize,
+        /// Largest accepted position divergence, in pixels
+        #[arg(long, default_value_t = Tolerances::default().position)]
+        position_tolerance: f64,
+        /// Largest accepted velocity divergence
+        #[arg(long, default_value_t = Tolerances::default().velocity)]
+        velocity_tolerance: f64,
+    },
+    /// Measure the average update time
+    Benchmark {
+        /// Number of timed updates
+        #[arg(
+            long,
+            default_value_t = particle_simulation::constants::BENCHMARK_RUNS,
+            value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        runs: usize,
+    },
+}
+
+impl Cli {
+    pub fn scene_settings(&self) -> SceneSettings {
+        return SceneSettings {
+            screen_size: self.screen_size,
+            particle_count: self.particles,
+            particle_types_count: self.types,
+            seed: self.seed,
+            force_law: self.force_law,
+            neighbor_search: self.neighbor_search,
+            substeps: self.substeps,
+            dt: self.dt,
+            integrator: self.integrator,
+            boundary: self.boundary,
+            restitution: self.restitution,
+        };
+    }
+}
+
+fn parse_dt(value: &str) -> Result<f64, String> {
+    return value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|v| v.is_finite() && *v > 0.0)
+        .ok_or_else(|| format!("expected a positive number, got `{value}`"));
+}
+
+fn parse_restitution(value: &str) -> Result<f64, String> {
+    return value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|v| (0.0..=1.0).contains(v))
+        .ok_or_else(|| format!("expected a number from 0 to 1, got `{value}`"));
+}
+
+fn parse_screen_size(value: &str) -> Result<[u32; 2], String> {
+    let (width, height) = value
+        .split_once(['x', 'X'])
+        .ok_or_else(|| format!("expected WIDTHxHEIGHT, got `{value}`"))?;
+    let parse = |v: &str| {
+        v.trim()
+            .parse::<u32>()
+            .ok()
+            .filter(|v| *v > 0)
+            .ok_or_else(|| format!("invalid dimension `{v}`"))
+    };
+    return Ok([parse(width)?, parse(height)?]);
+}
@@ -8,17 +8,76 @@
 @group(0) @binding(6) var<storage, read> in_type_radii: array<f32>;
 @group(0) @binding(7) var<storage, read> in_type_min_distance: array<f32>;
 
+// Per-particle state kept between the stages of one step: the state at the
+// start of the step, for RK4 and the obstacles, and the running sum of the RK4
+// derivatives, both packed as (position, velocity).
+@group(0) @binding(8) var<storage, read_write> base_states: array<vec4f>;
+@group(0) @binding(9) var<storage, read_write> accumulators: array<vec4f>;
+
 
 struct GlobalUniforms {
     screen_size_x: f32,
     screen_size_y: f32,
     particle_types_count: u32,
+    // `ForceLawKind` as u32
+    force_law: u32,
+    // `SceneSettings::dt`
+    dt: f32,
+    // `Integrator` as u32
+    integrator: u32,
+    // `Boundary` as u32
+    boundary: u32,
+    // `SceneSettings::restitution`
+    restitution: f32,
 }
 @group(1) @binding(0) var<uniform> global_uniforms: GlobalUniforms;
 @group(1) @binding(1) var<storage, read> in_type_masses: array<f32>;
 @group(1) @binding(2) var<storage, read> in_type_drag: array<f32>;
+// Obstacles packed by `encode_obstacles` in wgpu_scene.rs: the obstacle count,
+// the offset of every record, then the records. Floats are stored as bits.
+@group(1) @binding(3) var<storage, read> obstacle_words: array<u32>;
+// Force fields packed by `encode_fields` in wgpu_scene.rs, laid out like
+// `obstacle_words`.
+@group(1) @binding(4) var<storage, read> field_words: array<u32>;
+
+// Output of binning.wgsl for the current positions.
+struct GridUniforms {
+    cells_x: u32,
+    cells_y: u32,
+    cell_size_x: f32,
+    cell_size_y: f32,
+}
+@group(2) @binding(0) var<uniform> grid: GridUniforms;
+@group(2) @binding(1) var<storage, read> cell_start: array<u32>;
+@group(2) @binding(2) var<storage, read> sorted_indices: array<u32>;
 
-const DELTA_T: f32 = 1;
+// Has to match `constants::K` on the CPU side.
+const K: f32 = 0.034;
+
+// Stage of the integrator this dispatch runs, from 0 to
+// `Integrator::stages` - 1. Every stage evaluates the forces at the input
+// positions once.
+struct StageUniforms {
+    stage: u32,
+}
+@group(3) @binding(0) var<uniform> stage_uniforms: StageUniforms;
+
+const INTEGRATOR_SEMI_IMPLICIT_EULER: u32 = 0;
+const INTEGRATOR_VELOCITY_VERLET: u32 = 1;
+const INTEGRATOR_RK4: u32 = 2;
+
+const BOUNDARY_PERIODIC: u32 = 0;
+const BOUNDARY_REFLECTIVE: u32 = 1;
+const BOUNDARY_ABSORBING: u32 = 2;
+
+// Absorbed particles stay in the buffers until the CPU side removes them, parked
+// at this position. Has to match `ABSORBED_POSITION` in wgpu_scene.rs and
+// `ABSORBED` in binning.wgsl.
+const ABSORBED: f32 = -1.0e30;
+
+fn is_absorbed(position: vec2f) -> bool {
+    return position.x <= ABSORBED;
+}
 
 fn get_force(i: u32, j: u32) -> f32 {
     return in_type_forces[i * global_uniforms.particle_types_count + j];
@@ -36,12 +95,410 @@ fn remap(x: f32, a: f32, b: f32, c: f32, d: f32) -> f32 {
     return c + (x - a) * (d - c) / (b - a);
 }
 
+const FORCE_LAW_PIECEWISE_LINEAR: u32 = 0;
+const FORCE_LAW_LENNARD_JONES: u32 = 1;
+const FORCE_LAW_GAUSSIAN: u32 = 2;
+const FORCE_LAW_SMOOTH_STEP: u32 = 3;
+
+// Mirrors the `ForceLaw` implementations in force_law.rs. Positive values attract.
+fn force_magnitude(distance: f32, strength: f32, min_distance: f32, radius: f32) -> f32 {
+    var magnitude = 0.0;
+    switch global_uniforms.force_law {
+        case FORCE_LAW_LENNARD_JONES: {
+            if distance < max(radius, min_distance) {
+                let x = min_distance / max(distance, 0.8 * min_distance);
+                let x7 = pow(x, 7.0);
+                magnitude = strength * x7 - abs(strength) * x7 * x7 / x;
+            }
+        }
+        case FORCE_LAW_GAUSSIAN: {
+            if distance < min_distance {
+                let width = 0.5 * min_distance;
+                magnitude += abs(strength) * -6.6 * exp(-(distance * distance) / (2.0 * width * width));
+            }
+            if distance < radius {
+                let offset = distance - 0.5 * radius;
+                let width = radius / 6.0;
+                magnitude += strength * exp(-(offset * offset) / (2.0 * width * width));
+            }
+        }
+        case FORCE_LAW_SMOOTH_STEP: {
+            if distance < min_distance {
+                magnitude += abs(strength) * -6.6 * (1.0 - smoothstep(0.0, 1.0, distance / min_distance));
+            }
+            if distance < radius {
+                magnitude += strength * (1.0 - smoothstep(0.0, 1.0, distance / radius));
+            }
+        }
+        default: {
+            if distance < min_distance {
+                magnitude += abs(strength) * -6.0 * remap(distance, 0.0, min_distance, 1.1, 0.0);
+            }
+            if distance < radius {
+                magnitude += strength * remap(distance, 0.0, radius, 1.0, 0.0);
+            }
+        }
+    }
+    return magnitude * K;
+}
+
+fn cell_coord(position: f32, cell_size: f32, cells: u32) -> u32 {
+    return min(u32(max(floor(position / cell_size), 0.0)), cells - 1);
+}
+
+// `offset`-th cell to visit along an axis around `center`. Axes with fewer
+// than three cells are walked once in full, so no cell is visited twice.
+fn neighbor_cell(center: u32, offset: u32, cells: u32) -> u32 {
+    if cells < 3 {
+        return offset;
+    }
+    return (center + cells - 1 + offset) % cells;
+}
+
+// Mirrors `Boundary::minimum_image`.
+fn minimum_image(v: vec2f, screen_size: vec2f) -> vec2f {
+    var direction = v;
+    if global_uniforms.boundary == BOUNDARY_PERIODIC {
+        if direction.x > 0.5 * screen_size.x {
+            direction.x -= screen_size.x;
+        }
+        if direction.x < -0.5 * screen_size.x {
+            direction.x += screen_size.x;
+        }
+        if direction.y > 0.5 * screen_size.y {
+            direction.y -= screen_size.y;
+        }
+        if direction.y < -0.5 * screen_size.y {
+            direction.y += screen_size.y;
+        }
+    }
+    return direction;
+}
+
+// Force applied to particle 1 by particle 2.
+fn pair_force(p1_pos: vec2f, p1_type_index: u32, p2_index: u32, screen_size: vec2f) -> vec2f {
+    let p2_pos = in_positions[p2_index];
+    let p2_type_index = in_type_indexes[p2_index];
+
+    var direction = minimum_image(p2_pos - p1_pos, screen_size);
+
+    let distance = length(direction);
+    direction = normalize(direction);
+
+    return direction * force_magnitude(
+        distance,
+        get_force(p1_type_index, p2_type_index),
+        get_min_distance(p1_type_index, p2_type_index),
+        get_radii(p1_type_index, p2_type_index),
+    );
+}
+
+// Mirrors the reflective case of `Boundary::confine`, for one axis. Returns
+// the new (position, velocity).
+fn reflect_axis(position: f32, velocity: f32, size: f32) -> vec2f {
+    var result = vec2f(position, velocity);
+    if position < 0.0 {
+        result = vec2f(-position, -velocity * global_uniforms.restitution);
+    } else if position > size {
+        result = vec2f(2.0 * size - position, -velocity * global_uniforms.restitution);
+    }
+    result.x = clamp(result.x, 0.0, size);
+    return result;
+}
+
+fn last_stage() -> u32 {
+    switch global_uniforms.integrator {
+        case INTEGRATOR_VELOCITY_VERLET: {
+            return 1u;
+        }
+        case INTEGRATOR_RK4: {
+            return 3u;
+        }
+        default: {
+            return 0u;
+        }
+    }
+}
+
+const OBSTACLE_CIRCLE: u32 = 0;
+const OBSTACLE_SEGMENT: u32 = 1;
+const OBSTACLE_POLYGON: u32 = 2;
+const OBSTACLE_MASK: u32 = 3;
+// Words before the permeability flags of a record: kind, point count, size,
+// mask cells along x and y.
+const OBSTACLE_HEADER: u32 = 5;
+
+fn obstacle_float(index: u32) -> f32 {
+    return bitcast<f32>(obstacle_words[index]);
+}
+
+fn obstacle_point(record: u32, i: u32) -> vec2f {
+    let index = record + OBSTACLE_HEADER + global_uniforms.particle_types_count + 2u * i;
+    return vec2f(obstacle_float(index), obstacle_float(index + 1u));
+}
+
+fn closest_on_segment(pos: vec2f, start: vec2f, end: vec2f) -> vec2f {
+    let along = end - start;
+    let length_squared = dot(along, along);
+    var t = 0.0;
+    if length_squared > 0.0 {
+        t = clamp(dot(pos - start, along) / length_squared, 0.0, 1.0);
+    }
+    return start + along * t;
+}
+
+fn normalize_or_x(v: vec2f) -> vec2f {
+    let l = length(v);
+    if l == 0.0 {
+        return vec2f(1.0, 0.0);
+    }
+    return v / l;
+}
+
+// Mirrors `away_from` in obstacle.rs, packed as (distance, direction).
+fn away_from(pos: vec2f, origin: vec2f, fallback: vec2f) -> vec3f {
+    let direction = pos - origin;
+    let distance = length(direction);
+    if distance == 0.0 {
+        return vec3f(0.0, fallback);
+    }
+    return vec3f(distance, direction / distance);
+}
+
+// Mirrors `Shape::surface`, packed as (signed distance, way out).
+fn obstacle_surface(record: u32, pos: vec2f) -> vec3f {
+    let point_count = obstacle_words[record + 1u];
+    let size = obstacle_float(record + 2u);
+    switch obstacle_words[record] {
+        case OBSTACLE_CIRCLE: {
+            let surface = away_from(pos, obstacle_point(record, 0u), vec2f(1.0, 0.0));
+            return vec3f(surface.x - size, surface.yz);
+        }
+        case OBSTACLE_SEGMENT: {
+            let start = obstacle_point(record, 0u);
+            let end = obstacle_point(record, 1u);
+            let across = normalize_or_x(vec2f(start.y - end.y, end.x - start.x));
+            let surface = away_from(pos, closest_on_segment(pos, start, end), across);
+            return vec3f(surface.x - size, surface.yz);
+        }
+        default: {
+            var closest = obstacle_point(record, 0u);
+            var closest_distance = 3.4e38;
+            var edge_normal = vec2f(1.0, 0.0);
+            var inside = false;
+            for (var i = 0u; i < point_count; i++) {
+                let a = obstacle_point(record, i);
+                let b = obstacle_point(record, (i + 1u) % point_count);
+                let candidate = closest_on_segment(pos, a, b);
+                let distance = length(pos - candidate);
+                if distance < closest_distance {
+                    closest = candidate;
+                    closest_distance = distance;
+                    edge_normal = vec2f(b.y - a.y, a.x - b.x);
+                }
+                if (a.y > pos.y) != (b.y > pos.y)
+                    && pos.x < a.x + (pos.y - a.y) * (b.x - a.x) / (b.y - a.y) {
+                    inside = !inside;
+                }
+            }
+            // `size` holds the sign of the polygon's area.
+            edge_normal = normalize_or_x(edge_normal * size);
+            if closest_distance < CONTACT_DISTANCE {
+                return vec3f(select(closest_distance, -closest_distance, inside), edge_normal);
+            }
+            if inside {
+                let surface = away_from(closest, pos, edge_normal);
+                return vec3f(-surface.x, surface.yz);
+            }
+            return away_from(pos, closest, edge_normal);
+        }
+    }
+}
+
+fn mask_solid(record: u32, pos: vec2f) -> bool {
+    let cell = floor((pos - obstacle_point(record, 0u)) / obstacle_float(record + 2u));
+    let cells_x = obstacle_words[record + 3u];
+    let cells_y = obstacle_words[record + 4u];
+    if any(cell < vec2f(0.0)) || cell.x >= f32(cells_x) || cell.y >= f32(cells_y) {
+        return false;
+    }
+    // The cells follow the origin, the mask's only point.
+    let first_cell = record + OBSTACLE_HEADER + global_uniforms.particle_types_count + 2u;
+    return obstacle_words[first_cell + u32(cell.y) * cells_x + u32(cell.x)] != 0u;
+}
+
+const CONTACT_DISTANCE: f32 = 1e-3;
+const MAX_CONTACT_STEPS: u32 = 32u;
+
+struct Contact {
+    found: bool,
+    position: vec2f,
+    normal: vec2f,
+}
+
+// Mirrors `Shape::first_contact`.
+fn first_contact(record: u32, path_start: vec2f, path_end: vec2f) -> Contact {
+    let path = path_end - path_start;
+    let path_length = length(path);
+    var t = 0.0;
+    if path_length > 0.0 {
+        for (var step = 0u; step < MAX_CONTACT_STEPS; step++) {
+            let point = path_start + path * t;
+            let surface = obstacle_surface(record, point);
+            if surface.x < -CONTACT_DISTANCE {
+                break;
+            }
+            if surface.x < CONTACT_DISTANCE && dot(path, surface.yz) < 0.0 {
+                return Contact(true, point - surface.yz * surface.x, surface.yz);
+            }
+            if t >= 1.0 {
+                break;
+            }
+            t = min(t + max(surface.x, CONTACT_DISTANCE) / path_length, 1.0);
+        }
+    }
+    return Contact(false, path_end, vec2f(0.0));
+}
+
+// Mirrors `bounce` in obstacle.rs.
+fn bounce(velocity: vec2f, normal: vec2f) -> vec2f {
+    let normal_speed = dot(velocity, normal);
+    if normal_speed < 0.0 {
+        return velocity - normal * ((1.0 + global_uniforms.restitution) * normal_speed);
+    }
+    return velocity;
+}
+
+// Mirrors `Obstacle::collide`, packed as (position, velocity).
+fn collide_obstacle(record: u32, previous: vec2f, position: vec2f, velocity: vec2f) -> vec4f {
+    var pos = position;
+    var vel = velocity;
+    let restitution = global_uniforms.restitution;
+    if obstacle_words[record] == OBSTACLE_MASK {
+        if !mask_solid(record, pos) || mask_solid(record, previous) {
+            return vec4f(pos, vel);
+        }
+        if !mask_solid(record, vec2f(pos.x, previous.y)) {
+            pos.y = previous.y;
+            vel.y = -vel.y * restitution;
+        } else if !mask_solid(record, vec2f(previous.x, pos.y)) {
+            pos.x = previous.x;
+            vel.x = -vel.x * restitution;
+        } else {
+            pos = previous;
+            vel = -vel * restitution;
+        }
+        return vec4f(pos, vel);
+    }
+    let contact = first_contact(record, previous, pos);
+    if contact.found {
+        return vec4f(contact.position, bounce(vel, contact.normal));
+    }
+    let surface = obstacle_surface(record, pos);
+    if surface.x >= 0.0 {
+        return vec4f(pos, vel);
+    }
+    pos += surface.yz * -surface.x;
+    return vec4f(pos, bounce(vel, surface.yz));
+}
+
+// Mirrors `ObstacleSet::collide`.
+fn collide_obstacles(type_index: u32, previous: vec2f, position: vec2f, velocity: vec2f) -> vec4f {
+    var state = vec4f(position, velocity);
+    let count = obstacle_words[0];
+    for (var k = 0u; k < count; k++) {
+        let record = obstacle_words[1u + k];
+        let permeable = obstacle_words[record + OBSTACLE_HEADER + type_index] != 0u;
+        if !permeable {
+            state = collide_obstacle(record, previous, state.xy, state.zw);
+        }
+    }
+    return state;
+}
+
+const FIELD_GRAVITY: u32 = 0;
+const FIELD_ATTRACTOR: u32 = 1;
+const FIELD_VORTEX: u32 = 2;
+const FIELD_FLOW: u32 = 3;
+// Words before the responses of a record: kind, flow width and height, and
+// five parameters.
+const FIELD_HEADER: u32 = 8;
+
+fn field_float(index: u32) -> f32 {
+    return bitcast<f32>(field_words[index]);
+}
+
+fn flow_vector(record: u32, x: u32, y: u32) -> vec2f {
+    let width = field_words[record + 1u];
+    let index = record + FIELD_HEADER + global_uniforms.particle_types_count + 2u * (y * width + x);
+    return vec2f(field_float(index), field_float(index + 1u));
+}
+
+// Mirrors `FieldKind::acceleration`.
+fn field_acceleration(record: u32, pos: vec2f, screen_size: vec2f) -> vec2f {
+    let p = record + 3u;
+    switch field_words[record] {
+        case FIELD_GRAVITY: {
+            return vec2f(field_float(p), field_float(p + 1u));
+        }
+        case FIELD_ATTRACTOR, FIELD_VORTEX: {
+            let towards = minimum_image(vec2f(field_float(p), field_float(p + 1u)) - pos, screen_size);
+            let strength = field_float(p + 2u);
+            // Negative radii stand for unlimited ones.
+            let radius = field_float(p + 3u);
+            let softening = field_float(p + 4u);
+            let distance = length(towards);
+            if distance == 0.0 || (radius >= 0.0 && distance >= radius) {
+                return vec2f(0.0);
+            }
+            let pull = towards * (strength / (distance * distance + softening * softening) / distance);
+            if field_words[record] == FIELD_VORTEX {
+                return vec2f(pull.y, -pull.x);
+            }
+            return pull;
+        }
+        default: {
+            let width = field_words[record + 1u];
+            let height = field_words[record + 2u];
+            let uv = (pos - vec2f(field_float(p), field_float(p + 1u))) / field_float(p + 2u);
+            if any(uv < vec2f(0.0)) || uv.x >= f32(width) || uv.y >= f32(height) {
+                return vec2f(0.0);
+            }
+            let xy = clamp(uv - 0.5, vec2f(0.0), vec2f(f32(width - 1u), f32(height - 1u)));
+            let x0 = u32(xy.x);
+            let y0 = u32(xy.y);
+            let x1 = min(x0 + 1u, width - 1u);
+            let y1 = min(y0 + 1u, height - 1u);
+            let f = xy - vec2f(f32(x0), f32(y0));
+            let top = mix(flow_vector(record, x0, y0), flow_vector(record, x1, y0), f.x);
+            let bottom = mix(flow_vector(record, x0, y1), flow_vector(record, x1, y1), f.x);
+            return mix(top, bottom, f.y) * field_float(p + 3u);
+        }
+    }
+}
+
+// Mirrors `FieldSet::acceleration`.
+fn fields_acceleration(type_index: u32, pos: vec2f, screen_size: vec2f) -> vec2f {
+    var total = vec2f(0.0);
+    let count = field_words[0];
+    for (var k = 0u; k < count; k++) {
+        let record = field_words[1u + k];
+        let response = field_float(record + FIELD_HEADER + type_index);
+        if response != 0.0 {
+            total += field_acceleration(record, pos, screen_size) * response;
+        }
+    }
+    return total;
+}
+
 @compute
 @workgroup_size(64, 1, 1)
 fn main(
-    @builtin(global_invocation_id) global_invocation_id: vec3<u32>
+    @builtin(global_invocation_id) global_invocation_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>,
 ) {
-    let p1_index = global_invocation_id.x;
+    // Dispatches too large for one dimension continue in further rows of y.
+    let p1_index = global_invocation_id.y * num_workgroups.x * 64u + global_invocation_id.x;
     let total = arrayLength(&in_positions);
     let screen_size = vec2f(global_uniforms.screen_size_x, global_uniforms.screen_size_y);
 
@@ -52,57 +509,134 @@ fn main(
     let p1_pos = in_positions[p1_index];
     let p1_velocity = in_velocities[p1_index];
     let p1_type_index = in_type_indexes[p1_index];
+    if is_absorbed(p1_pos) {
+        out_positions[p1_index] = p1_pos;
+        out_velocities[p1_index] = p1_velocity;
+        return;
+    }
     var total_force = vec2f(0.0);
 
-    for (var p2_index: u32 = 0; p2_index < total; p2_index++) {
-        if p1_index == p2_index {
-            continue;
+    if grid.cells_x * grid.cells_y == 1u {
+        // A single cell holds every particle, so nothing was binned and the
+        // particles are walked in index order.
+        for (var p2_index = 0u; p2_index < total; p2_index++) {
+            if p1_index == p2_index || is_absorbed(in_positions[p2_index]) {
+                continue;
+            }
+            total_force += pair_force(p1_pos, p1_type_index, p2_index, screen_size);
         }
-        let p2_pos = in_positions[p2_index];
-        let p2_velocity = in_velocities[p2_index];
-        let p2_type_index = in_type_indexes[p2_index];
+    } else {
+        // Only the 3x3 block of grid cells around the particle can be in reach.
+        let center_x = cell_coord(p1_pos.x, grid.cell_size_x, grid.cells_x);
+        let center_y = cell_coord(p1_pos.y, grid.cell_size_y, grid.cells_y);
+        for (var offset_y: u32 = 0; offset_y < min(grid.cells_y, 3u); offset_y++) {
+            let cell_y = neighbor_cell(center_y, offset_y, grid.cells_y);
+            for (var offset_x: u32 = 0; offset_x < min(grid.cells_x, 3u); offset_x++) {
+                let cell = cell_y * grid.cells_x + neighbor_cell(center_x, offset_x, grid.cells_x);
+                for (var k = cell_start[cell]; k < cell_start[cell + 1]; k++) {
+                    let p2_index = sorted_indices[k];
+                    if p1_index == p2_index {
+                        continue;
+                    }
+                    total_force += pair_force(p1_pos, p1_type_index, p2_index, screen_size);
+                }
+            }
+        }
+    }
 
-        var direction = p2_pos - p1_pos;
-        if direction.x > 0.5 * screen_size.x {
-            direction.x -= screen_size.x;
+    let p_mass = in_type_masses[p1_type_index];
+    let p_drag = in_type_drag[p1_type_index];
+    let dt = global_uniforms.dt;
+    let acceleration = total_force / p_mass
+        + fields_acceleration(p1_type_index, p1_pos, screen_size);
+
+    // Mirrors `integrate` in integrator.rs.
+    let stage = stage_uniforms.stage;
+    if stage == 0 {
+        base_states[p1_index] = vec4f(p1_pos, p1_velocity);
+    }
+    var next_position: vec2f;
+    var next_velocity: vec2f;
+    switch global_uniforms.integrator {
+        case INTEGRATOR_VELOCITY_VERLET: {
+            next_velocity = p1_velocity + acceleration * (0.5 * dt);
+            if stage == 0 {
+                next_position = p1_pos + next_velocity * dt;
+            } else {
+                next_velocity *= pow(p_drag, dt);
+                next_position = p1_pos;
+            }
         }
-        if direction.x < -0.5 * screen_size.x {
-            direction.x += screen_size.x;
+        case INTEGRATOR_RK4: {
+            let gamma = -log(p_drag);
+            let derivative = vec4f(p1_velocity, acceleration - gamma * p1_velocity);
+            var base = vec4f(p1_pos, p1_velocity);
+            if stage == 0 {
+                accumulators[p1_index] = derivative;
+            } else {
+                base = base_states[p1_index];
+            }
+            var next: vec4f;
+            switch stage {
+                case 0u, 1u: {
+                    if stage == 1 {
+                        accumulators[p1_index] += 2.0 * derivative;
+                    }
+                    next = base + derivative * (0.5 * dt);
+                }
+                case 2u: {
+                    accumulators[p1_index] += 2.0 * derivative;
+                    next = base + derivative * dt;
+                }
+                default: {
+                    next = base + (accumulators[p1_index] + derivative) * (dt / 6.0);
+                }
+            }
+            next_position = next.xy;
+            next_velocity = next.zw;
         }
-        if direction.y > 0.5 * screen_size.y {
-            direction.y -= screen_size.y;
+        default: {
+            next_velocity = (p1_velocity + acceleration * dt) * pow(p_drag, dt);
+            next_position = p1_pos + next_velocity * dt;
         }
-        if direction.y < -0.5 * screen_size.y {
-            direction.y += screen_size.y;
+    }
+
+    // Mirrors `Boundary::confine`. Intermediate RK4 states only wrap.
+    let intermediate = global_uniforms.integrator == INTEGRATOR_RK4 && stage < 3;
+    switch global_uniforms.boundary {
+        case BOUNDARY_PERIODIC: {
+            next_position = next_position - screen_size * floor(next_position / screen_size);
         }
-        
-        let distance = length(direction);
-        direction = normalize(direction);
+        case BOUNDARY_REFLECTIVE: {
+            if !intermediate {
+                let x = reflect_axis(next_position.x, next_velocity.x, screen_size.x);
+                let y = reflect_axis(next_position.y, next_velocity.y, screen_size.y);
+                next_position = vec2f(x.x, y.x);
+                next_velocity = vec2f(x.y, y.y);
+            }
+        }
+        default: {}
+    }
 
-        let p_min_distance = get_min_distance(p1_type_index, p2_type_index);
-        if distance < p_min_distance {
-            let force = direction 
-                * abs(get_force(p1_type_index, p2_type_index)) 
-                * remap(distance, 0.0, p_min_distance, 1.1, 0.0) 
-                * -0.204;
-            total_force += force;
+    // Obstacles and absorbing walls act on the state at the end of the step.
+    if stage == last_stage() {
+        var previous = p1_pos;
+        if stage != 0 {
+            previous = base_states[p1_index].xy;
         }
+        // Seen from the new position, before periodic worlds wrapped it.
+        previous = next_position - minimum_image(next_position - previous, screen_size);
+        let state = collide_obstacles(p1_type_index, previous, next_position, next_velocity);
+        next_position = state.xy;
+        next_velocity = state.zw;
 
-        let p_radii = get_radii(p1_type_index, p2_type_index);
-        if distance < p_radii {
-            let force = direction 
-                * get_force(p1_type_index, p2_type_index) 
-                * remap(distance, 0.0, p_radii, 1.0, 0.0) 
-                * 0.084;
-            total_force += force;
+        let outside = any(next_position < vec2f(0.0)) || any(next_position >= screen_size);
+        if global_uniforms.boundary == BOUNDARY_ABSORBING && outside {
+            next_position = vec2f(ABSORBED);
+            next_velocity = vec2f(0.0);
         }
     }
 
-    let p_mass = in_type_masses[p1_type_index];
-    let p_drag = in_type_drag[p1_type_index];
-    let p_next_velocity = (p1_velocity + total_force / p_mass) * p_drag;
-
-    let final_position = (p1_pos + p_next_velocity * DELTA_T + screen_size) % screen_size;
-    out_positions[p1_index] = final_position;
-    out_velocities[p1_index] = p_next_velocity;
-}
\ No newline at end of file
+    out_positions[p1_index] = next_position;
+    out_velocities[p1_index] = next_velocity;
+}
@@ -0,0 +1,674 @@
+//! Cross-backend conformance checks.
+//!
+//! Every backend starts from the same [`Snapshot`] and is stepped alongside
+//! [`reference_step`], a plain single-threaded `f64` implementation of the
+//! scene loop that favours readability over speed. After every step each
+//! particle's position and velocity are compared against the reference.
+
+use graphics::math::Vec2d;
+
+use crate::{
+    boundary::Boundary,
+    constants::K,
+    field::{FieldKind, FieldSet},
+    force_law::ForceLawKind,
+    integrator::Integrator,
+    obstacle::{ObstacleSet, Shape},
+    particle_type::ParticleTypeManager,
+    scene_like::SceneLike,
+    snapshot::Snapshot,
+    vector::{len, remap, sub, wrap},
+    MultithreadedScene, MultithreadedSceneV2, Particle, SceneSettings, WgpuScene,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Tolerances {
+    /// Largest accepted distance between a particle and its reference, in pixels.
+    pub position: f64,
+    /// Largest accepted length of the velocity difference.
+    pub velocity: f64,
+}
+
+impl Default for Tolerances {
+    fn default() -> Self {
+        // Loose enough for the `f32` GPU backend over a handful of steps.
+        return Tolerances {
+            position: 1e-2,
+            velocity: 1e-2,
+        };
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ParticleDivergence {
+    pub index: usize,
+    pub position: f64,
+    pub velocity: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct BackendReport {
+    pub backend: String,
+    pub steps: usize,
+    pub tolerances: Tolerances,
+    /// Largest position divergence seen after each step.
+    pub max_position_per_step: Vec<f64>,
+    /// Largest velocity divergence seen after each step.
+    pub max_velocity_per_step: Vec<f64>,
+    /// Per-particle divergence after the last step, in particle order.
+    pub particles: Vec<ParticleDivergence>,
+    /// Set when the backend lost or gained particles, which makes the
+    /// per-particle comparison meaningless.
+    pub count_mismatch: Option<(usize, usize)>,
+}
+
+impl BackendReport {
+    pub fn max_position(&self) -> f64 {
+        return self
+            .max_position_per_step
+            .iter()
+            .copied()
+            .fold(0.0, f64::max);
+    }
+
+    pub fn max_velocity(&self) -> f64 {
+        return self
+            .max_velocity_per_step
+            .iter()
+            .copied()
+            .fold(0.0, f64::max);
+    }
+
+    pub fn failing_particles(&self) -> impl Iterator<Item = &ParticleDivergence> {
+        return self.particles.iter().filter(|p| {
+            !(p.position <= self.tolerances.position && p.velocity <= self.tolerances.velocity)
+        });
+    }
+
+    /// NaN divergence counts as a failure.
+    pub fn passed(&self) -> bool {
+        return self.count_mismatch.is_none()
+            && self.max_position() <= self.tolerances.position
+            && self.max_velocity() <= self.tolerances.velocity
+            && self.failing_particles().next().is_none();
+    }
+
+    /// The `count` particles that diverged the most after the last step.
+    pub fn worst_particles(&self, count: usize) -> Vec<&ParticleDivergence> {
+        let mut worst = self.particles.iter().collect::<Vec<_>>();
+        worst.sort_by(|a, b| b.position.total_cmp(&a.position));
+        worst.truncate(count);
+        return worst;
+    }
+}
+
+/// One step of the reference simulation. An update runs
+/// [`SceneSettings::substeps`] of these.
+///
+/// The force laws, integrators, fields and obstacle collisions are written
+/// out again here rather than shared with the CPU scenes, so that a mistake
+/// in the shared code shows up as a divergence.
+pub fn reference_step(
+    particles: &[Particle],
+    particle_types: &ParticleTypeManager,
+    obstacles: &ObstacleSet,
+    fields: &FieldSet,
+    settings: &SceneSettings,
+) -> Vec<Particle> {
+    let screen_size = settings.screen_size.map(|v| v as f64);
+    let dt = settings.dt;
+    let accelerations =
+        |states: &[Particle]| reference_accelerations(states, particle_types, fields, settings);
+    let drag = |particle: &Particle| particle_types.get_particle_drag(particle.type_index);
+    let mut next = particles.to_vec();
+    match settings.integrator {
+        Integrator::SemiImplicitEuler => {
+            let acc = accelerations(particles);
+            for (particle, a) in next.iter_mut().zip(&acc) {
+                let keep = drag(particle).powf(dt);
+                particle.vel = [0, 1].map(|axis| (particle.vel[axis] + a[axis] * dt) * keep);
+                particle.pos = [0, 1].map(|axis| particle.pos[axis] + particle.vel[axis] * dt);
+            }
+        }
+        Integrator::VelocityVerlet => {
+            let acc = accelerations(particles);
+            for (particle, a) in next.iter_mut().zip(&acc) {
+                particle.vel = [0, 1].map(|axis| particle.vel[axis] + 0.5 * a[axis] * dt);
+                particle.pos = [0, 1].map(|axis| particle.pos[axis] + particle.vel[axis] * dt);
+                confine(settings, particle);
+            }
+            let acc = accelerations(&next);
+            for (particle, a) in next.iter_mut().zip(&acc) {
+                let keep = drag(particle).powf(dt);
+                particle.vel = [0, 1].map(|axis| (particle.vel[axis] + 0.5 * a[axis] * dt) * keep);
+            }
+        }
+        Integrator::Rk4 => {
+            // The state is (position, velocity) with the derivative
+            // (velocity, acceleration - gamma * velocity).
+            let derivative = |states: &[Particle]| {
+                return states
+                    .iter()
+                    .zip(accelerations(states))
+                    .map(|(state, a)| {
+                        let gamma = -drag(state).ln();
+                        let dv = [0, 1].map(|axis| a[axis] - gamma * state.vel[axis]);
+                        return (state.vel, dv);
+                    })
+                    .collect::<Vec<_>>();
+            };
+            let advanced = |k: &[(Vec2d, Vec2d)], h: f64| {
+                return particles
+                    .iter()
+                    .zip(k)
+                    .map(|(particle, (dx, dv))| {
+                        let mut state = *particle;
+                        for axis in 0..2 {
+                            state.pos[axis] += dx[axis] * h;
+                            state.vel[axis] += dv[axis] * h;
+                        }
+                        // Intermediate states only wrap, the walls act on the
+                        // final one.
+                        if settings.boundary == Boundary::Periodic {
+                            wrap(&mut state.pos, &screen_size);
+                        }
+                        return state;
+                    })
+                    .collect::<Vec<_>>();
+            };
+            let k1 = derivative(particles);
+            let k2 = derivative(&advanced(&k1, 0.5 * dt));
+            let k3 = derivative(&advanced(&k2, 0.5 * dt));
+            let k4 = derivative(&advanced(&k3, dt));
+            for (i, particle) in next.iter_mut().enumerate() {
+                for axis in 0..2 {
+                    particle.pos[axis] +=
+                        (k1[i].0[axis] + 2.0 * k2[i].0[axis] + 2.0 * k3[i].0[axis] + k4[i].0[axis])
+                            * dt
+                            / 6.0;
+                    particle.vel[axis] +=
+                        (k1[i].1[axis] + 2.0 * k2[i].1[axis] + 2.0 * k3[i].1[axis] + k4[i].1[axis])
+                            * dt
+                            / 6.0;
+                }
+            }
+        }
+    }
+    for (particle, previous) in next.iter_mut().zip(particles) {
+        if settings.integrator != Integrator::VelocityVerlet {
+            confine(settings, particle);
+        }
+        // Where the particle started, seen from where it ended up.
+        let mut moved = [0, 1].map(|axis| particle.pos[axis] - previous.pos[axis]);
+        settings.boundary.minimum_image(&mut moved, &screen_size);
+        let start = [0, 1].map(|axis| particle.pos[axis] - moved[axis]);
+        for obstacle in &obstacles.obstacles {
+            if !obstacle.permeable_to.contains(&particle.type_index) {
+                reference_collide(&obstacle.shape, &start, particle, settings);
+            }
+        }
+    }
+    next.retain(|particle| !settings.boundary.absorbs(&particle.pos, &screen_size));
+    return next;
+}
+
+fn confine(settings: &SceneSettings, particle: &mut Particle) {
+    let screen_size = settings.screen_size.map(|v| v as f64);
+    settings.boundary.confine(
+        &mut particle.pos,
+        &mut particle.vel,
+        &screen_size,
+        settings.restitution,
+    );
+}
+
+/// Acceleration of every particle from the other particles and the fields.
+fn reference_accelerations(
+    particles: &[Particle],
+    particle_types: &ParticleTypeManager,
+    fields: &FieldSet,
+    settings: &SceneSettings,
+) -> Vec<Vec2d> {
+    let screen_size = settings.screen_size.map(|v| v as f64);
+    return particles
+        .iter()
+        .enumerate()
+        .map(|(i, particle)| {
+            let a = particle.type_index;
+            let mut force = [0.0, 0.0];
+            for (j, other) in particles.iter().enumerate() {
+                if i == j {
+                    continue;
+                }
+                let mut direction = other.pos;
+                sub(&mut direction, &particle.pos);
+                settings
+                    .boundary
+                    .minimum_image(&mut direction, &screen_size);
+                let distance = len(&direction);
+                let magnitude = reference_magnitude(
+                    settings.force_law,
+                    distance,
+                    particle_types.get_forces(a, other.type_index),
+                    particle_types.get_min_distance(a, other.type_index),
+                    particle_types.get_radii(a, other.type_index),
+                );
+                for axis in 0..2 {
+                    force[axis] += direction[axis] / distance * magnitude;
+                }
+            }
+            let mass = particle_types.get_particle_mass(a);
+            let field = reference_field(fields, a, &particle.pos, settings);
+            return [0, 1].map(|axis| force[axis] / mass + field[axis]);
+        })
+        .collect();
+}
+
+/// The built-in force laws, towards the other particle when positive.
+fn reference_magnitude(
+    law: ForceLawKind,
+    distance: f64,
+    strength: f64,
+    min_distance: f64,
+    radius: f64,
+) -> f64 {
+    let repulsion = -strength.abs();
+    let magnitude = match law {
+        ForceLawKind::PiecewiseLinear => {
+            let mut magnitude = 0.0;
+            if distance < min_distance {
+                magnitude += 6.0 * repulsion * remap(distance, 0.0, min_distance, 1.1, 0.0);
+            }
+            if distance < radius {
+                magnitude += strength * remap(distance, 0.0, radius, 1.0, 0.0);
+            }
+            magnitude
+        }
+        ForceLawKind::LennardJones => {
+            if distance >= radius.max(min_distance) {
+                return 0.0;
+            }
+            let x = min_distance / distance.max(0.8 * min_distance);
+            strength * x.powi(7) + repulsion * x.powi(13)
+        }
+        ForceLawKind::Gaussian => {
+            let gaussian = |x: f64, width: f64| (-0.5 * (x / width).powi(2)).exp();
+            let mut magnitude = 0.0;
+            if distance < min_distance {
+                magnitude += 6.6 * repulsion * gaussian(distance, 0.5 * min_distance);
+            }
+            if distance < radius {
+                magnitude += strength * gaussian(distance - 0.5 * radius, radius / 6.0);
+            }
+            magnitude
+        }
+        ForceLawKind::SmoothStep => {
+            let falloff = |x: f64| {
+                let x = x.clamp(0.0, 1.0);
+                return 1.0 - x * x * (3.0 - 2.0 * x);
+            };
+            let mut magnitude = 0.0;
+            if distance < min_distance {
+                magnitude += 6.6 * repulsion * falloff(distance / min_distance);
+            }
+            if distance < radius {
+                magnitude += strength * falloff(distance / radius);
+            }
+            magnitude
+        }
+    };
+    return magnitude * K;
+}
+
+/// Sum of the field accelerations on a particle of `type_index` at `pos`.
+fn reference_field(
+    fields: &FieldSet,
+    type_index: usize,
+    pos: &Vec2d,
+    settings: &SceneSettings,
+) -> Vec2d {
+    let screen_size = settings.screen_size.map(|v| v as f64);
+    let mut total = [0.0, 0.0];
+    for field in &fields.fields {
+        let response = field.response.get(type_index).copied().unwrap_or(1.0);
+        let acceleration = match &field.kind {
+            FieldKind::Gravity { acceleration } => *acceleration,
+            FieldKind::Attractor {
+                center,
+                strength,
+                radius,
+                softening,
+            }
+            | FieldKind::Vortex {
+                center,
+                strength,
+                radius,
+                softening,
+            } => {
+                let mut offset = *center;
+                sub(&mut offset, pos);
+                settings.boundary.minimum_image(&mut offset, &screen_size);
+                let distance = len(&offset);
+                let in_range = radius.is_none_or(|radius| distance < radius);
+                if distance == 0.0 || !in_range {
+                    continue;
+                }
+                let pull = strength / (distance.powi(2) + softening.powi(2));
+                let inward = offset.map(|v| v / distance * pull);
+                match field.kind {
+                    FieldKind::Vortex { .. } => [inward[1], -inward[0]],
+                    _ => inward,
+                }
+            }
+            FieldKind::Flow {
+                origin,
+                cell_size,
+                strength,
+                width,
+                height,
+                vectors,
+                ..
+            } => {
+                let grid = [0, 1].map(|axis| (pos[axis] - origin[axis]) / cell_size);
+                let size = [*width, *height];
+                if (0..2).any(|axis| grid[axis] < 0.0 || grid[axis] >= size[axis] as f64) {
+                    continue;
+                }
+                // Blend the four closest cell centres.
+                let sample =
+                    [0, 1].map(|axis| (grid[axis] - 0.5).clamp(0.0, (size[axis] - 1) as f64));
+                let low = sample.map(|v| v.floor());
+                let mut blended = [0.0, 0.0];
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let x = (low[0] as usize + dx).min(width - 1);
+                    let y = (low[1] as usize + dy).min(height - 1);
+                    let wx = if dx == 1 {
+                        sample[0] - low[0]
+                    } else {
+                        1.0 - (sample[0] - low[0])
+                    };
+                    let wy = if dy == 1 {
+                        sample[1] - low[1]
+                    } else {
+                        1.0 - (sample[1] - low[1])
+                    };
+                    for axis in 0..2 {
+                        blended[axis] += vectors[y * width + x][axis] * wx * wy;
+                    }
+                }
+                blended.map(|v| v * strength)
+            }
+        };
+        for axis in 0..2 {
+            total[axis] += acceleration[axis] * response;
+        }
+    }
+    return total;
+}
+
+/// Stops a particle at the first point where its straight path from
+/// `previous` runs into `shape` and bounces it off. Particles that started
+/// inside are pushed back to the surface instead. Masks have no surface, so
+/// the axes that moved the particle from `previous` into a solid cell are
+/// undone.
+fn reference_collide(
+    shape: &Shape,
+    previous: &Vec2d,
+    particle: &mut Particle,
+    settings: &SceneSettings,
+) {
+    let restitution = settings.restitution;
+    let bounce = |vel: Vec2d, normal: Vec2d| {
+        let normal_speed = vel[0] * normal[0] + vel[1] * normal[1];
+        if normal_speed >= 0.0 {
+            return vel;
+        }
+        let reflected = (1.0 + restitution) * normal_speed;
+        return [0, 1].map(|axis| vel[axis] - reflected * normal[axis]);
+    };
+    if let Shape::Mask {
+        origin,
+        cell_size,
+        rows,
+        ..
+    } = shape
+    {
+        let solid = |pos: &Vec2d| {
+            let cell = [0, 1].map(|axis| ((pos[axis] - origin[axis]) / cell_size).floor());
+            if cell[0] < 0.0 || cell[1] < 0.0 {
+                return false;
+            }
+            return rows
+                .get(cell[1] as usize)
+                .and_then(|row| row.as_bytes().get(cell[0] as usize))
+                == Some(&b'#');
+        };
+        let pos = particle.pos;
+        if !solid(&pos) || solid(previous) {
+            return;
+        }
+        if !solid(&[pos[0], previous[1]]) {
+            particle.pos[1] = previous[1];
+            particle.vel[1] *= -restitution;
+        } else if !solid(&[previous[0], pos[1]]) {
+            particle.pos[0] = previous[0];
+            particle.vel[0] *= -restitution;
+        } else {
+            particle.pos = *previous;
+            particle.vel = particle.vel.map(|v| -v * restitution);
+        }
+        return;
+    }
+
+    // Walk the path in steps of the distance to the surface.
+    let path = [0, 1].map(|axis| particle.pos[axis] - previous[axis]);
+    let path_length = len(&path);
+    if path_length > 0.0 {
+        let mut t: f64 = 0.0;
+        for _ in 0..32 {
+            let point = [0, 1].map(|axis| previous[axis] + path[axis] * t);
+            let (distance, normal) = reference_signed_distance(shape, &point);
+            if distance < -1e-3 {
+                break;
+            }
+            if distance < 1e-3 && path[0] * normal[0] + path[1] * normal[1] < 0.0 {
+                particle.pos = [0, 1].map(|axis| point[axis] - normal[axis] * distance);
+                particle.vel = bounce(particle.vel, normal);
+                return;
+            }
+            if t >= 1.0 {
+                break;
+            }
+            t = (t + distance.max(1e-3) / path_length).min(1.0);
+        }
+    }
+
+    let (distance, normal) = reference_signed_distance(shape, &particle.pos);
+    if distance >= 0.0 {
+        return;
+    }
+    particle.pos = [0, 1].map(|axis| particle.pos[axis] - normal[axis] * distance);
+    particle.vel = bounce(particle.vel, normal);
+}
+
+/// Distance from `pos` to the surface of `shape`, negative inside, and the
+/// direction out of the shape at the closest surface point. Not for masks.
+fn reference_signed_distance(shape: &Shape, pos: &Vec2d) -> (f64, Vec2d) {
+    let away = |from: Vec2d, fallback: Vec2d| {
+        let offset = [pos[0] - from[0], pos[1] - from[1]];
+        let distance = len(&offset);
+        return match distance {
+            0.0 => (0.0, fallback),
+            _ => (distance, offset.map(|v| v / distance)),
+        };
+    };
+    match shape {
+        Shape::Circle { center, radius } => {
+            let (distance, normal) = away(*center, [1.0, 0.0]);
+            return (distance - radius, normal);
+        }
+        Shape::Segment {
+            start,
+            end,
+            thickness,
+        } => {
+            let closest = reference_closest_on_segment(pos, start, end);
+            let across = reference_unit_or_x([start[1] - end[1], end[0] - start[0]]);
+            let (distance, normal) = away(closest, across);
+            return (distance - 0.5 * thickness, normal);
+        }
+        Shape::Polygon { points } => {
+            let edges = || (0..points.len()).map(|i| (&points[i], &points[(i + 1) % points.len()]));
+            let mut inside = false;
+            for (a, b) in edges() {
+                let crosses = (a[1] > pos[1]) != (b[1] > pos[1]);
+                if crosses && pos[0] < a[0] + (pos[1] - a[1]) * (b[0] - a[0]) / (b[1] - a[1]) {
+                    inside = !inside;
+                }
+            }
+            let mut closest = (f64::INFINITY, *pos, [1.0, 0.0]);
+            for (a, b) in edges() {
+                let candidate = reference_closest_on_segment(pos, a, b);
+                let distance = len(&[candidate[0] - pos[0], candidate[1] - pos[1]]);
+                if distance < closest.0 {
+                    closest = (distance, candidate, [b[1] - a[1], a[0] - b[0]]);
+                }
+            }
+            let (edge_distance, surface, edge) = closest;
+            // The edge normal points out for counter-clockwise polygons only.
+            let twice_area = edges().map(|(a, b)| a[0] * b[1] - b[0] * a[1]).sum::<f64>();
+            let edge_normal = reference_unit_or_x(edge.map(|v| v * twice_area.signum()));
+            if edge_distance < 1e-3 {
+                let sign = if inside { -1.0 } else { 1.0 };
+                return (sign * edge_distance, edge_normal);
+            }
+            let (distance, normal) = away(surface, edge_normal);
+            if inside && distance > 0.0 {
+                return (-distance, normal.map(|v| -v));
+            }
+            return (distance, normal);
+        }
+        Shape::Mask { .. } => unreachable!("masks have no surface"),
+    }
+}
+
+fn reference_closest_on_segment(pos: &Vec2d, start: &Vec2d, end: &Vec2d) -> Vec2d {
+    let along = [end[0] - start[0], end[1] - start[1]];
+    let length_squared = along[0] * along[0] + along[1] * along[1];
+    let mut t = 0.0;
+    if length_squared > 0.0 {
+        t = ((pos[0] - start[0]) * along[0] + (pos[1] - start[1]) * along[1]) / length_squared;
+        t = t.clamp(0.0, 1.0);
+    }
+    return [start[0] + t * along[0], start[1] + t * along[1]];
+}
+
+fn reference_unit_or_x(v: Vec2d) -> Vec2d {
+    let length = len(&v);
+    if length == 0.0 {
+        return [1.0, 0.0];
+    }
+    return v.map(|v| v / length);
+}
+
+/// Steps `S` and the reference `steps` times from `initial` and compares them.
+pub async fn check_backend<S: SceneLike>(
+    backend: &str,
+    initial: &Snapshot,
+    steps: usize,
+    tolerances: Tolerances,
+) -> BackendReport {
+    let mut scene: S = initial
+        .restore()
+        .await
+        .expect("Conformance snapshot should be valid for every backend");
+    let particle_types = initial
+        .particle_types()
+        .expect("Conformance snapshot should contain valid rules");
+    let screen_size = initial.settings.screen_size.map(|v| v as f64);
+    let mut reference = initial.particles.clone();
+    let mut report = BackendReport {
+        backend: backend.to_string(),
+        steps,
+        tolerances,
+        max_position_per_step: Vec::with_capacity(steps),
+        max_velocity_per_step: Vec::with_capacity(steps),
+        particles: vec![],
+        count_mismatch: None,
+    };
+    for _ in 0..steps {
+        scene.update().await;
+        for _ in 0..initial.settings.substeps {
+            reference = reference_step(
+                &reference,
+                &particle_types,
+                &initial.obstacles,
+                &initial.fields,
+                &initial.settings,
+            );
+        }
+        let actual = scene.get_particles();
+        if actual.len() != reference.len() {
+            report.count_mismatch = Some((reference.len(), actual.len()));
+            return report;
+        }
+        report.particles = actual
+            .iter()
+            .zip(&reference)
+            .enumerate()
+            .map(|(index, (a, r))| {
+                let mut position = a.pos;
+                sub(&mut position, &r.pos);
+                let mut velocity = a.vel;
+                sub(&mut velocity, &r.vel);
+                return ParticleDivergence {
+                    index,
+                    position: len(initial
+                        .settings
+                        .boundary
+                        .minimum_image(&mut position, &screen_size)),
+                    velocity: len(&velocity),
+                };
+            })
+            .collect();
+        report.max_position_per_step.push(
+            report
+                .particles
+                .iter()
+                .map(|p| p.position)
+                .fold(0.0, nan_max),
+        );
+        report.max_velocity_per_step.push(
+            report
+                .particles
+                .iter()
+                .map(|p| p.velocity)
+                .fold(0.0, nan_max),
+        );
+    }
+    return report;
+}
+
+/// Checks every backend. The GPU backend is skipped when no adapter exists.
+pub async fn run_conformance(
+    initial: &Snapshot,
+    steps: usize,
+    tolerances: Tolerances,
+) -> Vec<BackendReport> {
+    let mut reports = vec![
+        check_backend::<MultithreadedScene>("multithreaded", initial, steps, tolerances).await,
+        check_backend::<MultithreadedSceneV2>("multithreaded-v2", initial, steps, tolerances).await,
+    ];
+    if WgpuScene::is_available().await {
+        reports.push(check_backend::<WgpuScene>("wgpu", initial, steps, tolerances).await);
+    }
+    return reports;
+}
+
+/// `f64::max` that keeps NaN, so a NaN divergence is never hidden.
+fn nan_max(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        return f64::NAN;
+    }
+    return a.max(b);
+}
@@ -1,4 +1,6 @@
 pub const THREAD_COUNT: usize = 20;
 pub const K: f64 = 0.034;
-#[allow(dead_code)]
+pub const DEFAULT_SUBSTEPS: usize = 1;
+pub const DEFAULT_DT: f64 = 1.0;
+pub const DEFAULT_RESTITUTION: f64 = 1.0;
 pub const BENCHMARK_RUNS: usize = 1_000;
@@ -0,0 +1,290 @@
+use std::{path::Path, sync::Arc, time::Duration, time::Instant};
+
+use particle_simulation::{
+    particle_renderer::{ParticleRenderer, Viewport},
+    SceneLike, WgpuScene,
+};
+
+use crate::{
+    camera::Camera,
+    viewer::{print_particle_types, save_rules, SimulationClock},
+};
+use winit::{
+    application::ApplicationHandler,
+    dpi::PhysicalSize,
+    event::{ElementState, KeyEvent, MouseButton, MouseScrollDelta, WindowEvent},
+    event_loop::{ActiveEventLoop, EventLoop},
+    keyboard::{Key, NamedKey},
+    window::{Window, WindowId},
+};
+
+struct WindowState {
+    window: Arc<Window>,
+    surface: wgpu::Surface<'static>,
+    config: wgpu::SurfaceConfiguration,
+    renderer: ParticleRenderer,
+}
+
+struct DirectViewer<'a> {
+    scene: &'a mut WgpuScene,
+    state: Option<WindowState>,
+    window_size: [u32; 2],
+    camera: Camera,
+    cursor: [f64; 2],
+    panning: bool,
+    clock: SimulationClock,
+    stats: Stats,
+    rules_path: Option<&'a Path>,
+}
+
+/// Scroll distance of one mouse wheel notch on touchpads, which report
+/// pixels.
+const LINE_PIXELS: f64 = 40.0;
+
+/// Frame rate and update time for the window title, averaged over about a
+/// second.
+struct Stats {
+    since: Instant,
+    frames: u32,
+    updates: u32,
+    update_time: Duration,
+    fps: f64,
+    update_ms: f64,
+}
+
+impl Stats {
+    fn new() -> Stats {
+        return Stats {
+            since: Instant::now(),
+            frames: 0,
+            updates: 0,
+            update_time: Duration::ZERO,
+            fps: 0.0,
+            update_ms: 0.0,
+        };
+    }
+
+    fn record_update(&mut self, duration: Duration) {
+        self.updates += 1;
+        self.update_time += duration;
+    }
+
+    fn record_frame(&mut self) {
+        self.frames += 1;
+        let elapsed = self.since.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return;
+        }
+        self.fps = self.frames as f64 / elapsed.as_secs_f64();
+        if self.updates > 0 {
+            self.update_ms = self.update_time.as_secs_f64() * 1000.0 / self.updates as f64;
+        }
+        self.since = Instant::now();
+        self.frames = 0;
+        self.updates = 0;
+        self.update_time = Duration::ZERO;
+    }
+}
+
+/// Same keys as `viewer::display`, but the particles are drawn from the
+/// compute buffers and never read back, so there are no mouse tools and the
+/// camera cannot follow particles. The window title shows the frame rate,
+/// update time and particle count.
+pub fn display(scene: &mut WgpuScene, window_size: [u32; 2], rules_path: Option<&Path>) {
+    print_particle_types(scene);
+    scene.set_readback(false);
+    let event_loop = EventLoop::new().expect("Event loop should be available");
+    let camera = Camera::fitted(
+        scene.get_settings().screen_size,
+        window_size.map(|v| v as f64),
+    );
+    let mut viewer = DirectViewer {
+        scene,
+        state: None,
+        window_size,
+        camera,
+        cursor: [0.0, 0.0],
+        panning: false,
+        clock: SimulationClock::new(),
+        stats: Stats::new(),
+        rules_path,
+    };
+    event_loop
+        .run_app(&mut viewer)
+        .expect("Event loop should run until the window is closed");
+    pollster::block_on(viewer.scene.read_back());
+    viewer.scene.set_readback(true);
+}
+
+impl DirectViewer<'_> {
+    fn configure_surface(&mut self, size: PhysicalSize<u32>) {
+        let Some(state) = &mut self.state else {
+            return;
+        };
+        state.config.width = size.width.max(1);
+        state.config.height = size.height.max(1);
+        state.surface.configure(self.scene.device(), &state.config);
+    }
+
+    fn surface_size(&self) -> [f64; 2] {
+        return match &self.state {
+            Some(state) => [state.config.width as f64, state.config.height as f64],
+            None => self.window_size.map(|v| v as f64),
+        };
+    }
+
+    fn key_pressed(&mut self, key: &Key, event_loop: &ActiveEventLoop) {
+        match key {
+            Key::Named(NamedKey::Escape) => event_loop.exit(),
+            Key::Named(NamedKey::Space) => self.clock.paused = !self.clock.paused,
+            Key::Named(NamedKey::ArrowRight) => self.clock.single_step(),
+            Key::Named(NamedKey::ArrowUp) => self.clock.faster(),
+            Key::Named(NamedKey::ArrowDown) => self.clock.slower(),
+            Key::Character(c) if c == "0" => self.camera.fit(self.surface_size()),
+            Key::Character(c) if c.eq_ignore_ascii_case("s") => {
+                save_rules(&*self.scene, self.rules_path);
+            }
+            Key::Character(c) if c.eq_ignore_ascii_case("n") => {
+                let seed = self.scene.new_world();
+                println!("New world! (seed {})", seed);
+                print_particle_types(&*self.scene);
+            }
+            _ => {}
+        }
+    }
+
+    fn redraw(&mut self) {
+        for _ in 0..self.clock.updates_this_frame() {
+            let start = Instant::now();
+            pollster::block_on(self.scene.update());
+            self.stats.record_update(start.elapsed());
+        }
+        self.stats.record_frame();
+
+        let Some(state) = &self.state else {
+            return;
+        };
+        state.window.set_title(&format!(
+            "Simulation window - {:.0} FPS - update {:.2}ms - {} particles - step {} - speed x{}{}",
+            self.stats.fps,
+            self.stats.update_ms,
+            self.scene.get_settings().particle_count,
+            self.clock.steps,
+            self.clock.speed,
+            if self.clock.paused { " - paused" } else { "" }
+        ));
+        let frame = match state.surface.get_current_texture() {
+            Ok(frame) => frame,
+            Err(wgpu::SurfaceError::Outdated | wgpu::SurfaceError::Lost) => {
+                let size = state.window.inner_size();
+                self.configure_surface(size);
+                return;
+            }
+            Err(err) => {
+                eprintln!("Could not get the next frame: {}", err);
+                return;
+            }
+        };
+        let view = frame.texture.create_view(&Default::default());
+        let viewport = Viewport {
+            size: [state.config.width, state.config.height],
+            center: self.camera.center(),
+            zoom: self.camera.zoom(),
+        };
+        self.scene.render(&state.renderer, &view, &viewport);
+        state.window.pre_present_notify();
+        frame.present();
+    }
+}
+
+impl ApplicationHandler for DirectViewer<'_> {
+    fn resumed(&mut self, event_loop: &ActiveEventLoop) {
+        if self.state.is_some() {
+            return;
+        }
+        let window_size = self.window_size;
+        let window = Arc::new(
+            event_loop
+                .create_window(
+                    Window::default_attributes()
+                        .with_title("Simulation window")
+                        .with_inner_size(PhysicalSize::new(window_size[0], window_size[1])),
+                )
+                .expect("Window should be created"),
+        );
+        let surface = self
+            .scene
+            .instance()
+            .create_surface(Arc::clone(&window))
+            .expect("Surface should be created for the window");
+        let size = window.inner_size();
+        let mut config = surface
+            .get_default_config(self.scene.adapter(), size.width.max(1), size.height.max(1))
+            .expect("Simulation adapter should be able to present to the window");
+        // Piston does not convert colors to sRGB, so neither do we.
+        if let Some(format) = surface
+            .get_capabilities(self.scene.adapter())
+            .formats
+            .into_iter()
+            .find(|f| !f.is_srgb())
+        {
+            config.format = format;
+        }
+        surface.configure(self.scene.device(), &config);
+        let renderer = ParticleRenderer::new(self.scene.device(), config.format);
+        window.request_redraw();
+        self.state = Some(WindowState {
+            window,
+            surface,
+            config,
+            renderer,
+        });
+        // The window may have come out at another size than asked for.
+        self.camera.fit(self.surface_size());
+    }
+
+    fn window_event(&mut self, event_loop: &ActiveEventLoop, _: WindowId, event: WindowEvent) {
+        match event {
+            WindowEvent::CloseRequested => event_loop.exit(),
+            WindowEvent::Resized(size) => self.configure_surface(size),
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        logical_key,
+                        state: ElementState::Pressed,
+                        repeat: false,
+                        ..
+                    },
+                ..
+            } => self.key_pressed(&logical_key, event_loop),
+            WindowEvent::CursorMoved { position, .. } => {
+                let position = [position.x, position.y];
+                if self.panning {
+                    self.camera
+                        .pan([position[0] - self.cursor[0], position[1] - self.cursor[1]]);
+                }
+                self.cursor = position;
+            }
+            WindowEvent::MouseInput {
+                state,
+                button: MouseButton::Right,
+                ..
+            } => self.panning = state == ElementState::Pressed,
+            WindowEvent::MouseWheel { delta, .. } => {
+                let steps = match delta {
+                    MouseScrollDelta::LineDelta(_, lines) => lines as f64,
+                    MouseScrollDelta::PixelDelta(pixels) => pixels.y / LINE_PIXELS,
+                };
+                let window_size = self.surface_size();
+                self.camera.zoom_at(self.cursor, window_size, steps);
+            }
+            WindowEvent::RedrawRequested => {
+                self.redraw();
+                if let Some(state) = &self.state {
+                    state.window.request_redraw();
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::{fmt, io, path::Path};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// A file that could be read but not understood.
+    Parse(String),
+    /// Rule matrices or per-type parameters that do not fit together.
+    InvalidRules(String),
+    /// Obstacle shapes that cannot be collided with.
+    InvalidObstacles(String),
+    /// Force fields that cannot be evaluated.
+    InvalidFields(String),
+    /// Scene settings outside their valid range.
+    InvalidConfig(String),
+    /// State that cannot be loaded into the scene it was given to.
+    Mismatch(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Names the file the error came from without changing its kind.
+    pub fn in_file(self, path: &Path) -> Error {
+        let context = |message: String| format!("{}: {}", path.display(), message);
+        return match self {
+            Error::Io(err) => Error::Io(io::Error::new(err.kind(), context(err.to_string()))),
+            Error::Parse(message) => Error::Parse(context(message)),
+            Error::InvalidRules(message) => Error::InvalidRules(context(message)),
+            Error::InvalidObstacles(message) => Error::InvalidObstacles(context(message)),
+            Error::InvalidFields(message) => Error::InvalidFields(context(message)),
+            Error::InvalidConfig(message) => Error::InvalidConfig(context(message)),
+            Error::Mismatch(message) => Error::Mismatch(context(message)),
+        };
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{err}"),
+            Error::Parse(message) => write!(f, "parse error: {message}"),
+            Error::InvalidRules(message) => write!(f, "invalid particle rules: {message}"),
+            Error::InvalidObstacles(message) => write!(f, "invalid obstacles: {message}"),
+            Error::InvalidFields(message) => write!(f, "invalid force fields: {message}"),
+            Error::InvalidConfig(message) => write!(f, "invalid scene config: {message}"),
+            Error::Mismatch(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        return Error::Io(err);
+    }
+}
@@ -0,0 +1,358 @@
+//! External force fields acting on every particle, stored as TOML.
+//!
+//! ```toml
+//! [[fields]]
+//! kind = "gravity"
+//! acceleration = [0.0, 0.05]
+//!
+//! # Pulls particles in, type 2 is pushed away instead.
+//! [[fields]]
+//! kind = "attractor"
+//! center = [400.0, 300.0]
+//! strength = 200.0
+//! radius = 300.0
+//! response = [1.0, 1.0, -1.0]
+//!
+//! [[fields]]
+//! kind = "vortex"
+//! center = [800.0, 300.0]
+//! strength = 150.0
+//!
+//! # Red and green encode x and y from -1 to 1, one pixel per `cell_size`
+//! # world units. Inline fields use `width`, `height` and row-major `vectors`.
+//! [[fields]]
+//! kind = "flow"
+//! image = "wind.png"
+//! cell_size = 20.0
+//! strength = 0.1
+//! ```
+//!
+//! Every field yields an acceleration, scaled by the `response` of the
+//! particle's type. Types without an entry respond with 1.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use graphics::math::Vec2d;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Error, Result},
+    vector::{len, mul_scalar, sub},
+    SceneSettings,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum FieldKind {
+    /// The same acceleration everywhere.
+    Gravity { acceleration: Vec2d },
+    /// Radial pull of `strength / (distance² + softening²)` towards `center`.
+    /// Negative strengths repel.
+    Attractor {
+        center: Vec2d,
+        strength: f64,
+        /// No effect beyond this distance. Unlimited if left out.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        radius: Option<f64>,
+        #[serde(default = "default_softening")]
+        softening: f64,
+    },
+    /// Like an attractor, but turning counter-clockwise around `center`
+    /// instead of pulling towards it.
+    Vortex {
+        center: Vec2d,
+        strength: f64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        radius: Option<f64>,
+        #[serde(default = "default_softening")]
+        softening: f64,
+    },
+    /// A grid of vectors placed at cell centres from `origin` and sampled
+    /// bilinearly. Zero outside the grid.
+    Flow {
+        #[serde(default)]
+        origin: Vec2d,
+        #[serde(default = "default_cell_size")]
+        cell_size: f64,
+        #[serde(default = "default_strength")]
+        strength: f64,
+        #[serde(default)]
+        width: usize,
+        #[serde(default)]
+        height: usize,
+        #[serde(default)]
+        vectors: Vec<Vec2d>,
+        /// Image the vectors are read from by [`FieldSet::load`], if any.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        image: Option<PathBuf>,
+    },
+}
+
+fn default_softening() -> f64 {
+    return 10.0;
+}
+
+fn default_cell_size() -> f64 {
+    return 1.0;
+}
+
+fn default_strength() -> f64 {
+    return 1.0;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Field {
+    #[serde(flatten)]
+    pub kind: FieldKind,
+    /// Scale of the field's acceleration for each particle type.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub response: Vec<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct FieldSet {
+    #[serde(default)]
+    pub fields: Vec<Field>,
+}
+
+impl FieldKind {
+    /// Acceleration at `pos` before the per-type response.
+    fn acceleration(&self, pos: &Vec2d, settings: &SceneSettings) -> Vec2d {
+        let screen_size = settings.screen_size.map(|v| v as f64);
+        return match self {
+            FieldKind::Gravity { acceleration } => *acceleration,
+            FieldKind::Attractor {
+                center,
+                strength,
+                radius,
+                softening,
+            }
+            | FieldKind::Vortex {
+                center,
+                strength,
+                radius,
+                softening,
+            } => {
+                let mut towards = *center;
+                sub(&mut towards, pos);
+                settings.boundary.minimum_image(&mut towards, &screen_size);
+                let distance = len(&towards);
+                if distance == 0.0 || radius.is_some_and(|radius| distance >= radius) {
+                    return [0.0, 0.0];
+                }
+                let magnitude = strength / (distance * distance + softening * softening);
+                mul_scalar(&mut towards, magnitude / distance);
+                if let FieldKind::Vortex { .. } = self {
+                    // A quarter turn from the inward direction.
+                    return [towards[1], -towards[0]];
+                }
+                towards
+            }
+            FieldKind::Flow {
+                origin,
+                cell_size,
+                strength,
+                width,
+                height,
+                vectors,
+                ..
+            } => {
+                let u = (pos[0] - origin[0]) / cell_size;
+                let v = (pos[1] - origin[1]) / cell_size;
+                if u < 0.0 || v < 0.0 || u >= *width as f64 || v >= *height as f64 {
+                    return [0.0, 0.0];
+                }
+                // Offsets from the cell centres, clamped at the edges.
+                let x = (u - 0.5).clamp(0.0, (width - 1) as f64);
+                let y = (v - 0.5).clamp(0.0, (height - 1) as f64);
+                let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+                let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+                let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+                let at = |x: usize, y: usize| vectors[y * width + x];
+                [0, 1].map(|axis| {
+                    let top = at(x0, y0)[axis] * (1.0 - fx) + at(x1, y0)[axis] * fx;
+                    let bottom = at(x0, y1)[axis] * (1.0 - fx) + at(x1, y1)[axis] * fx;
+                    (top * (1.0 - fy) + bottom * fy) * strength
+                })
+            }
+        };
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        let valid = match self {
+            FieldKind::Gravity { .. } => true,
+            FieldKind::Attractor {
+                radius, softening, ..
+            }
+            | FieldKind::Vortex {
+                radius, softening, ..
+            } => *softening >= 0.0 && radius.is_none_or(|radius| radius > 0.0),
+            FieldKind::Flow {
+                cell_size,
+                width,
+                height,
+                vectors,
+                ..
+            } => *cell_size > 0.0 && vectors.len() == width * height,
+        };
+        if !valid {
+            return Err(Error::InvalidFields(format!("malformed field {:?}", self)));
+        }
+        return Ok(());
+    }
+}
+
+impl Field {
+    pub fn response(&self, type_index: usize) -> f64 {
+        return self.response.get(type_index).copied().unwrap_or(1.0);
+    }
+}
+
+impl FieldSet {
+    pub fn is_empty(&self) -> bool {
+        return self.fields.is_empty();
+    }
+
+    /// Sum of the accelerations of every field on a particle of `type_index`
+    /// at `pos`.
+    pub fn acceleration(&self, type_index: usize, pos: &Vec2d, settings: &SceneSettings) -> Vec2d {
+        let mut total = [0.0, 0.0];
+        for field in &self.fields {
+            let response = field.response(type_index);
+            if response == 0.0 {
+                continue;
+            }
+            let acceleration = field.kind.acceleration(pos, settings);
+            total[0] += acceleration[0] * response;
+            total[1] += acceleration[1] * response;
+        }
+        return total;
+    }
+
+    pub fn from_toml(source: &str) -> Result<FieldSet> {
+        let fields: FieldSet =
+            toml::from_str(source).map_err(|err| Error::Parse(err.to_string()))?;
+        for field in &fields.fields {
+            field.kind.validate()?;
+        }
+        return Ok(fields);
+    }
+
+    pub fn to_toml(&self) -> String {
+        return toml::to_string(self).expect("Fields should always be serializable to TOML");
+    }
+
+    /// Reads the fields and the flow images they refer to. Image paths are
+    /// relative to the field file.
+    pub fn load(path: &Path) -> Result<FieldSet> {
+        let mut fields = fs::read_to_string(path)
+            .map_err(Error::from)
+            .and_then(|source| FieldSet::from_toml(&source))
+            .map_err(|err| err.in_file(path))?;
+        let directory = path.parent().unwrap_or(Path::new(""));
+        for field in &mut fields.fields {
+            if let FieldKind::Flow {
+                width,
+                height,
+                vectors,
+                image: Some(image),
+                ..
+            } = &mut field.kind
+            {
+                *image = directory.join(&*image);
+                let pixels = image::open(&*image)
+                    .map_err(|err| Error::Parse(format!("{}: {}", image.display(), err)))?
+                    .into_rgb8();
+                *width = pixels.width() as usize;
+                *height = pixels.height() as usize;
+                *vectors = pixels
+                    .pixels()
+                    .map(|pixel| {
+                        let [red, green, _] = pixel.0;
+                        [red, green].map(|v| v as f64 / 255.0 * 2.0 - 1.0)
+                    })
+                    .collect();
+            }
+        }
+        return Ok(fields);
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_toml())?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> SceneSettings {
+        return toml::from_str(
+            "screen_size = [400, 300]\nparticle_count = 0\nparticle_types_count = 3\nseed = 0",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn response_scales_each_type() {
+        let fields = FieldSet::from_toml(
+            "[[fields]]\nkind = \"gravity\"\nacceleration = [0.0, 2.0]\nresponse = [1.0, -0.5]",
+        )
+        .unwrap();
+        let settings = settings();
+        assert_eq!(fields.acceleration(0, &[1.0, 1.0], &settings), [0.0, 2.0]);
+        assert_eq!(fields.acceleration(1, &[1.0, 1.0], &settings), [0.0, -1.0]);
+        // Types without an entry respond fully.
+        assert_eq!(fields.acceleration(2, &[1.0, 1.0], &settings), [0.0, 2.0]);
+    }
+
+    #[test]
+    fn attractors_pull_inside_their_radius_and_vortices_turn() {
+        let fields = FieldSet::from_toml(
+            "[[fields]]\nkind = \"attractor\"\ncenter = [100.0, 100.0]\nstrength = 500.0\nradius = 50.0\nsoftening = 0.0\n\n\
+             [[fields]]\nkind = \"vortex\"\ncenter = [300.0, 100.0]\nstrength = 500.0\nsoftening = 0.0",
+        )
+        .unwrap();
+        let settings = settings();
+        let pull = |pos: Vec2d| fields.fields[0].kind.acceleration(&pos, &settings);
+        // 500 / 10² towards the centre.
+        assert_eq!(pull([110.0, 100.0]), [-5.0, 0.0]);
+        assert_eq!(pull([100.0, 160.0]), [0.0, 0.0]);
+        let turn = fields.fields[1]
+            .kind
+            .acceleration(&[300.0, 110.0], &settings);
+        assert_eq!(turn, [-5.0, 0.0]);
+    }
+
+    #[test]
+    fn flow_interpolates_between_cell_centres() {
+        let fields = FieldSet::from_toml(
+            "[[fields]]\nkind = \"flow\"\norigin = [0.0, 0.0]\ncell_size = 10.0\nstrength = 2.0\n\
+             width = 2\nheight = 1\nvectors = [[1.0, 0.0], [0.0, 1.0]]",
+        )
+        .unwrap();
+        let settings = settings();
+        assert_eq!(fields.acceleration(0, &[5.0, 5.0], &settings), [2.0, 0.0]);
+        assert_eq!(fields.acceleration(0, &[10.0, 5.0], &settings), [1.0, 1.0]);
+        // Clamped at the edge cells, zero outside the grid.
+        assert_eq!(fields.acceleration(0, &[19.0, 1.0], &settings), [0.0, 2.0]);
+        assert_eq!(fields.acceleration(0, &[21.0, 5.0], &settings), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn malformed_fields_are_rejected() {
+        for source in [
+            "[[fields]]\nkind = \"attractor\"\ncenter = [0.0, 0.0]\nstrength = 1.0\nradius = 0.0",
+            "[[fields]]\nkind = \"flow\"\nwidth = 2\nheight = 2\nvectors = [[1.0, 0.0]]",
+        ] {
+            assert!(matches!(
+                FieldSet::from_toml(source),
+                Err(Error::InvalidFields(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Pairwise interaction laws.
+//!
+//! A [`ForceLaw`] turns the distance between two particles and the rules of
+//! their type pair into the magnitude of the force along the direction
+//! towards the other particle. Positive values attract, negative repel.
+
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use graphics::math::Vec2d;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    constants::K,
+    particle_type::ParticleTypeManager,
+    vector::{len, mul_scalar, normalize},
+};
+
+pub trait ForceLaw: Send + Sync {
+    /// `strength` is the `forces` entry of the type pair. The result has to be
+    /// zero beyond both `min_distance` and `radius`, since neighbour searches
+    /// skip particles farther away than that.
+    fn magnitude(&self, distance: f64, strength: f64, min_distance: f64, radius: f64) -> f64;
+}
+
+/// The original law: a linear repulsion ramp inside `min_distance` and a
+/// linear attraction ramp that fades out at `radius`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PiecewiseLinear;
+
+impl ForceLaw for PiecewiseLinear {
+    #[inline(always)]
+    fn magnitude(&self, distance: f64, strength: f64, min_distance: f64, radius: f64) -> f64 {
+        let mut magnitude = 0.0;
+        if distance < min_distance {
+            magnitude += strength.abs() * -6.0 * (1.1 - 1.1 * distance / min_distance) * K;
+        }
+        if distance < radius {
+            magnitude += strength * (1.0 - distance / radius) * K;
+        }
+        return magnitude;
+    }
+}
+
+/// Lennard-Jones shaped law with `min_distance` as the equilibrium distance
+/// of attracting pairs. The core is clamped at `0.8 * min_distance` so close
+/// encounters do not explode.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LennardJones;
+
+impl ForceLaw for LennardJones {
+    #[inline(always)]
+    fn magnitude(&self, distance: f64, strength: f64, min_distance: f64, radius: f64) -> f64 {
+        if distance >= radius.max(min_distance) {
+            return 0.0;
+        }
+        let x = min_distance / distance.max(0.8 * min_distance);
+        let x7 = x.powi(7);
+        return (strength * x7 - strength.abs() * x7 * x7 / x) * K;
+    }
+}
+
+/// Gaussian repulsion around the particle plus a Gaussian attraction bump
+/// centred halfway to `radius`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Gaussian;
+
+impl ForceLaw for Gaussian {
+    #[inline(always)]
+    fn magnitude(&self, distance: f64, strength: f64, min_distance: f64, radius: f64) -> f64 {
+        let mut magnitude = 0.0;
+        if distance < min_distance {
+            let width = 0.5 * min_distance;
+            magnitude +=
+                strength.abs() * -6.6 * (-(distance * distance) / (2.0 * width * width)).exp();
+        }
+        if distance < radius {
+            let offset = distance - 0.5 * radius;
+            let width = radius / 6.0;
+            magnitude += strength * (-(offset * offset) / (2.0 * width * width)).exp();
+        }
+        return magnitude * K;
+    }
+}
+
+/// Same ramps as [`PiecewiseLinear`], eased with smoothstep so the force has
+/// no kinks at `min_distance` and `radius`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SmoothStep;
+
+impl ForceLaw for SmoothStep {
+    #[inline(always)]
+    fn magnitude(&self, distance: f64, strength: f64, min_distance: f64, radius: f64) -> f64 {
+        let mut magnitude = 0.0;
+        if distance < min_distance {
+            magnitude += strength.abs() * -6.6 * (1.0 - smoothstep(distance / min_distance));
+        }
+        if distance < radius {
+            magnitude += strength * (1.0 - smoothstep(distance / radius));
+        }
+        return magnitude * K;
+    }
+}
+
+#[inline(always)]
+fn smoothstep(x: f64) -> f64 {
+    let x = x.clamp(0.0, 1.0);
+    return x * x * (3.0 - 2.0 * x);
+}
+
+/// The built-in laws. These are the ones every backend, including the GPU
+/// kernel, can run; custom [`ForceLaw`]s only work on the CPU scenes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForceLawKind {
+    #[default]
+    PiecewiseLinear,
+    LennardJones,
+    Gaussian,
+    SmoothStep,
+}
+
+impl ForceLawKind {
+    pub fn law(&self) -> Arc<dyn ForceLaw> {
+        return match self {
+            ForceLawKind::PiecewiseLinear => Arc::new(PiecewiseLinear),
+            ForceLawKind::LennardJones => Arc::new(LennardJones),
+            ForceLawKind::Gaussian => Arc::new(Gaussian),
+            ForceLawKind::SmoothStep => Arc::new(SmoothStep),
+        };
+    }
+}
+
+/// Force applied to a particle of `type_a` by a particle of `type_b` that is
+/// `direction` away, already passed through
+/// [`Boundary::minimum_image`](crate::boundary::Boundary::minimum_image).
+#[inline(always)]
+pub fn pair_force(
+    law: &dyn ForceLaw,
+    particle_types: &ParticleTypeManager,
+    type_a: usize,
+    type_b: usize,
+    mut direction: Vec2d,
+) -> Vec2d {
+    let distance = len(&direction);
+    normalize(&mut direction);
+    let magnitude = law.magnitude(
+        distance,
+        particle_types.get_forces(type_a, type_b),
+        particle_types.get_min_distance(type_a, type_b),
+        particle_types.get_radii(type_a, type_b),
+    );
+    mul_scalar(&mut direction, magnitude);
+    return direction;
+}
@@ -0,0 +1,138 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error, scene_like::SceneLike, snapshot::Snapshot, trajectory::TrajectoryWriter, Particle,
+};
+
+/// Stop conditions and output location of a headless run.
+///
+/// The run ends after `steps` updates or once `time_budget` is spent,
+/// whichever comes first. At least one of them should be set.
+#[derive(Debug, Clone)]
+pub struct HeadlessOptions {
+    pub steps: Option<usize>,
+    pub time_budget: Option<Duration>,
+    pub output_dir: PathBuf,
+    /// Save `checkpoint.psnap` every this many steps.
+    pub checkpoint_every: Option<usize>,
+    /// Record a trajectory to this file, one frame every `record_every` steps.
+    pub trajectory: Option<PathBuf>,
+    pub record_every: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct HeadlessReport {
+    pub step_times: Vec<Duration>,
+    pub total_time: Duration,
+}
+
+impl HeadlessReport {
+    pub fn steps(&self) -> usize {
+        return self.step_times.len();
+    }
+
+    pub fn average_step_time(&self) -> Duration {
+        if self.step_times.is_empty() {
+            return Duration::ZERO;
+        }
+        return self.step_times.iter().sum::<Duration>() / self.step_times.len() as u32;
+    }
+}
+
+/// Steps `scene` until one of the stop conditions is met, then writes
+/// `particles.csv`, `timings.csv` and a final `snapshot.psnap` into
+/// `options.output_dir`.
+pub async fn run_headless<S: SceneLike>(
+    scene: &mut S,
+    options: &HeadlessOptions,
+) -> error::Result<HeadlessReport> {
+    fs::create_dir_all(&options.output_dir)?;
+    let mut trajectory = match &options.trajectory {
+        Some(path) => {
+            let colors = scene
+                .get_particle_types()
+                .particle_types()
+                .iter()
+                .map(|t| t.color)
+                .collect::<Vec<_>>();
+            let mut writer = TrajectoryWriter::create(
+                path,
+                scene.get_settings().screen_size,
+                &colors,
+                options.record_every,
+            )?;
+            writer.record(0, &scene.get_particles())?;
+            Some(writer)
+        }
+        None => None,
+    };
+    let start = Instant::now();
+    let mut step_times = Vec::with_capacity(options.steps.unwrap_or(0));
+    loop {
+        if options.steps.is_some_and(|steps| step_times.len() >= steps) {
+            break;
+        }
+        if options
+            .time_budget
+            .is_some_and(|budget| start.elapsed() >= budget)
+        {
+            break;
+        }
+        let step_start = Instant::now();
+        scene.update().await;
+        step_times.push(step_start.elapsed());
+        if let Some(writer) = &mut trajectory {
+            writer.record(step_times.len() as u64, &scene.get_particles())?;
+        }
+        if options
+            .checkpoint_every
+            .is_some_and(|every| step_times.len().is_multiple_of(every))
+        {
+            scene
+                .export_snapshot()
+                .save(&options.output_dir.join("checkpoint.psnap"))?;
+        }
+    }
+    if let Some(writer) = trajectory {
+        writer.finish()?;
+    }
+    let report = HeadlessReport {
+        step_times,
+        total_time: start.elapsed(),
+    };
+
+    write_particles(
+        &options.output_dir.join("particles.csv"),
+        &scene.get_particles(),
+    )?;
+    write_timings(&options.output_dir.join("timings.csv"), &report.step_times)?;
+    Snapshot::capture(scene).save(&options.output_dir.join("snapshot.psnap"))?;
+    return Ok(report);
+}
+
+pub fn write_particles(path: &Path, particles: &[Particle]) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "index,type_index,pos_x,pos_y,vel_x,vel_y")?;
+    for (i, p) in particles.iter().enumerate() {
+        writeln!(
+            out,
+            "{},{},{},{},{},{}",
+            i, p.type_index, p.pos[0], p.pos[1], p.vel[0], p.vel[1]
+        )?;
+    }
+    return out.flush();
+}
+
+pub fn write_timings(path: &Path, step_times: &[Duration]) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "step,update_ms")?;
+    for (i, time) in step_times.iter().enumerate() {
+        writeln!(out, "{},{}", i, time.as_secs_f64() * 1000.0)?;
+    }
+    return out.flush();
+}
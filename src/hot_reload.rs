@@ -0,0 +1,206 @@
+//! Watches the files a scene was loaded from and reloads them on change.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, Sender},
+};
+
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use particle_simulation::{
+    error::Result, field::FieldSet, obstacle::ObstacleSet, scene_config::SceneConfig,
+    ParticleTypeManager, SceneLike,
+};
+
+use crate::cli::Cli;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileKind {
+    Rules,
+    Obstacles,
+    Fields,
+    Config,
+}
+
+/// The new contents of a changed file, checked but not yet applied.
+pub enum Reloaded {
+    Rules(ParticleTypeManager),
+    Obstacles(ObstacleSet),
+    Fields(FieldSet),
+    Config(SceneConfig),
+}
+
+impl Reloaded {
+    /// Swaps the contents into `scene` without touching the particles.
+    pub fn apply(self, scene: &mut impl SceneLike) {
+        match self {
+            Reloaded::Rules(particle_types) => scene.set_particle_types(particle_types),
+            Reloaded::Obstacles(obstacles) => scene.set_obstacles(obstacles),
+            Reloaded::Fields(fields) => scene.set_fields(fields),
+            Reloaded::Config(config) => scene.configure(&config),
+        }
+    }
+}
+
+pub struct WatchedFile {
+    pub kind: FileKind,
+    pub path: PathBuf,
+    /// What the viewer itself last wrote to the file. Changes that leave
+    /// these contents in place are its own and not reloaded.
+    written: Option<String>,
+}
+
+impl WatchedFile {
+    pub fn load(&self) -> Result<Reloaded> {
+        return Ok(match self.kind {
+            FileKind::Rules => Reloaded::Rules(ParticleTypeManager::load(&self.path)?),
+            FileKind::Obstacles => Reloaded::Obstacles(ObstacleSet::load(&self.path)?),
+            FileKind::Fields => Reloaded::Fields(FieldSet::load(&self.path)?),
+            FileKind::Config => Reloaded::Config(SceneConfig::load(&self.path)?),
+        });
+    }
+}
+
+pub struct HotReload {
+    files: Vec<WatchedFile>,
+    events: Receiver<notify::Result<Event>>,
+    /// Stops watching when dropped.
+    _watcher: Option<RecommendedWatcher>,
+}
+
+impl HotReload {
+    /// Watches the rule, obstacle, field and config files given to `cli`.
+    /// Reloading is off if the platform cannot watch files.
+    pub fn from_cli(cli: &Cli) -> HotReload {
+        let files = [
+            (FileKind::Rules, &cli.rules),
+            (FileKind::Obstacles, &cli.obstacles),
+            (FileKind::Fields, &cli.fields),
+            (FileKind::Config, &cli.config),
+        ]
+        .into_iter()
+        .filter_map(|(kind, path)| {
+            let path = path.as_ref()?;
+            // Events name absolute paths.
+            let path = path.canonicalize().unwrap_or_else(|_| path.clone());
+            return Some(WatchedFile {
+                kind,
+                path,
+                written: None,
+            });
+        })
+        .collect::<Vec<_>>();
+        let (sender, events) = channel();
+        let watcher = match HotReload::watch(&files, sender) {
+            Ok(watcher) => Some(watcher),
+            Err(err) => {
+                eprintln!("Could not watch the scene files, reloading is off: {}", err);
+                None
+            }
+        };
+        return HotReload {
+            files,
+            events,
+            _watcher: watcher,
+        };
+    }
+
+    /// Watches the directories rather than the files, since editors often
+    /// save by replacing the file.
+    fn watch(
+        files: &[WatchedFile],
+        sender: Sender<notify::Result<Event>>,
+    ) -> notify::Result<RecommendedWatcher> {
+        let mut watcher = notify::recommended_watcher(sender)?;
+        for file in files {
+            let directory = file.path.parent().unwrap_or(Path::new("."));
+            watcher.watch(directory, RecursiveMode::NonRecursive)?;
+        }
+        return Ok(watcher);
+    }
+
+    /// Remembers that the viewer wrote `path`, so that the change events of
+    /// that write do not reload it.
+    pub fn saved(&mut self, path: &Path) {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        for file in &mut self.files {
+            if file.path == path {
+                file.written = fs::read_to_string(&path).ok();
+            }
+        }
+    }
+
+    /// Files modified since the last call, each once, except by the viewer's
+    /// own saves.
+    pub fn changed(&mut self) -> Vec<&WatchedFile> {
+        let mut touched = vec![false; self.files.len()];
+        for event in self.events.try_iter() {
+            let Ok(event) = event else {
+                continue;
+            };
+            if event.kind.is_access() {
+                continue;
+            }
+            for (file, touched) in self.files.iter().zip(&mut touched) {
+                *touched |= event.paths.contains(&file.path);
+            }
+        }
+        for (file, touched) in self.files.iter_mut().zip(&mut touched) {
+            if !*touched || file.written.is_none() {
+                continue;
+            }
+            if fs::read_to_string(&file.path).ok() == file.written {
+                *touched = false;
+            } else {
+                file.written = None;
+            }
+        }
+        return self
+            .files
+            .iter()
+            .zip(touched)
+            .filter(|(_, touched)| *touched)
+            .map(|(file, _)| file)
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use notify::{event::ModifyKind, EventKind};
+
+    use super::*;
+
+    #[test]
+    fn own_saves_are_not_reloaded() {
+        let path = std::env::temp_dir().join(format!("hot-reload-{}.toml", std::process::id()));
+        fs::write(&path, "saved").unwrap();
+        let (sender, events) = channel();
+        let mut hot_reload = HotReload {
+            files: vec![WatchedFile {
+                kind: FileKind::Rules,
+                path: path.clone(),
+                written: None,
+            }],
+            events,
+            _watcher: None,
+        };
+        let modified = || {
+            let event = Event::new(EventKind::Modify(ModifyKind::Any)).add_path(path.clone());
+            sender.send(Ok(event)).unwrap();
+        };
+
+        hot_reload.saved(&path);
+        modified();
+        modified();
+        assert!(hot_reload.changed().is_empty());
+        fs::write(&path, "edited").unwrap();
+        modified();
+        assert_eq!(hot_reload.changed().len(), 1);
+        // Edits back to the saved contents are the user's too.
+        fs::write(&path, "saved").unwrap();
+        modified();
+        assert_eq!(hot_reload.changed().len(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,372 @@
+//! Overlay with performance and population statistics for the viewer.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use graphics::{line_from_to, rectangle, text::Text, Context, Transformed};
+use opengl_graphics::{GlGraphics, GlyphCache, TextureSettings};
+use particle_simulation::{Particle, SceneLike};
+
+use crate::viewer::SimulationClock;
+
+const FONT: &[u8] = include_bytes!("../assets/fonts/DejaVuSansMono.ttf");
+pub const FONT_SIZE: u32 = 12;
+const LINE_HEIGHT: f64 = 16.0;
+const PANEL_WIDTH: f64 = 360.0;
+const PLOT_HEIGHT: f64 = 36.0;
+/// Frames averaged for the FPS.
+const FRAME_WINDOW: usize = 60;
+/// Updates the step time average and percentiles are taken over.
+const STEP_WINDOW: usize = 300;
+/// Frames shown in the rolling plots, one pixel each.
+const HISTORY: usize = PANEL_WIDTH as usize - 20;
+
+/// How long notices stay on screen. Errors stay until the next notice.
+const NOTICE_DURATION: Duration = Duration::from_secs(3);
+
+pub const TEXT_COLOR: [f32; 4] = [0.9, 0.9, 0.9, 1.0];
+pub const PANEL_COLOR: [f32; 4] = [0.0, 0.0, 0.0, 0.6];
+const ERROR_COLOR: [f32; 4] = [1.0, 0.4, 0.4, 1.0];
+
+/// The embedded monospace font, for any text the viewer draws.
+pub fn load_font() -> GlyphCache<'static> {
+    return GlyphCache::from_bytes(FONT, (), TextureSettings::new())
+        .expect("Embedded font should load");
+}
+
+/// One point of the rolling plots.
+struct Sample {
+    fps: f64,
+    step_ms: f64,
+    type_counts: Vec<usize>,
+}
+
+pub struct Hud {
+    pub visible: bool,
+    glyphs: GlyphCache<'static>,
+    backend: String,
+    last_frame: Option<Instant>,
+    frame_times: VecDeque<Duration>,
+    step_times: VecDeque<Duration>,
+    history: VecDeque<Sample>,
+    /// Line at the bottom of the window, shown even while the HUD is hidden.
+    notice: Option<Notice>,
+}
+
+struct Notice {
+    text: String,
+    is_error: bool,
+    shown: Instant,
+}
+
+impl Hud {
+    pub fn new(backend: &str) -> Hud {
+        return Hud {
+            visible: true,
+            glyphs: load_font(),
+            backend: backend.to_string(),
+            last_frame: None,
+            frame_times: VecDeque::with_capacity(FRAME_WINDOW),
+            step_times: VecDeque::with_capacity(STEP_WINDOW),
+            history: VecDeque::with_capacity(HISTORY),
+            notice: None,
+        };
+    }
+
+    /// Shows `text` at the bottom of the window, replacing the last notice.
+    pub fn notify(&mut self, text: String, is_error: bool) {
+        self.notice = Some(Notice {
+            text,
+            is_error,
+            shown: Instant::now(),
+        });
+    }
+
+    pub fn record_step(&mut self, duration: Duration) {
+        if self.step_times.len() == STEP_WINDOW {
+            self.step_times.pop_front();
+        }
+        self.step_times.push_back(duration);
+    }
+
+    /// Takes the samples of one rendered frame.
+    pub fn record_frame(&mut self, particles: &[Particle], particle_types_count: usize) {
+        let now = Instant::now();
+        if let Some(last_frame) = self.last_frame.replace(now) {
+            if self.frame_times.len() == FRAME_WINDOW {
+                self.frame_times.pop_front();
+            }
+            self.frame_times.push_back(now - last_frame);
+        }
+        let mut type_counts = vec![0; particle_types_count];
+        for particle in particles {
+            if let Some(count) = type_counts.get_mut(particle.type_index) {
+                *count += 1;
+            }
+        }
+        if self.history.len() == HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(Sample {
+            fps: self.fps(),
+            step_ms: self.step_average().as_secs_f64() * 1000.0,
+            type_counts,
+        });
+    }
+
+    fn fps(&self) -> f64 {
+        let total = self.frame_times.iter().sum::<Duration>().as_secs_f64();
+        if total == 0.0 {
+            return 0.0;
+        }
+        return self.frame_times.len() as f64 / total;
+    }
+
+    fn step_average(&self) -> Duration {
+        if self.step_times.is_empty() {
+            return Duration::ZERO;
+        }
+        return self.step_times.iter().sum::<Duration>() / self.step_times.len() as u32;
+    }
+
+    /// Step time below which `fraction` of the recent updates finished.
+    fn step_percentile(&self, fraction: f64) -> Duration {
+        return percentile(&self.step_times, fraction);
+    }
+
+    pub fn draw(
+        &mut self,
+        scene: &impl SceneLike,
+        clock: &SimulationClock,
+        c: Context,
+        gl: &mut GlGraphics,
+    ) {
+        self.draw_notice(c, gl);
+        if !self.visible {
+            return;
+        }
+        let settings = scene.get_settings();
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let mut lines = vec![
+            (
+                format!("{:.0} FPS, {} backend", self.fps(), self.backend),
+                TEXT_COLOR,
+            ),
+            (
+                format!(
+                    "step {:.2}ms avg, p50 {:.2} p95 {:.2} p99 {:.2}",
+                    ms(self.step_average()),
+                    ms(self.step_percentile(0.5)),
+                    ms(self.step_percentile(0.95)),
+                    ms(self.step_percentile(0.99))
+                ),
+                TEXT_COLOR,
+            ),
+            (
+                format!(
+                    "{} particles, seed {}",
+                    settings.particle_count, settings.seed
+                ),
+                TEXT_COLOR,
+            ),
+            (clock_line(clock), TEXT_COLOR),
+        ];
+        if let Some(latest) = self.history.back() {
+            for (type_index, count) in latest.type_counts.iter().enumerate() {
+                lines.push((
+                    format!("type {type_index}: {count}"),
+                    scene.get_particle_color(type_index),
+                ));
+            }
+        }
+        let plots = 3;
+        let top = 44.0;
+        let height = lines.len() as f64 * LINE_HEIGHT + plots as f64 * (PLOT_HEIGHT + 8.0) + 12.0;
+        rectangle(
+            PANEL_COLOR,
+            [10.0, top, PANEL_WIDTH, height],
+            c.transform,
+            gl,
+        );
+
+        let mut y = top + 6.0;
+        for (line, color) in &lines {
+            y += LINE_HEIGHT;
+            // Rendering only fails for glyphs the font lacks.
+            let _ = Text::new_color(*color, FONT_SIZE).draw(
+                line,
+                &mut self.glyphs,
+                &c.draw_state,
+                c.transform.trans(20.0, y - 4.0),
+                gl,
+            );
+        }
+
+        y += 8.0;
+        let fps = self.history.iter().map(|s| s.fps).collect::<Vec<_>>();
+        self.draw_plot("FPS", &[(&fps, TEXT_COLOR)], [20.0, y], c, gl);
+        y += PLOT_HEIGHT + 8.0;
+        let step_ms = self.history.iter().map(|s| s.step_ms).collect::<Vec<_>>();
+        let step_color = [1.0, 0.8, 0.3, 1.0];
+        self.draw_plot(
+            "average step ms",
+            &[(&step_ms, step_color)],
+            [20.0, y],
+            c,
+            gl,
+        );
+        y += PLOT_HEIGHT + 8.0;
+        let counts = (0..settings.particle_types_count)
+            .map(|type_index| {
+                let counts = self
+                    .history
+                    .iter()
+                    .map(|s| s.type_counts.get(type_index).copied().unwrap_or(0) as f64)
+                    .collect::<Vec<_>>();
+                return (counts, scene.get_particle_color(type_index));
+            })
+            .collect::<Vec<_>>();
+        let series = counts
+            .iter()
+            .map(|(counts, color)| (counts.as_slice(), *color))
+            .collect::<Vec<_>>();
+        self.draw_plot("particles per type", &series, [20.0, y], c, gl);
+    }
+
+    fn draw_notice(&mut self, c: Context, gl: &mut GlGraphics) {
+        let Some(notice) = &self.notice else {
+            return;
+        };
+        if !notice.is_error && notice.shown.elapsed() > NOTICE_DURATION {
+            self.notice = None;
+            return;
+        }
+        // Parse errors span several lines, pointing at the mistake.
+        let lines = notice.text.lines().collect::<Vec<_>>();
+        let longest = lines.iter().map(|line| line.chars().count()).max();
+        let width = longest.unwrap_or(0) as f64 * 0.6 * FONT_SIZE as f64 + 20.0;
+        let height = lines.len() as f64 * LINE_HEIGHT + 8.0;
+        let top = c.get_view_size()[1] - 10.0 - height;
+        rectangle(PANEL_COLOR, [10.0, top, width, height], c.transform, gl);
+        let color = if notice.is_error {
+            ERROR_COLOR
+        } else {
+            TEXT_COLOR
+        };
+        for (i, line) in lines.iter().enumerate() {
+            let y = top + (i + 1) as f64 * LINE_HEIGHT;
+            let _ = Text::new_color(color, FONT_SIZE).draw(
+                line,
+                &mut self.glyphs,
+                &c.draw_state,
+                c.transform.trans(20.0, y),
+                gl,
+            );
+        }
+    }
+
+    /// Line plots sharing one vertical scale, from 0 to the largest value,
+    /// with the newest values on the right.
+    fn draw_plot(
+        &mut self,
+        label: &str,
+        series: &[(&[f64], [f32; 4])],
+        origin: [f64; 2],
+        c: Context,
+        gl: &mut GlGraphics,
+    ) {
+        let width = HISTORY as f64;
+        rectangle(
+            [1.0, 1.0, 1.0, 0.08],
+            [origin[0], origin[1], width, PLOT_HEIGHT],
+            c.transform,
+            gl,
+        );
+        let max = series
+            .iter()
+            .flat_map(|(values, _)| values.iter().copied())
+            .fold(0.0, f64::max);
+        let _ = Text::new_color([0.6, 0.6, 0.6, 1.0], FONT_SIZE - 3).draw(
+            &format!("{label}, max {max:.1}"),
+            &mut self.glyphs,
+            &c.draw_state,
+            c.transform.trans(origin[0] + 2.0, origin[1] + 10.0),
+            gl,
+        );
+        if max <= 0.0 {
+            return;
+        }
+        let bottom = origin[1] + PLOT_HEIGHT;
+        for (values, color) in series {
+            let start = width - values.len() as f64;
+            let point = |i: usize| {
+                [
+                    origin[0] + start + i as f64,
+                    bottom - values[i] / max * PLOT_HEIGHT,
+                ]
+            };
+            for i in 1..values.len() {
+                line_from_to(*color, 0.5, point(i - 1), point(i), c.transform, gl);
+            }
+        }
+    }
+}
+
+/// Step count, and whether the simulation runs and how fast.
+fn clock_line(clock: &SimulationClock) -> String {
+    let rate = if clock.paused {
+        "paused".to_string()
+    } else if clock.speed >= 1.0 {
+        format!("{} steps per frame", clock.speed)
+    } else {
+        format!("1 step every {} frames", 1.0 / clock.speed)
+    };
+    return format!("step {}, speed x{}, {}", clock.steps, clock.speed, rate);
+}
+
+/// The entry of `durations` below which `fraction` of them lie, or zero if
+/// there are none.
+fn percentile(durations: &VecDeque<Duration>, fraction: f64) -> Duration {
+    let mut sorted = durations.iter().copied().collect::<Vec<_>>();
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    sorted.sort();
+    let index = ((sorted.len() - 1) as f64 * fraction).round() as usize;
+    return sorted[index];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_pick_from_the_sorted_durations() {
+        let durations = [5, 1, 4, 2, 3]
+            .map(Duration::from_millis)
+            .into_iter()
+            .collect::<VecDeque<_>>();
+        assert_eq!(percentile(&durations, 0.0), Duration::from_millis(1));
+        assert_eq!(percentile(&durations, 0.5), Duration::from_millis(3));
+        assert_eq!(percentile(&durations, 0.95), Duration::from_millis(5));
+        assert_eq!(percentile(&VecDeque::new(), 0.5), Duration::ZERO);
+    }
+
+    #[test]
+    fn clock_line_shows_pause_and_rate() {
+        let mut clock = SimulationClock::new();
+        clock.faster();
+        assert_eq!(clock_line(&clock), "step 0, speed x2, 2 steps per frame");
+        clock.slower();
+        clock.slower();
+        clock.slower();
+        assert_eq!(
+            clock_line(&clock),
+            "step 0, speed x0.25, 1 step every 4 frames"
+        );
+        clock.paused = true;
+        assert_eq!(clock_line(&clock), "step 0, speed x0.25, paused");
+    }
+}
@@ -0,0 +1,155 @@
+//! Time integration shared by the CPU scenes and the conformance reference.
+//!
+//! Drag is a per-time-unit damping: a particle keeps `drag` of its velocity
+//! after one time unit, so `drag.powf(dt)` after a step of length `dt`.
+
+use clap::ValueEnum;
+use graphics::math::Vec2d;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    boundary::Boundary,
+    vector::{add, mul_scalar, sub, wrap},
+    SceneSettings,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Integrator {
+    /// Velocity first, then position with the new velocity. One force
+    /// evaluation per step.
+    #[default]
+    SemiImplicitEuler,
+    /// Kick, drift, kick. Two force evaluations per step.
+    VelocityVerlet,
+    /// Classic fourth order Runge-Kutta. Four force evaluations per step.
+    Rk4,
+}
+
+impl Integrator {
+    /// Number of force evaluations per step.
+    pub fn stages(&self) -> usize {
+        return match self {
+            Integrator::SemiImplicitEuler => 1,
+            Integrator::VelocityVerlet => 2,
+            Integrator::Rk4 => 4,
+        };
+    }
+}
+
+/// Advances every particle by one step of [`SceneSettings::dt`] with
+/// [`SceneSettings::integrator`], confining it with
+/// [`SceneSettings::boundary`]. Absorbed particles are left outside the world
+/// for the caller to remove.
+///
+/// `drag` holds the drag of each particle's type. `accelerations` returns the
+/// acceleration of every particle for the given positions and is called
+/// [`Integrator::stages`] times.
+pub fn integrate(
+    settings: &SceneSettings,
+    positions: &mut [Vec2d],
+    velocities: &mut [Vec2d],
+    drag: &[f64],
+    mut accelerations: impl FnMut(&[Vec2d]) -> Vec<Vec2d>,
+) {
+    let dt = settings.dt;
+    let screen_size = &settings.screen_size.map(|v| v as f64);
+    let boundary = settings.boundary;
+    let restitution = settings.restitution;
+    match settings.integrator {
+        Integrator::SemiImplicitEuler => {
+            let acc = accelerations(positions);
+            for i in 0..positions.len() {
+                let mut dv = acc[i];
+                add(&mut velocities[i], mul_scalar(&mut dv, dt));
+                mul_scalar(&mut velocities[i], drag[i].powf(dt));
+                let mut dx = velocities[i];
+                add(&mut positions[i], mul_scalar(&mut dx, dt));
+                boundary.confine(
+                    &mut positions[i],
+                    &mut velocities[i],
+                    screen_size,
+                    restitution,
+                );
+            }
+        }
+        Integrator::VelocityVerlet => {
+            let acc = accelerations(positions);
+            for i in 0..positions.len() {
+                let mut dv = acc[i];
+                add(&mut velocities[i], mul_scalar(&mut dv, 0.5 * dt));
+                let mut dx = velocities[i];
+                add(&mut positions[i], mul_scalar(&mut dx, dt));
+                boundary.confine(
+                    &mut positions[i],
+                    &mut velocities[i],
+                    screen_size,
+                    restitution,
+                );
+            }
+            let acc = accelerations(positions);
+            for i in 0..positions.len() {
+                let mut dv = acc[i];
+                add(&mut velocities[i], mul_scalar(&mut dv, 0.5 * dt));
+                mul_scalar(&mut velocities[i], drag[i].powf(dt));
+            }
+        }
+        Integrator::Rk4 => {
+            // Drag as the continuous damping `dv/dt = -gamma * v`.
+            let gamma = drag.iter().map(|d| -d.ln()).collect::<Vec<f64>>();
+            let base_positions = positions.to_vec();
+            let base_velocities = velocities.to_vec();
+            let mut sum_positions = vec![[0.0, 0.0]; positions.len()];
+            let mut sum_velocities = vec![[0.0, 0.0]; positions.len()];
+            let mut stage_positions = base_positions.clone();
+            let mut stage_velocities = base_velocities.clone();
+            for (stage, (weight, step)) in [(1.0, 0.5), (2.0, 0.5), (2.0, 1.0), (1.0, 0.0)]
+                .into_iter()
+                .enumerate()
+            {
+                let acc = accelerations(&stage_positions);
+                for i in 0..positions.len() {
+                    let k_position = stage_velocities[i];
+                    let mut damping = stage_velocities[i];
+                    mul_scalar(&mut damping, gamma[i]);
+                    let mut k_velocity = acc[i];
+                    sub(&mut k_velocity, &damping);
+
+                    let mut weighted = k_position;
+                    add(&mut sum_positions[i], mul_scalar(&mut weighted, weight));
+                    let mut weighted = k_velocity;
+                    add(&mut sum_velocities[i], mul_scalar(&mut weighted, weight));
+
+                    if stage < 3 {
+                        let mut dx = k_position;
+                        stage_positions[i] = base_positions[i];
+                        add(&mut stage_positions[i], mul_scalar(&mut dx, step * dt));
+                        // Only the final state meets the walls.
+                        if boundary == Boundary::Periodic {
+                            wrap(&mut stage_positions[i], screen_size);
+                        }
+                        let mut dv = k_velocity;
+                        stage_velocities[i] = base_velocities[i];
+                        add(&mut stage_velocities[i], mul_scalar(&mut dv, step * dt));
+                    }
+                }
+            }
+            for i in 0..positions.len() {
+                add(
+                    &mut positions[i],
+                    mul_scalar(&mut sum_positions[i], dt / 6.0),
+                );
+                add(
+                    &mut velocities[i],
+                    mul_scalar(&mut sum_velocities[i], dt / 6.0),
+                );
+                boundary.confine(
+                    &mut positions[i],
+                    &mut velocities[i],
+                    screen_size,
+                    restitution,
+                );
+            }
+        }
+    }
+}
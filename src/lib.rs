@@ -0,0 +1,95 @@
+//! Particle life simulation with interchangeable CPU and GPU backends.
+//!
+//! Every backend implements [`SceneLike`], so tools can drive any of them
+//! through the same `new` / `init` / `update` / `get_particles` cycle.
+
+#![allow(clippy::needless_return)]
+
+pub mod boundary;
+pub mod conformance;
+pub mod constants;
+pub mod error;
+pub mod field;
+pub mod force_law;
+pub mod headless;
+pub mod integrator;
+pub mod multithreaded_scene;
+pub mod multithreaded_scene_v2;
+pub mod neighbor_grid;
+pub mod obstacle;
+pub mod particle_renderer;
+pub mod particle_type;
+mod receive_into_slice;
+pub mod rule_set;
+pub mod scene_config;
+pub mod scene_like;
+pub mod snapshot;
+pub mod trajectory;
+pub mod vector;
+pub mod wgpu_scene;
+
+use boundary::Boundary;
+use force_law::ForceLawKind;
+use graphics::math::Vec2d;
+use integrator::Integrator;
+use neighbor_grid::NeighborSearch;
+use serde::{Deserialize, Serialize};
+
+pub use crate::{
+    multithreaded_scene::MultithreadedScene, multithreaded_scene_v2::MultithreadedSceneV2,
+    particle_type::ParticleTypeManager, scene_like::SceneLike, wgpu_scene::WgpuScene,
+};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Particle {
+    pub pos: Vec2d,
+    pub vel: Vec2d,
+    pub type_index: usize,
+}
+
+impl Particle {
+    pub fn new() -> Particle {
+        return Particle::default();
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SceneSettings {
+    pub screen_size: [u32; 2],
+    /// Current number of particles, which changes as particles are spawned,
+    /// removed or absorbed.
+    pub particle_count: usize,
+    pub particle_types_count: usize,
+    /// Seed used to generate the [`ParticleTypeManager`] rules.
+    pub seed: u64,
+    #[serde(default)]
+    pub force_law: ForceLawKind,
+    #[serde(default)]
+    pub neighbor_search: NeighborSearch,
+    /// Simulation steps per [`SceneLike::update`].
+    #[serde(default = "default_substeps")]
+    pub substeps: usize,
+    /// Length of one simulation step.
+    #[serde(default = "default_dt")]
+    pub dt: f64,
+    #[serde(default)]
+    pub integrator: Integrator,
+    #[serde(default)]
+    pub boundary: Boundary,
+    /// Fraction of the normal velocity kept when bouncing off a
+    /// [`Boundary::Reflective`] wall.
+    #[serde(default = "default_restitution")]
+    pub restitution: f64,
+}
+
+fn default_substeps() -> usize {
+    return constants::DEFAULT_SUBSTEPS;
+}
+
+fn default_dt() -> f64 {
+    return constants::DEFAULT_DT;
+}
+
+fn default_restitution() -> f64 {
+    return constants::DEFAULT_RESTITUTION;
+}
@@ -1,114 +1,279 @@
-extern crate glutin_window;
-extern crate graphics;
-extern crate opengl_graphics;
-extern crate piston;
-extern crate rand;
+#![allow(clippy::needless_return)]
 
-mod constants;
-mod multithreaded_scene;
-mod multithreaded_scene_v2;
-mod particle_type;
-mod receive_into_slice;
-mod scene_like;
-mod vector;
-mod wgpu_scene;
+#[cfg(feature = "viewer")]
+mod camera;
+mod cli;
+#[cfg(feature = "viewer")]
+mod direct_viewer;
+#[cfg(feature = "viewer")]
+mod hot_reload;
+#[cfg(feature = "viewer")]
+mod hud;
+#[cfg(feature = "viewer")]
+mod mouse_tools;
+#[cfg(feature = "viewer")]
+mod rule_panel;
+#[cfg(feature = "viewer")]
+mod viewer;
 
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[allow(unused_imports)]
-use crate::{
-    constants::BENCHMARK_RUNS, multithreaded_scene::MultithreadedScene,
-    multithreaded_scene_v2::MultithreadedSceneV2, scene_like::SceneLike, wgpu_scene::WgpuScene,
+use clap::Parser;
+use particle_simulation::{
+    conformance::{run_conformance, Tolerances},
+    field::FieldSet,
+    headless::{run_headless, HeadlessOptions},
+    obstacle::ObstacleSet,
+    scene_config::SceneConfig,
+    snapshot::Snapshot,
+    MultithreadedScene, MultithreadedSceneV2, ParticleTypeManager, SceneLike, WgpuScene,
 };
-use glutin_window::GlutinWindow as Window;
-use graphics::math::Vec2d;
-use opengl_graphics::GlGraphics;
-use piston::{EventSettings, Events, PressEvent, RenderEvent, WindowSettings};
 
-#[derive(Debug, Clone, Copy)]
-struct Particle {
-    pos: Vec2d,
-    vel: Vec2d,
-    type_index: usize,
-}
+use crate::cli::{Backend, Cli, Mode};
 
-impl Particle {
-    fn new() -> Particle {
-        return Particle {
-            pos: Vec2d::default(),
-            vel: Vec2d::default(),
-            type_index: 0,
+fn main() {
+    let cli = Cli::parse();
+    if let Some(Mode::Playback { file }) = &cli.mode {
+        return playback(file);
+    }
+    if let Some(Mode::Conformance {
+        steps,
+        position_tolerance,
+        velocity_tolerance,
+    }) = &cli.mode
+    {
+        let tolerances = Tolerances {
+            position: *position_tolerance,
+            velocity: *velocity_tolerance,
         };
+        return pollster::block_on(conformance(&cli, *steps, tolerances));
+    }
+    if let Some(Mode::Window { direct: true, .. }) = &cli.mode {
+        return pollster::block_on(direct_window(&cli));
+    }
+    match cli.backend {
+        Backend::Multithreaded => pollster::block_on(run::<MultithreadedScene>(&cli)),
+        Backend::MultithreadedV2 => pollster::block_on(run::<MultithreadedSceneV2>(&cli)),
+        Backend::Wgpu => pollster::block_on(run::<WgpuScene>(&cli)),
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-struct SceneSettings {
-    screen_size: [u32; 2],
-    particle_count: usize,
-    particle_types_count: usize,
+async fn create_scene<S: SceneLike>(cli: &Cli) -> S {
+    let mut scene = match &cli.resume {
+        Some(path) => {
+            let resumed = match Snapshot::load(path) {
+                Ok(snapshot) => snapshot.restore::<S>().await,
+                Err(err) => Err(err),
+            };
+            resumed.unwrap_or_else(|err| {
+                eprintln!("Could not resume from {}: {}", path.display(), err);
+                std::process::exit(1);
+            })
+        }
+        None => load_scene::<S>(cli).await,
+    };
+    if let Some(path) = &cli.config {
+        match SceneConfig::load(path) {
+            Ok(config) => scene.configure(&config),
+            Err(err) => {
+                eprintln!("Could not load config from {}: {}", path.display(), err);
+                std::process::exit(1);
+            }
+        }
+    }
+    return scene;
 }
 
-const SCREEN_SIZE: [u32; 2] = [2320, 1280];
-
-fn main() {
-    let mut scene = pollster::block_on(WgpuScene::new(SceneSettings {
-        screen_size: SCREEN_SIZE,
-        particle_count: 5_000,
-        particle_types_count: 5,
-    }));
+/// A new scene with the rules, obstacles and fields given to `cli`.
+async fn load_scene<S: SceneLike>(cli: &Cli) -> S {
+    let mut scene = S::new(cli.scene_settings()).await;
     scene.init();
-    // let start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-    // for _ in 0..BENCHMARK_RUNS {
-    //     pollster::block_on(scene.update());
-    // }
-    // let end = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-    // let diff = end - start;
-    // println!("[Bench] ({}x) {}ms", BENCHMARK_RUNS, diff.as_millis());
-    // println!(
-    //     "[Bench] Average update time {}ms",
-    //     diff.as_secs_f32() * 1000.0 / BENCHMARK_RUNS as f32
-    // );
-    pollster::block_on(display(&mut scene));
+    if let Some(path) = &cli.rules {
+        match ParticleTypeManager::load(path) {
+            Ok(particle_types) => scene.set_particle_types(particle_types),
+            Err(err) => {
+                eprintln!("Could not load rules from {}: {}", path.display(), err);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(path) = &cli.obstacles {
+        match ObstacleSet::load(path) {
+            Ok(obstacles) => scene.set_obstacles(obstacles),
+            Err(err) => {
+                eprintln!("Could not load obstacles from {}: {}", path.display(), err);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(path) = &cli.fields {
+        match FieldSet::load(path) {
+            Ok(fields) => scene.set_fields(fields),
+            Err(err) => {
+                eprintln!("Could not load fields from {}: {}", path.display(), err);
+                std::process::exit(1);
+            }
+        }
+    }
+    return scene;
 }
 
-#[allow(dead_code)]
-async fn display(scene: &mut impl SceneLike) {
-    let mut window: Window = WindowSettings::new("Simulation window", SCREEN_SIZE)
-        .exit_on_esc(true)
-        .build()
-        .unwrap();
-    use graphics::*;
-    let mut gl = GlGraphics::new(glutin_window::OpenGL::V3_2);
-    let mut events = Events::new(EventSettings::new());
-    let mut i = 0;
-    let mut diff_sum = Duration::new(0, 0);
-    while let Some(e) = events.next(&mut window) {
-        if let Some(_) = e.press_args() {
-            println!("New world!");
-            scene.new_world();
+async fn run<S: SceneLike>(cli: &Cli) {
+    let mut scene = create_scene::<S>(cli).await;
+    let default_mode = Mode::Window {
+        direct: false,
+        window_size: None,
+    };
+    match cli.mode.as_ref().unwrap_or(&default_mode) {
+        #[cfg(feature = "viewer")]
+        Mode::Window { window_size, .. } => {
+            let window_size = window_size.unwrap_or(scene.get_settings().screen_size);
+            let mut hot_reload = hot_reload::HotReload::from_cli(cli);
+            viewer::display(
+                &mut scene,
+                window_size,
+                cli.backend.name(),
+                &mut hot_reload,
+                cli.rules.as_deref(),
+            )
+            .await
+        }
+        #[cfg(not(feature = "viewer"))]
+        Mode::Window { .. } => {
+            eprintln!("Built without the `viewer` feature, use the `headless` mode instead");
+            std::process::exit(2);
+        }
+        Mode::Headless {
+            steps,
+            time_budget,
+            output,
+            checkpoint_every,
+            record,
+            record_every,
+        } => {
+            let options = HeadlessOptions {
+                steps: *steps,
+                time_budget: time_budget.map(Duration::from_secs_f64),
+                output_dir: output.clone(),
+                checkpoint_every: *checkpoint_every,
+                trajectory: record.clone(),
+                record_every: *record_every,
+            };
+            let report = match run_headless(&mut scene, &options).await {
+                Ok(report) => report,
+                Err(err) => {
+                    eprintln!("Headless run failed: {}", err);
+                    std::process::exit(1);
+                }
+            };
+            println!(
+                "[Headless] Simulated {} steps in {}ms, average update time {}ms",
+                report.steps(),
+                report.total_time.as_millis(),
+                report.average_step_time().as_secs_f32() * 1000.0
+            );
+            println!("[Headless] Results written to {}", output.display());
         }
-        if let Some(args) = e.render_args() {
-            i += 1;
+        Mode::ExportRules { output } => {
+            if let Err(err) = scene.get_particle_types().save(output) {
+                eprintln!("Could not write rules to {}: {}", output.display(), err);
+                std::process::exit(1);
+            }
+            println!("Rules written to {}", output.display());
+        }
+        Mode::Playback { .. } | Mode::Conformance { .. } => {
+            unreachable!("handled before a scene is created")
+        }
+        Mode::Benchmark { runs } => {
             let start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-            scene.update().await;
+            for _ in 0..*runs {
+                scene.update().await;
+            }
             let end = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-            if i == 100 {
-                println!("Average update time {}ms", diff_sum.as_secs_f32() * 10.0);
+            let diff = end - start;
+            println!("[Bench] ({}x) {}ms", runs, diff.as_millis());
+            println!(
+                "[Bench] Average update time {}ms",
+                diff.as_secs_f32() * 1000.0 / *runs as f32
+            );
+        }
+    }
+}
+
+#[cfg(feature = "viewer")]
+async fn direct_window(cli: &Cli) {
+    if !matches!(cli.backend, Backend::Wgpu) {
+        eprintln!("`window --direct` needs the wgpu backend");
+        std::process::exit(2);
+    }
+    let mut scene = create_scene::<WgpuScene>(cli).await;
+    let window_size = match &cli.mode {
+        Some(Mode::Window {
+            window_size: Some(window_size),
+            ..
+        }) => *window_size,
+        _ => scene.get_settings().screen_size,
+    };
+    direct_viewer::display(&mut scene, window_size, cli.rules.as_deref());
+}
+
+#[cfg(not(feature = "viewer"))]
+async fn direct_window(_cli: &Cli) {
+    eprintln!("Built without the `viewer` feature, use the `headless` mode instead");
+    std::process::exit(2);
+}
+
+#[cfg(feature = "viewer")]
+fn playback(file: &std::path::Path) {
+    match particle_simulation::trajectory::Trajectory::load(file) {
+        Ok(trajectory) => viewer::playback(&trajectory),
+        Err(err) => {
+            eprintln!("Could not load trajectory {}: {}", file.display(), err);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(feature = "viewer"))]
+fn playback(_file: &std::path::Path) {
+    eprintln!("Built without the `viewer` feature, playback is not available");
+    std::process::exit(2);
+}
+
+async fn conformance(cli: &Cli, steps: usize, tolerances: Tolerances) {
+    let initial = create_scene::<MultithreadedScene>(cli)
+        .await
+        .export_snapshot();
+    let reports = run_conformance(&initial, steps, tolerances).await;
+    let mut passed = true;
+    for report in &reports {
+        passed &= report.passed();
+        println!(
+            "[Conformance] {:<16} {} - max position divergence {:e}, max velocity divergence {:e}",
+            report.backend,
+            if report.passed() { "PASS" } else { "FAIL" },
+            report.max_position(),
+            report.max_velocity()
+        );
+        if let Some((expected, actual)) = report.count_mismatch {
+            println!("    expected {} particles, got {}", expected, actual);
+            continue;
+        }
+        if !report.passed() {
+            println!(
+                "    {} of {} particles out of tolerance, worst:",
+                report.failing_particles().count(),
+                report.particles.len()
+            );
+            for p in report.worst_particles(5) {
+                println!(
+                    "    #{:<8} position {:e}, velocity {:e}",
+                    p.index, p.position, p.velocity
+                );
             }
-            gl.draw(args.viewport(), |c, gl| {
-                clear([0.0, 0.0, 0.0, 1.0], gl);
-                diff_sum += end - start;
-                for particle in &*scene.get_particles() {
-                    let color = &scene.get_particle_color(particle.type_index);
-                    ellipse(
-                        *color,
-                        rectangle::centered_square(particle.pos[0], particle.pos[1], 3.0),
-                        c.transform,
-                        gl,
-                    );
-                }
-            });
         }
     }
+    if !passed {
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,244 @@
+//! Mouse tools of the viewer. They only go through [`SceneLike`], so they
+//! work on every backend.
+
+use std::fmt;
+
+use graphics::math::Vec2d;
+use particle_simulation::{
+    field::{Field, FieldKind, FieldSet},
+    vector::{len, sub},
+    Particle, SceneLike,
+};
+use rand::{rng, Rng};
+
+/// Particles added per frame while painting.
+const SPAWN_PER_FRAME: usize = 4;
+/// Strength of the attractor the push and attract tools act as, see
+/// [`FieldKind::Attractor`].
+const PULL_STRENGTH: f64 = 600.0;
+const MIN_BRUSH_RADIUS: f64 = 5.0;
+const MAX_BRUSH_RADIUS: f64 = 400.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tool {
+    /// Paints particles of the selected type.
+    Spawn,
+    /// Removes the particles under the brush.
+    Erase,
+    /// Pushes nearby particles away from the cursor.
+    Push,
+    /// Pulls nearby particles towards the cursor.
+    Attract,
+    /// Picks up the particles under the brush and carries them along.
+    Grab,
+}
+
+impl Tool {
+    pub const ALL: [Tool; 5] = [
+        Tool::Spawn,
+        Tool::Erase,
+        Tool::Push,
+        Tool::Attract,
+        Tool::Grab,
+    ];
+}
+
+impl fmt::Display for Tool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Tool::Spawn => "spawn",
+            Tool::Erase => "erase",
+            Tool::Push => "push",
+            Tool::Attract => "attract",
+            Tool::Grab => "grab",
+        };
+        return write!(f, "{name}");
+    }
+}
+
+pub struct MouseTools {
+    pub tool: Tool,
+    pub spawn_type: usize,
+    pub brush_radius: f64,
+    cursor: Vec2d,
+    /// Cursor position when the last frame was applied, for throwing grabbed
+    /// particles.
+    last_cursor: Vec2d,
+    pressed: bool,
+    /// Grabbed particle indexes and their offsets from the cursor.
+    grabbed: Vec<(usize, Vec2d)>,
+}
+
+impl MouseTools {
+    pub fn new() -> MouseTools {
+        return MouseTools {
+            tool: Tool::Spawn,
+            spawn_type: 0,
+            brush_radius: 30.0,
+            cursor: [0.0, 0.0],
+            last_cursor: [0.0, 0.0],
+            pressed: false,
+            grabbed: vec![],
+        };
+    }
+
+    pub fn cursor(&self) -> Vec2d {
+        return self.cursor;
+    }
+
+    pub fn select(&mut self, tool: Tool) {
+        self.release();
+        self.tool = tool;
+    }
+
+    pub fn next_spawn_type(&mut self, particle_types_count: usize) {
+        self.spawn_type = (self.spawn_type + 1) % particle_types_count;
+    }
+
+    /// Grows the brush for positive `steps` and shrinks it for negative ones.
+    pub fn resize_brush(&mut self, steps: f64) {
+        self.brush_radius =
+            (self.brush_radius * 1.2_f64.powf(steps)).clamp(MIN_BRUSH_RADIUS, MAX_BRUSH_RADIUS);
+    }
+
+    /// Moves the brush to `cursor`, in world coordinates.
+    pub fn move_to(&mut self, cursor: Vec2d) {
+        self.cursor = cursor;
+    }
+
+    pub fn press(&mut self, scene: &impl SceneLike) {
+        self.pressed = true;
+        self.last_cursor = self.cursor;
+        if self.tool == Tool::Grab {
+            self.grabbed = scene
+                .get_particles()
+                .iter()
+                .enumerate()
+                .filter_map(|(i, particle)| {
+                    let mut offset = particle.pos;
+                    sub(&mut offset, &self.cursor);
+                    return (len(&offset) < self.brush_radius).then_some((i, offset));
+                })
+                .collect();
+        }
+    }
+
+    pub fn release(&mut self) {
+        self.pressed = false;
+        self.grabbed.clear();
+    }
+
+    /// Drops the grabbed particles, whose indexes no longer hold once
+    /// particles were removed. The button stays held.
+    pub fn let_go(&mut self) {
+        self.grabbed.clear();
+    }
+
+    /// Applies the held tool for one frame. Called before every update.
+    pub fn apply(&mut self, scene: &mut impl SceneLike) {
+        if !self.pressed {
+            return;
+        }
+        match self.tool {
+            Tool::Spawn => {
+                let random_source = &mut rng();
+                let spawn_type = self.spawn_type % scene.get_settings().particle_types_count;
+                let particles = (0..SPAWN_PER_FRAME)
+                    .map(|_| {
+                        // Uniform over the brush disc.
+                        let distance = self.brush_radius * random_source.random::<f64>().sqrt();
+                        let angle = random_source.random_range(0.0..std::f64::consts::TAU);
+                        let mut particle = Particle::new();
+                        particle.pos = inside_world(
+                            scene,
+                            [
+                                self.cursor[0] + distance * angle.cos(),
+                                self.cursor[1] + distance * angle.sin(),
+                            ],
+                        );
+                        particle.type_index = spawn_type;
+                        return particle;
+                    })
+                    .collect::<Vec<_>>();
+                scene.spawn_particles(&particles);
+            }
+            Tool::Erase => {
+                scene.erase_particles(self.cursor, self.brush_radius);
+            }
+            Tool::Grab => {
+                let mut velocity = self.cursor;
+                sub(&mut velocity, &self.last_cursor);
+                let velocity = velocity.map(|v| v / frame_time(scene));
+                let moves = self
+                    .grabbed
+                    .iter()
+                    .map(|(i, offset)| {
+                        let pos = [self.cursor[0] + offset[0], self.cursor[1] + offset[1]];
+                        return (*i, inside_world(scene, pos), velocity);
+                    })
+                    .collect::<Vec<_>>();
+                scene.move_particles(&moves);
+            }
+            Tool::Push | Tool::Attract => self.pull(scene),
+        }
+        self.last_cursor = self.cursor;
+    }
+
+    /// Speeds up the particles under the brush by one frame of an attractor
+    /// at the cursor. The attractor is never added to the scene's fields, so
+    /// it does not end up in snapshots or saved files.
+    fn pull(&self, scene: &mut impl SceneLike) {
+        let strength = if self.tool == Tool::Push {
+            -PULL_STRENGTH
+        } else {
+            PULL_STRENGTH
+        };
+        let attractor = FieldSet {
+            fields: vec![Field {
+                kind: FieldKind::Attractor {
+                    center: self.cursor,
+                    strength,
+                    radius: Some(self.brush_radius),
+                    softening: 0.5 * self.brush_radius,
+                },
+                response: vec![],
+            }],
+        };
+        let settings = scene.get_settings();
+        let frame_time = frame_time(scene);
+        let moves = scene
+            .get_particles()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, particle)| {
+                let acceleration =
+                    attractor.acceleration(particle.type_index, &particle.pos, &settings);
+                if acceleration == [0.0, 0.0] {
+                    return None;
+                }
+                let vel = [0, 1].map(|axis| particle.vel[axis] + acceleration[axis] * frame_time);
+                return Some((i, particle.pos, vel));
+            })
+            .collect::<Vec<_>>();
+        scene.move_particles(&moves);
+    }
+}
+
+/// Moves a tool target into the world, so painting or dragging beside it
+/// does not leave particles outside, where the boundary would drop them.
+fn inside_world(scene: &impl SceneLike, mut pos: Vec2d) -> Vec2d {
+    let settings = scene.get_settings();
+    let screen_size = settings.screen_size.map(|size| size as f64);
+    settings
+        .boundary
+        .confine(&mut pos, &mut [0.0, 0.0], &screen_size, settings.restitution);
+    // The largest position below the edge in the `f32` the GPU works in.
+    let last = settings.screen_size.map(|size| (size as f32).next_down() as f64);
+    return [pos[0].clamp(0.0, last[0]), pos[1].clamp(0.0, last[1])];
+}
+
+/// Simulated time per frame.
+fn frame_time(scene: &impl SceneLike) -> f64 {
+    let settings = scene.get_settings();
+    return settings.substeps as f64 * settings.dt;
+}
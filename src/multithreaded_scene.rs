@@ -1,10 +1,16 @@
 use std::sync::{Arc, Mutex};
 
-use crate::constants::{K, THREAD_COUNT};
+use crate::constants::THREAD_COUNT;
 use crate::{
+    field::FieldSet,
+    force_law::{pair_force, ForceLaw},
+    integrator::integrate,
+    neighbor_grid::NeighborGrid,
+    obstacle::ObstacleSet,
     particle_type::ParticleTypeManager,
-    scene_like::SceneLike,
-    vector::{add, div_scalar, len, mul_scalar, normalize, remap, sub},
+    scene_config::SceneConfig,
+    scene_like::{removal_mask, retain_unflagged, SceneLike},
+    vector::{add, div_scalar, sub},
     Particle, SceneSettings,
 };
 use graphics::math::Vec2d;
@@ -15,16 +21,143 @@ pub struct MultithreadedScene {
     particles: Arc<Vec<Particle>>,
     settings: Arc<SceneSettings>,
     pub particle_types: Arc<ParticleTypeManager>,
+    force_law: Arc<dyn ForceLaw>,
+    obstacles: ObstacleSet,
+    fields: Arc<FieldSet>,
     pool: ThreadPool,
 }
 
+impl MultithreadedScene {
+    /// Replaces the law picked by [`SceneSettings::force_law`], e.g. with a
+    /// custom [`ForceLaw`] implementation.
+    pub fn set_force_law(&mut self, force_law: Arc<dyn ForceLaw>) {
+        self.force_law = force_law;
+    }
+
+    /// One simulation step of length [`SceneSettings::dt`].
+    fn step(&mut self) {
+        let mut positions = self.particles.iter().map(|p| p.pos).collect::<Vec<_>>();
+        let mut velocities = self.particles.iter().map(|p| p.vel).collect::<Vec<_>>();
+        let drag = self
+            .particles
+            .iter()
+            .map(|p| self.particle_types.get_particle_drag(p.type_index))
+            .collect::<Vec<_>>();
+        integrate(
+            &self.settings,
+            &mut positions,
+            &mut velocities,
+            &drag,
+            |positions| self.accelerations(positions),
+        );
+        for (i, particle) in self.particles.iter().enumerate() {
+            self.obstacles.collide(
+                particle.type_index,
+                &particle.pos,
+                &mut positions[i],
+                &mut velocities[i],
+                &self.settings,
+            );
+        }
+        let screen_size = self.settings.screen_size.map(|v| v as f64);
+        let new_particles = self
+            .particles
+            .iter()
+            .zip(positions.into_iter().zip(velocities))
+            .map(|(particle, (pos, vel))| Particle {
+                pos,
+                vel,
+                ..*particle
+            })
+            .filter(|particle| !self.settings.boundary.absorbs(&particle.pos, &screen_size))
+            .collect::<Vec<_>>();
+        if new_particles.len() != self.particles.len() {
+            let mut settings = *self.settings;
+            settings.particle_count = new_particles.len();
+            self.settings = Arc::new(settings);
+        }
+        self.particles = Arc::new(new_particles);
+    }
+
+    /// Acceleration of every particle if the particles were at `positions`.
+    fn accelerations(&self, positions: &[Vec2d]) -> Vec<Vec2d> {
+        let positions = Arc::new(positions.to_vec());
+        let particle_count = positions.len();
+        let particles_per_job = particle_count.div_ceil(THREAD_COUNT);
+        let grid = Arc::new(NeighborGrid::build(
+            positions.iter().copied(),
+            self.settings.screen_size.map(|v| v as f64),
+            self.settings.neighbor_search.cutoff(&self.particle_types),
+        ));
+        let accelerations_mutexes = (0..THREAD_COUNT)
+            .map(|job_index| {
+                let particles = Arc::clone(&self.particles);
+                let positions = Arc::clone(&positions);
+                let particle_types = Arc::clone(&self.particle_types);
+                let settings = Arc::clone(&self.settings);
+                let screen_size = settings.screen_size.map(|v| v as f64);
+                let force_law = Arc::clone(&self.force_law);
+                let grid = Arc::clone(&grid);
+                let fields = Arc::clone(&self.fields);
+                let accelerations =
+                    Arc::new(Mutex::new(Vec::<Vec2d>::with_capacity(particles_per_job)));
+                let accelerations_in_thread = Arc::clone(&accelerations);
+                self.pool.execute(move || {
+                    let start_i = (job_index * particles_per_job).min(particle_count);
+                    let end_i = (start_i + particles_per_job).min(particle_count);
+                    for i in start_i..end_i {
+                        let particle = particles[i];
+                        let mut total_force: Vec2d = [0.0, 0.0];
+                        for &j in grid.neighbors(&positions[i]) {
+                            if i != j {
+                                let mut direction: Vec2d = positions[j];
+                                sub(&mut direction, &positions[i]);
+                                settings
+                                    .boundary
+                                    .minimum_image(&mut direction, &screen_size);
+                                let force = pair_force(
+                                    &*force_law,
+                                    &particle_types,
+                                    particle.type_index,
+                                    particles[j].type_index,
+                                    direction,
+                                );
+                                add(&mut total_force, &force);
+                            }
+                        }
+                        let mass = particle_types.get_particle_mass(particle.type_index);
+                        div_scalar(&mut total_force, mass);
+                        add(
+                            &mut total_force,
+                            &fields.acceleration(particle.type_index, &positions[i], &settings),
+                        );
+                        accelerations_in_thread.lock().unwrap().push(total_force);
+                    }
+                });
+                return accelerations;
+            })
+            .collect::<Vec<_>>();
+        self.pool.join();
+        return accelerations_mutexes
+            .iter()
+            .flat_map(|mutex| mutex.lock().unwrap().clone())
+            .collect();
+    }
+}
+
 impl SceneLike for MultithreadedScene {
     async fn new(settings: SceneSettings) -> Self {
         return MultithreadedScene {
             particles: Arc::new(vec![]),
             settings: Arc::new(settings),
             pool: ThreadPool::new(THREAD_COUNT),
-            particle_types: Arc::new(ParticleTypeManager::new(settings.particle_types_count)),
+            force_law: settings.force_law.law(),
+            obstacles: ObstacleSet::default(),
+            fields: Arc::new(FieldSet::default()),
+            particle_types: Arc::new(
+                ParticleTypeManager::new(settings.particle_types_count, settings.seed)
+                    .expect("Scenes need at least one particle type"),
+            ),
         };
     }
 
@@ -47,154 +180,102 @@ impl SceneLike for MultithreadedScene {
     }
 
     async fn update(&mut self) {
-        let particles_per_job = self.particles.len() / THREAD_COUNT;
-        let particles_mutexes = (0..THREAD_COUNT)
-            .map(|job_index| {
-                let particles = Arc::clone(&self.particles);
-                let particle_types = Arc::clone(&self.particle_types);
-                let settings = Arc::clone(&self.settings);
-                let screen_size = settings.screen_size;
-                let new_particles = Arc::new(Mutex::new(Vec::<Particle>::with_capacity(
-                    particles_per_job,
-                )));
-                let new_particles_in_thread = Arc::clone(&new_particles);
-                self.pool.execute(move || {
-                    let start_i = job_index * particles_per_job;
-                    let end_i = start_i + particles_per_job;
-                    // let mut new_particles_local = Vec::<Particle>::with_capacity(particles_per_job);
-                    for i in start_i..end_i {
-                        let particle = particles[i];
-                        let mut total_force: Vec2d = [0.0, 0.0];
-                        for j in 0..particles.len() {
-                            if i != j {
-                                let p = particles[j];
-                                let mut direction: Vec2d = p.pos;
-                                sub(&mut direction, &particle.pos);
-                                if direction[0] > 0.5 * screen_size[0] as f64 {
-                                    direction[0] -= screen_size[0] as f64;
-                                }
-                                if direction[0] < -0.5 * screen_size[0] as f64 {
-                                    direction[0] += screen_size[0] as f64;
-                                }
-                                if direction[1] > 0.5 * screen_size[1] as f64 {
-                                    direction[1] -= screen_size[1] as f64;
-                                }
-                                if direction[1] < -0.5 * screen_size[1] as f64 {
-                                    direction[1] += screen_size[1] as f64;
-                                }
-                                let distance = len(&direction);
-                                normalize(&mut direction);
-                                if distance
-                                    < particle_types
-                                        .get_min_distance(particle.type_index, p.type_index)
-                                {
-                                    let mut force = direction;
-                                    mul_scalar(
-                                        &mut force,
-                                        (particle_types
-                                            .get_forces(particle.type_index, p.type_index)
-                                            .abs())
-                                            * -6.0,
-                                    );
-                                    mul_scalar(
-                                        &mut force,
-                                        remap(
-                                            distance,
-                                            0.0,
-                                            particle_types.get_min_distance(
-                                                particle.type_index,
-                                                p.type_index,
-                                            ),
-                                            1.1,
-                                            0.0,
-                                        ),
-                                    );
-                                    mul_scalar(&mut force, K);
-                                    add(&mut total_force, &force);
-                                }
-                                if distance
-                                    < particle_types.get_radii(particle.type_index, p.type_index)
-                                {
-                                    // apply_forces(
-                                    //     &mut total_force,
-                                    //     &direction,
-                                    //     particle_types
-                                    //         .get_forces(particle.type_index, p.type_index),
-                                    //     remap(
-                                    //         distance,
-                                    //         0.0,
-                                    //         particle_types
-                                    //             .get_radii(particle.type_index, p.type_index),
-                                    //         1.0,
-                                    //         0.0,
-                                    //     ),
-                                    // );
-                                    let mut force = direction;
-                                    mul_scalar(
-                                        &mut force,
-                                        particle_types
-                                            .get_forces(particle.type_index, p.type_index),
-                                    );
-                                    mul_scalar(
-                                        &mut force,
-                                        remap(
-                                            distance,
-                                            0.0,
-                                            particle_types
-                                                .get_radii(particle.type_index, p.type_index),
-                                            1.0,
-                                            0.0,
-                                        ),
-                                    );
-                                    mul_scalar(&mut force, K);
-                                    add(&mut total_force, &force);
-                                }
-                            }
-                        }
-                        let mut new_particle = particle;
-                        let mass = particle_types.get_particle_mass(particle.type_index);
-                        div_scalar(&mut total_force, mass);
-                        add(&mut new_particle.vel, &total_force);
-                        add(&mut new_particle.pos, &new_particle.vel);
-                        new_particle.pos[0] =
-                            (new_particle.pos[0] + screen_size[0] as f64) % screen_size[0] as f64;
-                        new_particle.pos[1] =
-                            (new_particle.pos[1] + screen_size[1] as f64) % screen_size[1] as f64;
-                        mul_scalar(
-                            &mut new_particle.vel,
-                            particle_types.get_particle_drag(particle.type_index),
-                        );
-                        new_particles_in_thread.lock().unwrap().push(new_particle);
-                    }
-                });
-                return new_particles;
-            })
-            .collect::<Vec<_>>();
-        self.pool.join();
-        let new_particles = particles_mutexes
-            .iter()
-            .flat_map(|mutex| {
-                mutex
-                    .lock()
-                    .unwrap()
-                    .iter()
-                    .map(|particle| *particle)
-                    .collect::<Vec<Particle>>()
-            })
-            .collect::<Vec<_>>();
-        self.particles = Arc::new(new_particles);
+        for _ in 0..self.settings.substeps {
+            self.step();
+        }
     }
 
     fn get_particles(&self) -> Arc<Vec<Particle>> {
         return Arc::clone(&self.particles);
     }
 
-    fn new_world(&mut self) {
-        self.particle_types =
-            Arc::new(ParticleTypeManager::new(self.settings.particle_types_count));
+    fn new_world(&mut self) -> u64 {
+        let mut settings = *self.settings;
+        settings.seed = settings.seed.wrapping_add(1);
+        self.particle_types = Arc::new(
+            ParticleTypeManager::new(settings.particle_types_count, settings.seed)
+                .expect("Scenes need at least one particle type"),
+        );
+        self.settings = Arc::new(settings);
+        return settings.seed;
     }
 
     fn get_particle_color(&self, type_index: usize) -> [f32; 4] {
         return self.particle_types.get_particle_color(type_index);
     }
+
+    fn get_particle_types(&self) -> &ParticleTypeManager {
+        return &self.particle_types;
+    }
+
+    fn set_particle_types(&mut self, particle_types: ParticleTypeManager) {
+        let mut settings = *self.settings;
+        settings.particle_types_count = particle_types.particle_types_count();
+        if let Some(seed) = particle_types.seed() {
+            settings.seed = seed;
+        }
+        for particle in Arc::make_mut(&mut self.particles) {
+            particle.type_index %= settings.particle_types_count;
+        }
+        self.settings = Arc::new(settings);
+        self.particle_types = Arc::new(particle_types);
+    }
+
+    fn get_settings(&self) -> SceneSettings {
+        return *self.settings;
+    }
+
+    fn set_particles(&mut self, particles: &[Particle]) {
+        let mut settings = *self.settings;
+        settings.particle_count = particles.len();
+        self.settings = Arc::new(settings);
+        self.particles = Arc::new(particles.to_vec());
+    }
+
+    fn spawn_particles(&mut self, particles: &[Particle]) {
+        Arc::make_mut(&mut self.particles).extend_from_slice(particles);
+        let mut settings = *self.settings;
+        settings.particle_count = self.particles.len();
+        self.settings = Arc::new(settings);
+    }
+
+    fn remove_particles(&mut self, indexes: &[usize]) {
+        let removed = removal_mask(self.particles.len(), indexes);
+        retain_unflagged(Arc::make_mut(&mut self.particles), &removed);
+        let mut settings = *self.settings;
+        settings.particle_count = self.particles.len();
+        self.settings = Arc::new(settings);
+    }
+
+    fn move_particles(&mut self, moves: &[(usize, Vec2d, Vec2d)]) {
+        let particles = Arc::make_mut(&mut self.particles);
+        for &(i, pos, vel) in moves {
+            if let Some(particle) = particles.get_mut(i) {
+                particle.pos = pos;
+                particle.vel = vel;
+            }
+        }
+    }
+
+    fn get_obstacles(&self) -> &ObstacleSet {
+        return &self.obstacles;
+    }
+
+    fn set_obstacles(&mut self, obstacles: ObstacleSet) {
+        self.obstacles = obstacles;
+    }
+
+    fn get_fields(&self) -> &FieldSet {
+        return &self.fields;
+    }
+
+    fn set_fields(&mut self, fields: FieldSet) {
+        self.fields = Arc::new(fields);
+    }
+
+    fn configure(&mut self, config: &SceneConfig) {
+        let mut settings = *self.settings;
+        config.apply(&mut settings);
+        self.settings = Arc::new(settings);
+    }
 }
@@ -5,16 +5,25 @@ use rand::{rng, Rng};
 use threadpool::ThreadPool;
 
 use crate::{
-    constants::{K, THREAD_COUNT},
+    constants::THREAD_COUNT,
+    field::FieldSet,
+    force_law::{pair_force, ForceLaw},
+    integrator::integrate,
+    neighbor_grid::NeighborGrid,
+    obstacle::ObstacleSet,
     particle_type::ParticleTypeManager,
-    scene_like::SceneLike,
-    vector::{add, div_scalar, len, mul_scalar, normalize, remap, sub},
+    scene_config::SceneConfig,
+    scene_like::{removal_mask, retain_unflagged, SceneLike},
+    vector::{add, div_scalar, sub},
     Particle, SceneSettings,
 };
 
 pub struct MultithreadedSceneV2 {
     settings: Arc<SceneSettings>,
     pub particle_types: Arc<ParticleTypeManager>,
+    force_law: Arc<dyn ForceLaw>,
+    obstacles: ObstacleSet,
+    fields: Arc<FieldSet>,
     pool: ThreadPool,
 
     particles_pos: Arc<Vec<Vec2d>>,
@@ -22,75 +31,104 @@ pub struct MultithreadedSceneV2 {
     particles_type_indexes: Arc<Vec<usize>>,
 }
 
-impl SceneLike for MultithreadedSceneV2 {
-    async fn new(settings: SceneSettings) -> Self {
-        return Self {
-            settings: Arc::new(settings),
-            pool: ThreadPool::new(THREAD_COUNT),
-            particle_types: Arc::new(ParticleTypeManager::new(settings.particle_types_count)),
-
-            particles_pos: Arc::new(vec![]),
-            particles_vel: Arc::new(vec![]),
-            particles_type_indexes: Arc::new(vec![]),
-        };
+impl MultithreadedSceneV2 {
+    /// Replaces the law picked by [`SceneSettings::force_law`], e.g. with a
+    /// custom [`ForceLaw`] implementation.
+    pub fn set_force_law(&mut self, force_law: Arc<dyn ForceLaw>) {
+        self.force_law = force_law;
     }
 
-    fn init(&mut self) {
-        let random_source = &mut rng();
-        self.particles_pos = Arc::new(
-            (0..self.settings.particle_count)
-                .map(|_| {
-                    [
-                        random_source.random_range(0.0..(self.settings.screen_size[0] as f64)),
-                        random_source.random_range(0.0..(self.settings.screen_size[0] as f64)),
-                    ]
-                })
-                .collect(),
-        );
-        self.particles_vel = Arc::new(
-            (0..self.settings.particle_count)
-                .map(|_| [0.0, 0.0])
-                .collect(),
-        );
-        self.particles_type_indexes = Arc::new(
-            (0..self.settings.particle_count)
-                .map(|_| random_source.random_range(0..self.settings.particle_types_count))
-                .collect(),
+    /// One simulation step of length [`SceneSettings::dt`].
+    fn step(&mut self) {
+        let mut positions = self.particles_pos.to_vec();
+        let mut velocities = self.particles_vel.to_vec();
+        let drag = self
+            .particles_type_indexes
+            .iter()
+            .map(|t| self.particle_types.get_particle_drag(*t))
+            .collect::<Vec<_>>();
+        integrate(
+            &self.settings,
+            &mut positions,
+            &mut velocities,
+            &drag,
+            |positions| self.accelerations(positions),
         );
+        for i in 0..positions.len() {
+            self.obstacles.collide(
+                self.particles_type_indexes[i],
+                &self.particles_pos[i],
+                &mut positions[i],
+                &mut velocities[i],
+                &self.settings,
+            );
+        }
+        let boundary = self.settings.boundary;
+        let screen_size = self.settings.screen_size.map(|v| v as f64);
+        if positions
+            .iter()
+            .any(|pos| boundary.absorbs(pos, &screen_size))
+        {
+            let kept = (0..positions.len())
+                .filter(|i| !boundary.absorbs(&positions[*i], &screen_size))
+                .collect::<Vec<_>>();
+            positions = kept.iter().map(|i| positions[*i]).collect();
+            velocities = kept.iter().map(|i| velocities[*i]).collect();
+            self.particles_type_indexes = Arc::new(
+                kept.iter()
+                    .map(|i| self.particles_type_indexes[*i])
+                    .collect(),
+            );
+            let mut settings = *self.settings;
+            settings.particle_count = kept.len();
+            self.settings = Arc::new(settings);
+        }
+        self.particles_pos = Arc::new(positions);
+        self.particles_vel = Arc::new(velocities);
     }
 
-    async fn update(&mut self) {
-        let particles_per_job = self.particles_pos.len() / THREAD_COUNT;
-        let particles_mutexes = (0..THREAD_COUNT)
+    /// Acceleration of every particle if the particles were at `positions`.
+    fn accelerations(&self, positions: &[Vec2d]) -> Vec<Vec2d> {
+        let particles_pos = Arc::new(positions.to_vec());
+        let particle_count = particles_pos.len();
+        let particles_per_job = particle_count.div_ceil(THREAD_COUNT);
+        let grid = Arc::new(NeighborGrid::build(
+            particles_pos.iter().copied(),
+            self.settings.screen_size.map(|v| v as f64),
+            self.settings.neighbor_search.cutoff(&self.particle_types),
+        ));
+        let accelerations_mutexes = (0..THREAD_COUNT)
             .map(|job_index| {
-                let particles_vel = Arc::clone(&self.particles_vel);
-                let particles_pos = Arc::clone(&self.particles_pos);
+                let particles_pos = Arc::clone(&particles_pos);
                 let particles_type_indexes = Arc::clone(&self.particles_type_indexes);
 
                 let particle_types = Arc::clone(&self.particle_types);
+                let force_law = Arc::clone(&self.force_law);
+                let grid = Arc::clone(&grid);
                 let settings = Arc::clone(&self.settings);
                 let screen_size = [
                     settings.screen_size[0] as f64,
                     settings.screen_size[1] as f64,
                 ];
+                let boundary = settings.boundary;
+                let fields = Arc::clone(&self.fields);
 
-                let new_particles_vel_chunk =
-                    Arc::new(Mutex::new(Vec::<Vec2d>::with_capacity(particles_per_job)));
-                let new_particles_pos_chunk =
+                let accelerations_chunk =
                     Arc::new(Mutex::new(Vec::<Vec2d>::with_capacity(particles_per_job)));
 
                 self.pool.execute({
-                    let new_particles_vel_chunk = Arc::clone(&new_particles_vel_chunk);
-                    let new_particles_pos_chunk = Arc::clone(&new_particles_pos_chunk);
+                    let accelerations_chunk = Arc::clone(&accelerations_chunk);
                     move || {
-                        let start_i = job_index * particles_per_job;
-                        let end_i = start_i + particles_per_job;
-                        let mut velocities = (start_i..end_i)
+                        let start_i = (job_index * particles_per_job).min(particle_count);
+                        let end_i = (start_i + particles_per_job).min(particle_count);
+                        let mut accelerations = (start_i..end_i)
                             .map(|i| {
                                 let p_pos = particles_pos[i];
                                 let p_type = particles_type_indexes[i];
-                                let p_vel = particles_vel[i];
-                                let mut total_force = (0..particles_vel.len())
+                                let mut total_force = grid
+                                    .neighbors(&p_pos)
+                                    .iter()
+                                    .copied()
                                     .filter(|j| i != *j)
                                     .fold([0.0, 0.0], |force_acc, j| {
                                         let mut force_acc = force_acc;
@@ -98,123 +136,97 @@ impl SceneLike for MultithreadedSceneV2 {
                                         let p2_pos = particles_pos[j];
                                         let mut direction: Vec2d = p2_pos;
                                         sub(&mut direction, &p_pos);
-                                        if direction[0] > 0.5 * screen_size[0] {
-                                            direction[0] -= screen_size[0];
-                                        }
-                                        if direction[0] < -0.5 * screen_size[0] {
-                                            direction[0] += screen_size[0];
-                                        }
-                                        if direction[1] > 0.5 * screen_size[1] {
-                                            direction[1] -= screen_size[1];
-                                        }
-                                        if direction[1] < -0.5 * screen_size[1] {
-                                            direction[1] += screen_size[1];
-                                        }
-                                        let distance = len(&direction);
-                                        normalize(&mut direction);
-                                        let p_min_distance =
-                                            particle_types.get_min_distance(p_type, p2_type);
-                                        if distance < p_min_distance {
-                                            let mut force = direction;
-                                            mul_scalar(
-                                                &mut force,
-                                                (particle_types.get_forces(p_type, p2_type).abs())
-                                                    * -6.0,
-                                            );
-                                            mul_scalar(
-                                                &mut force,
-                                                remap(distance, 0.0, p_min_distance, 1.1, 0.0),
-                                            );
-                                            mul_scalar(&mut force, K);
-                                            add(&mut force_acc, &force);
-                                        }
-                                        let p_radii_distance =
-                                            particle_types.get_radii(p_type, p2_type);
-                                        if distance < p_radii_distance {
-                                            let mut force = direction;
-                                            mul_scalar(
-                                                &mut force,
-                                                particle_types.get_forces(p_type, p2_type),
-                                            );
-                                            mul_scalar(
-                                                &mut force,
-                                                remap(distance, 0.0, p_radii_distance, 1.0, 0.0),
-                                            );
-                                            mul_scalar(&mut force, K);
-                                            add(&mut force_acc, &force);
-                                        }
+                                        boundary.minimum_image(&mut direction, &screen_size);
+                                        let force = pair_force(
+                                            &*force_law,
+                                            &particle_types,
+                                            p_type,
+                                            p2_type,
+                                            direction,
+                                        );
+                                        add(&mut force_acc, &force);
                                         return force_acc;
                                     });
 
                                 let mass = particle_types.get_particle_mass(p_type);
                                 div_scalar(&mut total_force, mass);
-                                let mut next_p_vel = p_vel;
-                                add(&mut next_p_vel, &total_force);
-                                mul_scalar(
-                                    &mut next_p_vel,
-                                    particle_types.get_particle_drag(p_type),
+                                add(
+                                    &mut total_force,
+                                    &fields.acceleration(p_type, &p_pos, &settings),
                                 );
-                                return next_p_vel;
-                            })
-                            .collect::<Vec<Vec2d>>();
-
-                        let mut positions = (start_i..end_i)
-                            .map(|i| {
-                                let p_pos = particles_pos[i];
-                                let mut next_p_pos = p_pos;
-                                add(&mut next_p_pos, &velocities[i - start_i]);
-                                next_p_pos[0] = (next_p_pos[0] + screen_size[0]) % screen_size[0];
-                                next_p_pos[1] = (next_p_pos[1] + screen_size[1]) % screen_size[1];
-                                return next_p_pos;
+                                return total_force;
                             })
                             .collect::<Vec<Vec2d>>();
 
-                        let mut new_particles_pos_chunk = new_particles_pos_chunk.lock().unwrap();
-                        new_particles_pos_chunk.append(&mut positions);
-                        let mut new_particles_vel_chunk = new_particles_vel_chunk.lock().unwrap();
-                        new_particles_vel_chunk.append(&mut velocities);
+                        accelerations_chunk
+                            .lock()
+                            .unwrap()
+                            .append(&mut accelerations);
                     }
                 });
 
-                return (new_particles_vel_chunk, new_particles_pos_chunk);
+                return accelerations_chunk;
             })
             .collect::<Vec<_>>();
         self.pool.join();
-        let new_particles = particles_mutexes
+        return accelerations_mutexes
             .iter()
-            .map(|(vel_mutex, pos_mutex)| {
-                return (
-                    vel_mutex
-                        .lock()
-                        .unwrap()
-                        .clone()
-                        .into_iter()
-                        .collect::<Vec<Vec2d>>(),
-                    pos_mutex
-                        .lock()
-                        .unwrap()
-                        .clone()
-                        .into_iter()
-                        .collect::<Vec<Vec2d>>(),
-                );
-            })
-            .collect::<Vec<(Vec<_>, Vec<_>)>>();
+            .flat_map(|mutex| mutex.lock().unwrap().clone())
+            .collect::<Vec<Vec2d>>();
+    }
+}
+
+impl SceneLike for MultithreadedSceneV2 {
+    async fn new(settings: SceneSettings) -> Self {
+        return Self {
+            settings: Arc::new(settings),
+            pool: ThreadPool::new(THREAD_COUNT),
+            force_law: settings.force_law.law(),
+            obstacles: ObstacleSet::default(),
+            fields: Arc::new(FieldSet::default()),
+            particle_types: Arc::new(
+                ParticleTypeManager::new(settings.particle_types_count, settings.seed)
+                    .expect("Scenes need at least one particle type"),
+            ),
+
+            particles_pos: Arc::new(vec![]),
+            particles_vel: Arc::new(vec![]),
+            particles_type_indexes: Arc::new(vec![]),
+        };
+    }
+
+    fn init(&mut self) {
+        let random_source = &mut rng();
+        self.particles_pos = Arc::new(
+            (0..self.settings.particle_count)
+                .map(|_| {
+                    [
+                        random_source.random_range(0.0..(self.settings.screen_size[0] as f64)),
+                        random_source.random_range(0.0..(self.settings.screen_size[1] as f64)),
+                    ]
+                })
+                .collect(),
+        );
         self.particles_vel = Arc::new(
-            new_particles
-                .iter()
-                .flat_map(|(vel, _)| vel.clone())
-                .collect::<Vec<Vec2d>>(),
+            (0..self.settings.particle_count)
+                .map(|_| [0.0, 0.0])
+                .collect(),
         );
-        self.particles_pos = Arc::new(
-            new_particles
-                .into_iter()
-                .flat_map(|(_, pos)| pos)
-                .collect::<Vec<Vec2d>>(),
+        self.particles_type_indexes = Arc::new(
+            (0..self.settings.particle_count)
+                .map(|_| random_source.random_range(0..self.settings.particle_types_count))
+                .collect(),
         );
     }
 
+    async fn update(&mut self) {
+        for _ in 0..self.settings.substeps {
+            self.step();
+        }
+    }
+
     fn get_particles(&self) -> Arc<Vec<Particle>> {
-        let particles = (0..self.settings.particle_count)
+        let particles = (0..self.particles_pos.len())
             .map(|i| Particle {
                 pos: self.particles_pos[i],
                 vel: self.particles_vel[i],
@@ -224,11 +236,101 @@ impl SceneLike for MultithreadedSceneV2 {
         return Arc::new(particles);
     }
 
-    fn new_world(&mut self) {
-        todo!()
+    fn new_world(&mut self) -> u64 {
+        let mut settings = *self.settings;
+        settings.seed = settings.seed.wrapping_add(1);
+        self.particle_types = Arc::new(
+            ParticleTypeManager::new(settings.particle_types_count, settings.seed)
+                .expect("Scenes need at least one particle type"),
+        );
+        self.settings = Arc::new(settings);
+        return settings.seed;
     }
 
     fn get_particle_color(&self, type_index: usize) -> [f32; 4] {
         return self.particle_types.get_particle_color(type_index);
     }
+
+    fn get_particle_types(&self) -> &ParticleTypeManager {
+        return &self.particle_types;
+    }
+
+    fn set_particle_types(&mut self, particle_types: ParticleTypeManager) {
+        let mut settings = *self.settings;
+        settings.particle_types_count = particle_types.particle_types_count();
+        if let Some(seed) = particle_types.seed() {
+            settings.seed = seed;
+        }
+        for type_index in Arc::make_mut(&mut self.particles_type_indexes) {
+            *type_index %= settings.particle_types_count;
+        }
+        self.settings = Arc::new(settings);
+        self.particle_types = Arc::new(particle_types);
+    }
+
+    fn get_settings(&self) -> SceneSettings {
+        return *self.settings;
+    }
+
+    fn set_particles(&mut self, particles: &[Particle]) {
+        let mut settings = *self.settings;
+        settings.particle_count = particles.len();
+        self.settings = Arc::new(settings);
+        self.particles_pos = Arc::new(particles.iter().map(|p| p.pos).collect());
+        self.particles_vel = Arc::new(particles.iter().map(|p| p.vel).collect());
+        self.particles_type_indexes = Arc::new(particles.iter().map(|p| p.type_index).collect());
+    }
+
+    fn spawn_particles(&mut self, particles: &[Particle]) {
+        Arc::make_mut(&mut self.particles_pos).extend(particles.iter().map(|p| p.pos));
+        Arc::make_mut(&mut self.particles_vel).extend(particles.iter().map(|p| p.vel));
+        Arc::make_mut(&mut self.particles_type_indexes)
+            .extend(particles.iter().map(|p| p.type_index));
+        let mut settings = *self.settings;
+        settings.particle_count = self.particles_pos.len();
+        self.settings = Arc::new(settings);
+    }
+
+    fn remove_particles(&mut self, indexes: &[usize]) {
+        let removed = removal_mask(self.particles_pos.len(), indexes);
+        retain_unflagged(Arc::make_mut(&mut self.particles_pos), &removed);
+        retain_unflagged(Arc::make_mut(&mut self.particles_vel), &removed);
+        retain_unflagged(Arc::make_mut(&mut self.particles_type_indexes), &removed);
+        let mut settings = *self.settings;
+        settings.particle_count = self.particles_pos.len();
+        self.settings = Arc::new(settings);
+    }
+
+    fn move_particles(&mut self, moves: &[(usize, Vec2d, Vec2d)]) {
+        let particles_pos = Arc::make_mut(&mut self.particles_pos);
+        let particles_vel = Arc::make_mut(&mut self.particles_vel);
+        for &(i, pos, vel) in moves {
+            if i < particles_pos.len() {
+                particles_pos[i] = pos;
+                particles_vel[i] = vel;
+            }
+        }
+    }
+
+    fn get_obstacles(&self) -> &ObstacleSet {
+        return &self.obstacles;
+    }
+
+    fn set_obstacles(&mut self, obstacles: ObstacleSet) {
+        self.obstacles = obstacles;
+    }
+
+    fn get_fields(&self) -> &FieldSet {
+        return &self.fields;
+    }
+
+    fn set_fields(&mut self, fields: FieldSet) {
+        self.fields = Arc::new(fields);
+    }
+
+    fn configure(&mut self, config: &SceneConfig) {
+        let mut settings = *self.settings;
+        config.apply(&mut settings);
+        self.settings = Arc::new(settings);
+    }
 }
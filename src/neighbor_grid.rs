@@ -0,0 +1,204 @@
+//! Uniform grid for finding interaction partners on the periodic world.
+//!
+//! Cells are at least as large as the interaction cutoff, so every particle
+//! within reach lies in the 3x3 block of cells around a particle, with the
+//! block wrapping around the world edges. Every particle in a cell has the
+//! same block, so each cell's block is listed once, in ascending index order,
+//! at the cost of storing every particle in up to nine blocks.
+
+use clap::ValueEnum;
+use graphics::math::Vec2d;
+use serde::{Deserialize, Serialize};
+
+use crate::particle_type::ParticleTypeManager;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum NeighborSearch {
+    /// Only visit particles in neighbouring grid cells.
+    #[default]
+    Grid,
+    /// Visit every particle, O(N²). Kept as the baseline for the grid.
+    BruteForce,
+}
+
+impl NeighborSearch {
+    /// Cell size to build a [`NeighborGrid`] with for these rules.
+    pub fn cutoff(&self, particle_types: &ParticleTypeManager) -> f64 {
+        return match self {
+            NeighborSearch::Grid => particle_types.get_max_interaction_distance(),
+            NeighborSearch::BruteForce => f64::INFINITY,
+        };
+    }
+}
+
+pub struct NeighborGrid {
+    cells: [usize; 2],
+    cell_size: Vec2d,
+    /// `indices[block_start[c]..block_start[c + 1]]` are the particles in the
+    /// block around cell `c`.
+    block_start: Vec<usize>,
+    indices: Vec<usize>,
+}
+
+impl NeighborGrid {
+    /// Buckets `positions` into cells no smaller than `cutoff`. An infinite
+    /// cutoff gives a single cell, i.e. a brute-force search.
+    pub fn build(
+        positions: impl Iterator<Item = Vec2d>,
+        screen_size: Vec2d,
+        cutoff: f64,
+    ) -> NeighborGrid {
+        let cells = NeighborGrid::dimensions(screen_size, cutoff);
+        let cell_size = [
+            screen_size[0] / cells[0] as f64,
+            screen_size[1] / cells[1] as f64,
+        ];
+        let cell_total = cells[0] * cells[1];
+        let mut grid = NeighborGrid {
+            cells,
+            cell_size,
+            block_start: vec![0; cell_total + 1],
+            indices: vec![],
+        };
+        let particle_cells = positions
+            .map(|pos| grid.cell_of(&pos))
+            .collect::<Vec<_>>();
+        let mut cell_counts = vec![0; cell_total];
+        for &cell in &particle_cells {
+            cell_counts[cell] += 1;
+        }
+        for c in 0..cell_total {
+            let (block, block_size) = grid.block(c);
+            let count = block[..block_size].iter().map(|&b| cell_counts[b]).sum::<usize>();
+            grid.block_start[c + 1] = grid.block_start[c] + count;
+        }
+        // A cell is in the block around another exactly when that one is in
+        // its block, so walking the particles by index and adding each to the
+        // blocks around its cell fills every block in ascending order.
+        grid.indices = vec![0; grid.block_start[cell_total]];
+        let mut cursor = grid.block_start.clone();
+        for (i, &cell) in particle_cells.iter().enumerate() {
+            let (block, block_size) = grid.block(cell);
+            for &b in &block[..block_size] {
+                grid.indices[cursor[b]] = i;
+                cursor[b] += 1;
+            }
+        }
+        return grid;
+    }
+
+    /// Number of cells along each axis for a world of `screen_size`.
+    pub fn dimensions(screen_size: Vec2d, cutoff: f64) -> [usize; 2] {
+        return [0, 1].map(|axis| {
+            if cutoff.is_finite() && cutoff > 0.0 {
+                ((screen_size[axis] / cutoff).floor() as usize).max(1)
+            } else {
+                1
+            }
+        });
+    }
+
+    #[inline(always)]
+    fn cell_coords(&self, pos: &Vec2d) -> [usize; 2] {
+        return [0, 1].map(|axis| {
+            ((pos[axis] / self.cell_size[axis]).floor().max(0.0) as usize).min(self.cells[axis] - 1)
+        });
+    }
+
+    #[inline(always)]
+    fn cell_of(&self, pos: &Vec2d) -> usize {
+        let [x, y] = self.cell_coords(pos);
+        return y * self.cells[0] + x;
+    }
+
+    /// Cells to visit along `axis`. With fewer than three cells the wrapped
+    /// neighbours would repeat, so every cell is visited once instead.
+    #[inline(always)]
+    fn axis_cells(&self, axis: usize, center: usize) -> ([usize; 3], usize) {
+        let count = self.cells[axis];
+        if count < 3 {
+            return ([0, 1, 2], count);
+        }
+        return (
+            [(center + count - 1) % count, center, (center + 1) % count],
+            3,
+        );
+    }
+
+    /// Cells of the block around `cell`, each listed once.
+    fn block(&self, cell: usize) -> ([usize; 9], usize) {
+        let (xs, x_count) = self.axis_cells(0, cell % self.cells[0]);
+        let (ys, y_count) = self.axis_cells(1, cell / self.cells[0]);
+        let mut block = [0; 9];
+        let mut block_size = 0;
+        for y in &ys[..y_count] {
+            for x in &xs[..x_count] {
+                block[block_size] = y * self.cells[0] + x;
+                block_size += 1;
+            }
+        }
+        return (block, block_size);
+    }
+
+    /// Candidate partners of a particle at `pos`, in ascending index order so
+    /// forces are summed in the same order as a brute-force loop. The result
+    /// includes the particle itself and particles beyond the cutoff.
+    pub fn neighbors(&self, pos: &Vec2d) -> &[usize] {
+        let cell = self.cell_of(pos);
+        return &self.indices[self.block_start[cell]..self.block_start[cell + 1]];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cells_are_no_smaller_than_the_cutoff() {
+        assert_eq!(NeighborGrid::dimensions([400.0, 300.0], 90.0), [4, 3]);
+        assert_eq!(NeighborGrid::dimensions([400.0, 300.0], 500.0), [1, 1]);
+        assert_eq!(
+            NeighborGrid::dimensions([400.0, 300.0], f64::INFINITY),
+            [1, 1]
+        );
+        assert_eq!(NeighborGrid::dimensions([400.0, 300.0], 0.0), [1, 1]);
+    }
+
+    #[test]
+    fn neighbors_wrap_around_the_edges() {
+        let positions = [
+            [5.0, 5.0],
+            [395.0, 295.0],
+            [200.0, 150.0],
+            [395.0, 5.0],
+            [100.0, 5.0],
+        ];
+        let grid = NeighborGrid::build(positions.iter().copied(), [400.0, 300.0], 50.0);
+        assert_eq!(grid.neighbors(&positions[0]), [0, 1, 3]);
+        assert_eq!(grid.neighbors(&positions[2]), [2]);
+        assert_eq!(grid.neighbors(&positions[4]), [4]);
+    }
+
+    #[test]
+    fn narrow_worlds_visit_each_cell_once() {
+        // Two cells across: the left and right neighbour are the same cell.
+        let positions = [[10.0, 10.0], [190.0, 10.0], [10.0, 290.0]];
+        let grid = NeighborGrid::build(positions.iter().copied(), [200.0, 300.0], 90.0);
+        assert_eq!(grid.neighbors(&positions[0]), [0, 1, 2]);
+    }
+
+    #[test]
+    fn neighbors_come_in_ascending_order() {
+        // Particles of the nine cells interleave, so no cell comes in one piece.
+        let positions = (0..90)
+            .map(|i| [(i % 3) as f64 * 100.0 + 50.0, (i / 3 % 3) as f64 * 100.0 + 50.0])
+            .collect::<Vec<_>>();
+        let grid = NeighborGrid::build(positions.iter().copied(), [500.0, 500.0], 100.0);
+        for pos in &positions {
+            let neighbors = grid.neighbors(pos);
+            assert!(neighbors.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+        assert!(grid.neighbors(&positions[4]).iter().copied().eq(0..90));
+    }
+}
@@ -0,0 +1,573 @@
+//! Static obstacles particles collide with, stored as TOML.
+//!
+//! ```toml
+//! [[obstacles]]
+//! shape = "circle"
+//! center = [400.0, 300.0]
+//! radius = 50.0
+//!
+//! # A membrane that lets types 1 and 2 through.
+//! [[obstacles]]
+//! shape = "segment"
+//! start = [0.0, 600.0]
+//! end = [800.0, 600.0]
+//! thickness = 4.0
+//! permeable_to = [1, 2]
+//!
+//! [[obstacles]]
+//! shape = "polygon"
+//! points = [[100.0, 100.0], [200.0, 100.0], [150.0, 180.0]]
+//!
+//! # Dark pixels are solid, one pixel per `cell_size` world units. Inline
+//! # masks use `rows = ["..##..", ...]` with `#` for solid cells instead.
+//! [[obstacles]]
+//! shape = "mask"
+//! image = "maze.png"
+//! origin = [0.0, 0.0]
+//! cell_size = 4.0
+//! ```
+//!
+//! Obstacles are not wrapped around the edges of periodic worlds.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use graphics::math::Vec2d;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Error, Result},
+    vector::{add, dot, len, mul_scalar, sub},
+    SceneSettings,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "shape", rename_all = "kebab-case")]
+pub enum Shape {
+    Circle {
+        center: Vec2d,
+        radius: f64,
+    },
+    /// A line segment thickened into a capsule `thickness` wide.
+    Segment {
+        start: Vec2d,
+        end: Vec2d,
+        #[serde(default = "default_thickness")]
+        thickness: f64,
+    },
+    /// A closed polygon, convex or not, in either winding order.
+    Polygon {
+        points: Vec<Vec2d>,
+    },
+    /// A bitmap of solid cells. `rows[y]` holds one character per cell, `#`
+    /// for solid ones, starting at `origin`.
+    Mask {
+        #[serde(default)]
+        origin: Vec2d,
+        #[serde(default = "default_cell_size")]
+        cell_size: f64,
+        #[serde(default)]
+        rows: Vec<String>,
+        /// Image the rows are read from by [`ObstacleSet::load`], if any.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        image: Option<PathBuf>,
+    },
+}
+
+/// Distance from the surface at which a moving particle counts as touching
+/// it, see [`Shape::first_contact`].
+const CONTACT_DISTANCE: f64 = 1e-3;
+/// Steps [`Shape::first_contact`] takes before it gives up on a path that
+/// grazes the surface.
+const MAX_CONTACT_STEPS: usize = 32;
+
+fn default_thickness() -> f64 {
+    return 2.0;
+}
+
+fn default_cell_size() -> f64 {
+    return 1.0;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Obstacle {
+    #[serde(flatten)]
+    pub shape: Shape,
+    /// Types that pass through the obstacle as if it was not there.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub permeable_to: Vec<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ObstacleSet {
+    #[serde(default)]
+    pub obstacles: Vec<Obstacle>,
+}
+
+impl Shape {
+    /// Signed distance from `pos` to the surface, negative inside, and the
+    /// direction that leads out of the shape along the shortest path. `None`
+    /// for masks, which have no smooth surface.
+    fn surface(&self, pos: &Vec2d) -> Option<(f64, Vec2d)> {
+        return match self {
+            Shape::Circle { center, radius } => {
+                let (distance, normal) = away_from(pos, center, [1.0, 0.0]);
+                Some((distance - radius, normal))
+            }
+            Shape::Segment {
+                start,
+                end,
+                thickness,
+            } => {
+                let closest = closest_on_segment(pos, start, end);
+                let mut across = [start[1] - end[1], end[0] - start[0]];
+                let (distance, normal) = away_from(pos, &closest, *normalize_or_x(&mut across));
+                Some((distance - 0.5 * thickness, normal))
+            }
+            Shape::Polygon { points } => {
+                let mut closest = points[0];
+                let mut closest_distance = f64::INFINITY;
+                let mut edge_normal = [1.0, 0.0];
+                let mut inside = false;
+                for (i, a) in points.iter().enumerate() {
+                    let b = &points[(i + 1) % points.len()];
+                    let candidate = closest_on_segment(pos, a, b);
+                    let mut offset = *pos;
+                    let distance = len(sub(&mut offset, &candidate));
+                    if distance < closest_distance {
+                        closest = candidate;
+                        closest_distance = distance;
+                        edge_normal = [b[1] - a[1], a[0] - b[0]];
+                    }
+                    if (a[1] > pos[1]) != (b[1] > pos[1])
+                        && pos[0] < a[0] + (pos[1] - a[1]) * (b[0] - a[0]) / (b[1] - a[1])
+                    {
+                        inside = !inside;
+                    }
+                }
+                mul_scalar(&mut edge_normal, signed_area(points).signum());
+                let edge_normal = *normalize_or_x(&mut edge_normal);
+                // Too close to the edge for the offset to have a direction.
+                if closest_distance < CONTACT_DISTANCE {
+                    let distance = if inside {
+                        -closest_distance
+                    } else {
+                        closest_distance
+                    };
+                    return Some((distance, edge_normal));
+                }
+                if inside {
+                    // The way out leads towards the closest point.
+                    let (distance, normal) = away_from(&closest, pos, edge_normal);
+                    Some((-distance, normal))
+                } else {
+                    Some(away_from(pos, &closest, edge_normal))
+                }
+            }
+            Shape::Mask { .. } => None,
+        };
+    }
+
+    /// Where a particle moving straight from `from` to `to` first touches
+    /// the surface while heading inside, and the way out there. Marches along
+    /// the path by the distance to the surface, which never skips past it.
+    /// `None` for masks, particles that start inside and paths that stay
+    /// clear of the shape.
+    fn first_contact(&self, from: &Vec2d, to: &Vec2d) -> Option<(Vec2d, Vec2d)> {
+        let mut path = *to;
+        sub(&mut path, from);
+        let length = len(&path);
+        if length == 0.0 {
+            return None;
+        }
+        let mut t = 0.0;
+        for _ in 0..MAX_CONTACT_STEPS {
+            let mut point = path;
+            add(mul_scalar(&mut point, t), from);
+            let (distance, normal) = self.surface(&point)?;
+            if distance < -CONTACT_DISTANCE {
+                return None;
+            }
+            if distance < CONTACT_DISTANCE && dot(&path, &normal) < 0.0 {
+                // Onto the surface, wherever in the margin the march stopped.
+                let mut onto = normal;
+                add(&mut point, mul_scalar(&mut onto, -distance));
+                return Some((point, normal));
+            }
+            if t >= 1.0 {
+                return None;
+            }
+            t = (t + distance.max(CONTACT_DISTANCE) / length).min(1.0);
+        }
+        return None;
+    }
+
+    fn mask_solid(&self, pos: &Vec2d) -> bool {
+        let Shape::Mask {
+            origin,
+            cell_size,
+            rows,
+            ..
+        } = self
+        else {
+            return false;
+        };
+        let x = ((pos[0] - origin[0]) / cell_size).floor();
+        let y = ((pos[1] - origin[1]) / cell_size).floor();
+        if x < 0.0 || y < 0.0 {
+            return false;
+        }
+        return rows
+            .get(y as usize)
+            .and_then(|row| row.as_bytes().get(x as usize))
+            .is_some_and(|cell| *cell == b'#');
+    }
+
+    /// Whether `pos` is inside the shape.
+    pub fn contains(&self, pos: &Vec2d) -> bool {
+        return match self.surface(pos) {
+            Some((distance, _)) => distance < 0.0,
+            None => self.mask_solid(pos),
+        };
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        let valid = match self {
+            Shape::Circle { radius, .. } => *radius > 0.0,
+            Shape::Segment { thickness, .. } => *thickness > 0.0,
+            Shape::Polygon { points } => points.len() >= 3,
+            Shape::Mask {
+                cell_size, rows, ..
+            } => *cell_size > 0.0 && rows.iter().all(|row| row.is_ascii()),
+        };
+        if !valid {
+            return Err(Error::InvalidObstacles(format!(
+                "degenerate obstacle {:?}",
+                self
+            )));
+        }
+        return Ok(());
+    }
+}
+
+impl Obstacle {
+    pub fn blocks(&self, type_index: usize) -> bool {
+        return !self.permeable_to.contains(&type_index);
+    }
+
+    /// Stops a particle that ran into the obstacle during a step and bounces
+    /// it off, keeping `restitution` of its normal velocity. `previous` is
+    /// where the particle started the step, so that a fast particle is caught
+    /// on the side it came from even when it would have crossed the obstacle
+    /// or most of it. Particles that started inside are pushed out the
+    /// shortest way.
+    pub fn collide(&self, previous: &Vec2d, pos: &mut Vec2d, vel: &mut Vec2d, restitution: f64) {
+        if let Shape::Mask { .. } = self.shape {
+            if !self.shape.mask_solid(pos) || self.shape.mask_solid(previous) {
+                return;
+            }
+            // Undo the move along the axes that entered the mask.
+            if !self.shape.mask_solid(&[pos[0], previous[1]]) {
+                pos[1] = previous[1];
+                vel[1] = -vel[1] * restitution;
+            } else if !self.shape.mask_solid(&[previous[0], pos[1]]) {
+                pos[0] = previous[0];
+                vel[0] = -vel[0] * restitution;
+            } else {
+                *pos = *previous;
+                mul_scalar(vel, -restitution);
+            }
+            return;
+        }
+        if let Some((contact, normal)) = self.shape.first_contact(previous, pos) {
+            *pos = contact;
+            bounce(vel, &normal, restitution);
+            return;
+        }
+        let Some((distance, normal)) = self.shape.surface(pos) else {
+            return;
+        };
+        if distance < 0.0 {
+            let mut push = normal;
+            add(pos, mul_scalar(&mut push, -distance));
+            bounce(vel, &normal, restitution);
+        }
+    }
+}
+
+impl ObstacleSet {
+    pub fn is_empty(&self) -> bool {
+        return self.obstacles.is_empty();
+    }
+
+    /// Resolves the collisions of one particle of `type_index` with every
+    /// obstacle that blocks it, in order. `previous` is where the particle
+    /// started the step, before periodic worlds wrapped it.
+    pub fn collide(
+        &self,
+        type_index: usize,
+        previous: &Vec2d,
+        pos: &mut Vec2d,
+        vel: &mut Vec2d,
+        settings: &SceneSettings,
+    ) {
+        // Where the particle started as seen from `pos`, so the path between
+        // them does not cross the whole world.
+        let screen_size = settings.screen_size.map(|v| v as f64);
+        let mut start = *pos;
+        let mut moved = *pos;
+        sub(&mut moved, previous);
+        sub(
+            &mut start,
+            settings.boundary.minimum_image(&mut moved, &screen_size),
+        );
+        for obstacle in &self.obstacles {
+            if obstacle.blocks(type_index) {
+                obstacle.collide(&start, pos, vel, settings.restitution);
+            }
+        }
+    }
+
+    pub fn from_toml(source: &str) -> Result<ObstacleSet> {
+        let obstacles: ObstacleSet =
+            toml::from_str(source).map_err(|err| Error::Parse(err.to_string()))?;
+        for obstacle in &obstacles.obstacles {
+            obstacle.shape.validate()?;
+        }
+        return Ok(obstacles);
+    }
+
+    pub fn to_toml(&self) -> String {
+        return toml::to_string(self).expect("Obstacles should always be serializable to TOML");
+    }
+
+    /// Reads the obstacles and the mask images they refer to. Image paths are
+    /// relative to the obstacle file.
+    pub fn load(path: &Path) -> Result<ObstacleSet> {
+        let mut obstacles = fs::read_to_string(path)
+            .map_err(Error::from)
+            .and_then(|source| ObstacleSet::from_toml(&source))
+            .map_err(|err| err.in_file(path))?;
+        let directory = path.parent().unwrap_or(Path::new(""));
+        for obstacle in &mut obstacles.obstacles {
+            if let Shape::Mask {
+                rows,
+                image: Some(image),
+                ..
+            } = &mut obstacle.shape
+            {
+                *image = directory.join(&*image);
+                *rows = mask_rows(image)?;
+            }
+        }
+        return Ok(obstacles);
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_toml())?;
+        return Ok(());
+    }
+}
+
+/// Turns an image into mask rows: pixels darker than mid-grey and mostly
+/// opaque are solid.
+fn mask_rows(path: &Path) -> Result<Vec<String>> {
+    let image = image::open(path)
+        .map_err(|err| Error::Parse(format!("{}: {}", path.display(), err)))?
+        .into_luma_alpha8();
+    return Ok(image
+        .rows()
+        .map(|row| {
+            row.map(|pixel| {
+                let [luma, alpha] = pixel.0;
+                if luma < 128 && alpha >= 128 {
+                    '#'
+                } else {
+                    '.'
+                }
+            })
+            .collect()
+        })
+        .collect());
+}
+
+/// Reflects the part of `vel` heading into the surface with `normal`,
+/// keeping `restitution` of it.
+fn bounce(vel: &mut Vec2d, normal: &Vec2d, restitution: f64) {
+    let normal_speed = dot(vel, normal);
+    if normal_speed < 0.0 {
+        let mut reflected = *normal;
+        sub(
+            vel,
+            mul_scalar(&mut reflected, (1.0 + restitution) * normal_speed),
+        );
+    }
+}
+
+fn closest_on_segment(pos: &Vec2d, start: &Vec2d, end: &Vec2d) -> Vec2d {
+    let mut along = *end;
+    sub(&mut along, start);
+    let mut offset = *pos;
+    sub(&mut offset, start);
+    let length_squared = dot(&along, &along);
+    let t = if length_squared > 0.0 {
+        (dot(&offset, &along) / length_squared).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    let mut closest = *start;
+    add(&mut closest, mul_scalar(&mut along, t));
+    return closest;
+}
+
+/// Distance between `pos` and `from` and the direction from `from` to `pos`,
+/// or `fallback` if they coincide.
+fn away_from(pos: &Vec2d, from: &Vec2d, fallback: Vec2d) -> (f64, Vec2d) {
+    let mut direction = *pos;
+    sub(&mut direction, from);
+    let distance = len(&direction);
+    if distance == 0.0 {
+        return (0.0, fallback);
+    }
+    mul_scalar(&mut direction, 1.0 / distance);
+    return (distance, direction);
+}
+
+fn normalize_or_x(v: &mut Vec2d) -> &mut Vec2d {
+    let length = len(v);
+    if length == 0.0 {
+        *v = [1.0, 0.0];
+        return v;
+    }
+    return mul_scalar(v, 1.0 / length);
+}
+
+/// Positive for counter-clockwise polygons in a y-up frame.
+pub fn signed_area(points: &[Vec2d]) -> f64 {
+    let mut area = 0.0;
+    for (i, a) in points.iter().enumerate() {
+        let b = &points[(i + 1) % points.len()];
+        area += a[0] * b[1] - b[0] * a[1];
+    }
+    return 0.5 * area;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> SceneSettings {
+        return toml::from_str(
+            "screen_size = [400, 300]\nparticle_count = 0\nparticle_types_count = 2\nseed = 0",
+        )
+        .unwrap();
+    }
+
+    fn obstacle(source: &str) -> Obstacle {
+        return ObstacleSet::from_toml(&format!("[[obstacles]]\n{source}"))
+            .unwrap()
+            .obstacles
+            .remove(0);
+    }
+
+    #[test]
+    fn circles_push_particles_out_and_bounce_them() {
+        let circle = obstacle("shape = \"circle\"\ncenter = [0.0, 0.0]\nradius = 10.0");
+        let (mut pos, mut vel) = ([8.0, 0.0], [-2.0, 1.0]);
+        circle.collide(&[12.0, 0.0], &mut pos, &mut vel, 0.5);
+        assert_eq!((pos, vel), ([10.0, 0.0], [1.0, 1.0]));
+        // Particles outside are left alone.
+        let (mut pos, mut vel) = ([11.0, 0.0], [-2.0, 1.0]);
+        circle.collide(&[13.0, 0.0], &mut pos, &mut vel, 0.5);
+        assert_eq!((pos, vel), ([11.0, 0.0], [-2.0, 1.0]));
+    }
+
+    #[test]
+    fn polygons_contain_points_in_either_winding() {
+        for points in [
+            "[[0.0, 0.0], [10.0, 0.0], [10.0, 10.0], [0.0, 10.0]]",
+            "[[0.0, 10.0], [10.0, 10.0], [10.0, 0.0], [0.0, 0.0]]",
+        ] {
+            let square = obstacle(&format!("shape = \"polygon\"\npoints = {points}"));
+            assert!(square.shape.contains(&[5.0, 2.0]));
+            assert!(!square.shape.contains(&[5.0, -2.0]));
+            let (mut pos, mut vel) = ([5.0, 2.0], [0.0, 1.0]);
+            square.collide(&[5.0, -2.0], &mut pos, &mut vel, 1.0);
+            assert_eq!((pos, vel), ([5.0, 0.0], [0.0, -1.0]));
+        }
+    }
+
+    #[test]
+    fn fast_particles_stop_on_the_side_they_came_from() {
+        let wall = obstacle(
+            "shape = \"segment\"\nstart = [0.0, -10.0]\nend = [0.0, 10.0]\nthickness = 2.0",
+        );
+        // Crossing the whole wall in one step.
+        let (mut pos, mut vel) = ([20.0, 0.0], [30.0, 0.0]);
+        wall.collide(&[-10.0, 0.0], &mut pos, &mut vel, 1.0);
+        assert!((pos[0] + 1.0).abs() < 1e-3, "{pos:?}");
+        assert_eq!(vel, [-30.0, 0.0]);
+        // Landing past the middle of the wall.
+        let (mut pos, mut vel) = ([0.5, 0.0], [3.0, 0.0]);
+        wall.collide(&[-2.5, 0.0], &mut pos, &mut vel, 1.0);
+        assert!((pos[0] + 1.0).abs() < 1e-3, "{pos:?}");
+        assert_eq!(vel, [-3.0, 0.0]);
+    }
+
+    #[test]
+    fn particles_leave_surfaces_they_rest_on() {
+        let circle = obstacle("shape = \"circle\"\ncenter = [0.0, 0.0]\nradius = 10.0");
+        let (mut pos, mut vel) = ([12.0, 0.0], [2.0, 0.0]);
+        circle.collide(&[10.0, 0.0], &mut pos, &mut vel, 0.5);
+        assert_eq!((pos, vel), ([12.0, 0.0], [2.0, 0.0]));
+    }
+
+    #[test]
+    fn masks_undo_the_move_into_solid_cells() {
+        let mask = obstacle("shape = \"mask\"\ncell_size = 10.0\nrows = [\"..\", \".#\"]");
+        let (mut pos, mut vel) = ([15.0, 15.0], [1.0, 1.0]);
+        mask.collide(&[15.0, 5.0], &mut pos, &mut vel, 1.0);
+        assert_eq!((pos, vel), ([15.0, 5.0], [1.0, -1.0]));
+    }
+
+    #[test]
+    fn permeable_types_pass_through() {
+        let obstacles = ObstacleSet::from_toml(
+            "[[obstacles]]\nshape = \"circle\"\ncenter = [0.0, 0.0]\nradius = 10.0\npermeable_to = [1]",
+        )
+        .unwrap();
+        let settings = settings();
+        let (mut pos, mut vel) = ([8.0, 0.0], [-2.0, 0.0]);
+        obstacles.collide(1, &[12.0, 0.0], &mut pos, &mut vel, &settings);
+        assert_eq!(pos, [8.0, 0.0]);
+        obstacles.collide(0, &[12.0, 0.0], &mut pos, &mut vel, &settings);
+        assert_eq!(pos, [10.0, 0.0]);
+    }
+
+    #[test]
+    fn paths_across_periodic_edges_are_unwrapped() {
+        let obstacles = ObstacleSet::from_toml(
+            "[[obstacles]]\nshape = \"segment\"\nstart = [200.0, 0.0]\nend = [200.0, 300.0]\nthickness = 2.0",
+        )
+        .unwrap();
+        // Wrapped from the right edge to the left one, nowhere near the wall.
+        let (mut pos, mut vel) = ([2.0, 150.0], [4.0, 0.0]);
+        obstacles.collide(0, &[398.0, 150.0], &mut pos, &mut vel, &settings());
+        assert_eq!((pos, vel), ([2.0, 150.0], [4.0, 0.0]));
+    }
+
+    #[test]
+    fn degenerate_obstacles_are_rejected() {
+        for source in [
+            "shape = \"circle\"\ncenter = [0.0, 0.0]\nradius = 0.0",
+            "shape = \"polygon\"\npoints = [[0.0, 0.0], [1.0, 1.0]]",
+        ] {
+            assert!(matches!(
+                ObstacleSet::from_toml(&format!("[[obstacles]]\n{source}")),
+                Err(Error::InvalidObstacles(_))
+            ));
+        }
+    }
+}
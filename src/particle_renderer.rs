@@ -0,0 +1,198 @@
+//! Draws the particles of a [`WgpuScene`](crate::WgpuScene) straight from its
+//! storage buffers, so the interactive loop never reads them back.
+
+use encase::{ShaderType, UniformBuffer};
+use wgpu::{BindGroupLayout, Buffer, Device, Queue, RenderPipeline, TextureFormat};
+
+/// Radius of a drawn particle, in world units. Matches the piston viewer.
+pub const POINT_SIZE: f32 = 3.0;
+
+#[derive(ShaderType, Debug)]
+struct RenderUniforms {
+    center_x: f32,
+    center_y: f32,
+    /// Normalized device units per world unit.
+    scale_x: f32,
+    scale_y: f32,
+    point_size: f32,
+}
+
+/// The target a draw covers and the part of the world it shows.
+#[derive(Debug, Clone, Copy)]
+pub struct Viewport {
+    /// Size of the target, in pixels.
+    pub size: [u32; 2],
+    /// World position shown at the middle of the target.
+    pub center: [f64; 2],
+    /// Target pixels per world unit.
+    pub zoom: f64,
+}
+
+/// Device buffers read by one draw.
+pub struct ParticleInstances<'a> {
+    pub positions: &'a Buffer,
+    pub type_indexes: &'a Buffer,
+    /// One `vec4f` per particle type.
+    pub type_colors: &'a Buffer,
+    pub particle_count: u32,
+}
+
+pub struct ParticleRenderer {
+    pipeline: RenderPipeline,
+    bind_group_layout: BindGroupLayout,
+    uniforms: Buffer,
+}
+
+fn vertex_storage_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+    return wgpu::BindGroupLayoutEntry {
+        binding,
+        count: None,
+        visibility: wgpu::ShaderStages::VERTEX,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only: true },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+    };
+}
+
+impl ParticleRenderer {
+    /// `format` is the format of the texture views passed to [`Self::draw`].
+    pub fn new(device: &Device, format: TextureFormat) -> Self {
+        let shader = device.create_shader_module(wgpu::include_wgsl!("render.wgsl"));
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Render bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    count: None,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                },
+                vertex_storage_entry(1),
+                vertex_storage_entry(2),
+                vertex_storage_entry(3),
+            ],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Render pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Render pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: Some("vs_main"),
+                compilation_options: Default::default(),
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: Some("fs_main"),
+                compilation_options: Default::default(),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+            cache: None,
+        });
+        let uniforms = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Render uniforms"),
+            size: RenderUniforms::min_size().get(),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        return Self {
+            pipeline,
+            bind_group_layout,
+            uniforms,
+        };
+    }
+
+    /// Clears `view` and draws `instances`, if any, into it as `viewport`
+    /// describes.
+    pub fn draw(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        viewport: &Viewport,
+        instances: Option<&ParticleInstances>,
+    ) {
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Render pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                depth_slice: None,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        let Some(instances) = instances else {
+            return;
+        };
+
+        let mut encase_uniform_buffer = UniformBuffer::new(Vec::new());
+        encase_uniform_buffer
+            .write(&RenderUniforms {
+                center_x: viewport.center[0] as f32,
+                center_y: viewport.center[1] as f32,
+                scale_x: (2.0 * viewport.zoom / viewport.size[0].max(1) as f64) as f32,
+                scale_y: (2.0 * viewport.zoom / viewport.size[1].max(1) as f64) as f32,
+                point_size: POINT_SIZE,
+            })
+            .expect("Uniform buffer should contain render uniforms");
+        queue.write_buffer(
+            &self.uniforms,
+            0,
+            encase_uniform_buffer.into_inner().as_slice(),
+        );
+        // The compute pass alternates between two position buffers, so the
+        // bind group can't be kept around.
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Render bind group"),
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: self.uniforms.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: instances.positions.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: instances.type_indexes.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: instances.type_colors.as_entire_binding(),
+                },
+            ],
+        });
+
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.draw(0..6, 0..instances.particle_count);
+    }
+}
@@ -2,11 +2,35 @@ use graphics::{types::Color, Colored};
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaCha8Rng;
 
-#[derive(Debug)]
-struct ParticleType {
-    color: Color,
-    mass: f64,
-    drag: f64,
+use crate::error::{Error, Result};
+
+/// Seeds that produced interesting worlds, with a note on what they look like.
+pub const NICE_SEEDS: &[(u64, &str)] = &[
+    (1, ""),
+    (
+        6,
+        "unstable, but many persitent small structures that do not merge together",
+    ),
+];
+
+#[derive(Debug, Clone)]
+pub struct ParticleType {
+    pub name: String,
+    pub color: Color,
+    pub mass: f64,
+    /// Fraction of its velocity a particle keeps after one time unit.
+    pub drag: f64,
+}
+
+impl ParticleType {
+    pub fn new(name: impl Into<String>, color: Color, mass: f64, drag: f64) -> ParticleType {
+        return ParticleType {
+            name: name.into(),
+            color,
+            mass,
+            drag,
+        };
+    }
 }
 
 pub struct ParticleTypeManager {
@@ -14,21 +38,25 @@ pub struct ParticleTypeManager {
     forces: Vec<Vec<f64>>,
     min_distances: Vec<Vec<f64>>,
     radii: Vec<Vec<f64>>,
+    pub(crate) seed: Option<u64>,
 }
 
 impl ParticleTypeManager {
-    pub fn new(particle_types_count: usize) -> ParticleTypeManager {
-        // Nice seeds:
-        /*
-         * 1
-         * 6 - unstable, but many persitent small structures that do not merge together
-         */
-        let mut random_source = ChaCha8Rng::seed_from_u64(6);
+    /// Generates random rules for `particle_types_count` types from `seed`.
+    /// The same seed always produces the same rules, see [`NICE_SEEDS`].
+    pub fn new(particle_types_count: usize, seed: u64) -> Result<ParticleTypeManager> {
+        if particle_types_count == 0 {
+            return Err(Error::InvalidRules(
+                "at least one particle type is required".into(),
+            ));
+        }
+        let mut random_source = ChaCha8Rng::seed_from_u64(seed);
         let particle_types: Vec<ParticleType> = (0..particle_types_count)
             .map(|i| {
                 let color = Color::from([1.0, 0.0, 0.0, 1.0])
                     .hue_deg(i as f32 / particle_types_count as f32 * 360.0);
                 return ParticleType {
+                    name: format!("type-{i}"),
                     color,
                     mass: random_source.random_range(0.3..2.0),
                     drag: random_source.random_range(0.9..1.0),
@@ -56,20 +84,108 @@ impl ParticleTypeManager {
                     .collect()
             })
             .collect();
-        let manager = ParticleTypeManager {
+        return Ok(ParticleTypeManager {
             particle_types,
             forces,
             min_distances,
             radii,
-        };
-        manager.show();
-        return manager;
+            seed: Some(seed),
+        });
+    }
+
+    /// Builds rules from explicit values. Every matrix has to be
+    /// `particle_types.len()` x `particle_types.len()`, indexed `[type_a][type_b]`.
+    /// Masses have to be positive and finite, drag in `(0, 1]`, forces finite
+    /// and distances finite and non-negative.
+    pub fn from_parts(
+        particle_types: Vec<ParticleType>,
+        forces: Vec<Vec<f64>>,
+        min_distances: Vec<Vec<f64>>,
+        radii: Vec<Vec<f64>>,
+    ) -> Result<ParticleTypeManager> {
+        let count = particle_types.len();
+        if count == 0 {
+            return Err(Error::InvalidRules(
+                "at least one particle type is required".into(),
+            ));
+        }
+        for (name, matrix) in [
+            ("forces", &forces),
+            ("min_distances", &min_distances),
+            ("radii", &radii),
+        ] {
+            if matrix.len() != count || matrix.iter().any(|row| row.len() != count) {
+                return Err(Error::InvalidRules(format!(
+                    "`{name}` has to be a {count}x{count} matrix"
+                )));
+            }
+        }
+        if let Some(t) = particle_types
+            .iter()
+            .find(|t| !(t.mass.is_finite() && t.mass > 0.0))
+        {
+            return Err(Error::InvalidRules(format!(
+                "mass has to be positive and finite, got {}",
+                t.mass
+            )));
+        }
+        if let Some(t) = particle_types
+            .iter()
+            .find(|t| !(t.drag > 0.0 && t.drag <= 1.0))
+        {
+            return Err(Error::InvalidRules(format!(
+                "drag has to be above 0 and at most 1, got {}",
+                t.drag
+            )));
+        }
+        if let Some(force) = forces.iter().flatten().find(|f| !f.is_finite()) {
+            return Err(Error::InvalidRules(format!(
+                "`forces` has to be finite, got {force}"
+            )));
+        }
+        for (name, matrix) in [("min_distances", &min_distances), ("radii", &radii)] {
+            if let Some(distance) = matrix
+                .iter()
+                .flatten()
+                .find(|d| !(d.is_finite() && **d >= 0.0))
+            {
+                return Err(Error::InvalidRules(format!(
+                    "`{name}` has to be finite and non-negative, got {distance}"
+                )));
+            }
+        }
+        return Ok(ParticleTypeManager {
+            particle_types,
+            forces,
+            min_distances,
+            radii,
+            seed: None,
+        });
+    }
+
+    /// Seed the rules were generated from, `None` for explicitly built rules.
+    pub fn seed(&self) -> Option<u64> {
+        return self.seed;
     }
 
-    fn show(&self) {
-        println!("=== Current settings ===");
-        println!("Particles: {:?}", self.particle_types);
-        println!("========================");
+    pub fn particle_types_count(&self) -> usize {
+        return self.particle_types.len();
+    }
+
+    pub fn particle_types(&self) -> &[ParticleType] {
+        return &self.particle_types;
+    }
+
+    pub fn forces(&self) -> &[Vec<f64>] {
+        return &self.forces;
+    }
+
+    pub fn min_distances(&self) -> &[Vec<f64>] {
+        return &self.min_distances;
+    }
+
+    pub fn radii(&self) -> &[Vec<f64>] {
+        return &self.radii;
     }
 
     pub fn get_forces_flattened(&self) -> Vec<f32> {
@@ -96,6 +212,21 @@ impl ParticleTypeManager {
         self.particle_types.iter().map(|t| t.drag as f32).collect()
     }
 
+    pub fn get_colors(&self) -> Vec<Color> {
+        self.particle_types.iter().map(|t| t.color).collect()
+    }
+
+    /// Distance beyond which no type pair interacts.
+    pub fn get_max_interaction_distance(&self) -> f64 {
+        return self
+            .radii
+            .iter()
+            .chain(&self.min_distances)
+            .flatten()
+            .copied()
+            .fold(0.0, f64::max);
+    }
+
     #[inline(always)]
     pub fn get_particle_drag(&self, type_index: usize) -> f64 {
         return self.particle_types[type_index].drag;
@@ -106,6 +237,10 @@ impl ParticleTypeManager {
         return self.particle_types[type_index].mass;
     }
 
+    pub fn get_particle_name(&self, type_index: usize) -> &str {
+        return &self.particle_types[type_index].name;
+    }
+
     #[inline(always)]
     pub fn get_particle_color(&self, type_index: usize) -> Color {
         return self.particle_types[type_index].color;
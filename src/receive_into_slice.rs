@@ -3,17 +3,19 @@ use wgpu::{Buffer, BufferView, Device};
 
 pub async fn receive_into_slice<T: AnyBitPattern>(
     device: &Device,
-    buffer: Buffer,
-    destination: &mut Vec<T>,
+    buffer: &Buffer,
+    destination: &mut [T],
 ) {
     {
         let (tx, rx) = flume::bounded(1);
-        buffer.map_async(wgpu::MapMode::Read, .., move |result| {
+        // The buffer may have room for more than `destination`.
+        let size = size_of_val(destination) as u64;
+        buffer.map_async(wgpu::MapMode::Read, ..size, move |result| {
             tx.send(result).unwrap()
         });
         device.poll(wgpu::PollType::Wait).unwrap();
         rx.recv_async().await.unwrap().unwrap();
-        let output_data: BufferView = buffer.get_mapped_range(..);
+        let output_data: BufferView = buffer.get_mapped_range(..size);
         let out: &[T] = bytemuck::cast_slice(&output_data);
         destination.copy_from_slice(out);
     }
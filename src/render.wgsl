@@ -0,0 +1,61 @@
+// Instanced point sprites, one quad per particle, read straight from the
+// compute pass output.
+
+// Has to match `RenderUniforms` in particle_renderer.rs.
+struct RenderUniforms {
+    // World position at the middle of the target.
+    center_x: f32,
+    center_y: f32,
+    // Normalized device units per world unit.
+    scale_x: f32,
+    scale_y: f32,
+    // Radius of a particle, in world units.
+    point_size: f32,
+}
+@group(0) @binding(0) var<uniform> uniforms: RenderUniforms;
+@group(0) @binding(1) var<storage, read> positions: array<vec2f>;
+@group(0) @binding(2) var<storage, read> type_indexes: array<u32>;
+@group(0) @binding(3) var<storage, read> type_colors: array<vec4f>;
+
+struct VertexOutput {
+    @builtin(position) position: vec4f,
+    @location(0) color: vec4f,
+    // Position inside the quad, from -1 to 1 on both axes.
+    @location(1) corner: vec2f,
+}
+
+@vertex
+fn vs_main(
+    @builtin(vertex_index) vertex_index: u32,
+    @builtin(instance_index) instance_index: u32,
+) -> VertexOutput {
+    var corners = array<vec2f, 6>(
+        vec2f(-1.0, -1.0),
+        vec2f(1.0, -1.0),
+        vec2f(-1.0, 1.0),
+        vec2f(-1.0, 1.0),
+        vec2f(1.0, -1.0),
+        vec2f(1.0, 1.0),
+    );
+    let corner = corners[vertex_index];
+    let world = positions[instance_index] + corner * uniforms.point_size;
+    // World space has y pointing down, like the piston viewer.
+    let ndc = vec2f(
+        (world.x - uniforms.center_x) * uniforms.scale_x,
+        (uniforms.center_y - world.y) * uniforms.scale_y,
+    );
+
+    var out: VertexOutput;
+    out.position = vec4f(ndc, 0.0, 1.0);
+    out.color = type_colors[type_indexes[instance_index]];
+    out.corner = corner;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4f {
+    if dot(in.corner, in.corner) > 1.0 {
+        discard;
+    }
+    return in.color;
+}
@@ -0,0 +1,378 @@
+//! Panel for editing the rules of the running scene, with undo and redo.
+
+use graphics::{math::Vec2d, rectangle, text::Text, Context, Transformed};
+use opengl_graphics::{GlGraphics, GlyphCache};
+use particle_simulation::{rule_set::RuleSet, ParticleTypeManager, SceneLike};
+
+use crate::hud::{load_font, FONT_SIZE, PANEL_COLOR, TEXT_COLOR};
+
+const CELL_WIDTH: f64 = 48.0;
+const CELL_HEIGHT: f64 = 18.0;
+const LABEL_WIDTH: f64 = 44.0;
+const TITLE_HEIGHT: f64 = 24.0;
+const GAP: f64 = 8.0;
+const MARGIN: f64 = 10.0;
+const SELECTED_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 0.9];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Matrix {
+    Forces,
+    MinDistances,
+    Radii,
+}
+
+impl Matrix {
+    fn title(&self) -> &'static str {
+        return match self {
+            Matrix::Forces => "forces, row feels column",
+            Matrix::MinDistances => "min distances",
+            Matrix::Radii => "radii",
+        };
+    }
+
+    fn next(&self) -> Matrix {
+        return match self {
+            Matrix::Forces => Matrix::MinDistances,
+            Matrix::MinDistances => Matrix::Radii,
+            Matrix::Radii => Matrix::Forces,
+        };
+    }
+}
+
+/// An editable value of the rules.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Cell {
+    Matrix(Matrix, usize, usize),
+    Mass(usize),
+    Drag(usize),
+}
+
+/// A row of cells in the panel.
+#[derive(Clone, Copy)]
+enum Row {
+    Matrix(usize),
+    Mass,
+    Drag,
+}
+
+impl Row {
+    fn cell(&self, matrix: Matrix, column: usize) -> Cell {
+        return match *self {
+            Row::Matrix(row) => Cell::Matrix(matrix, row, column),
+            Row::Mass => Cell::Mass(column),
+            Row::Drag => Cell::Drag(column),
+        };
+    }
+}
+
+impl Cell {
+    /// Change of one adjustment step and the range the value is kept in.
+    fn step_and_range(&self) -> (f64, f64, f64) {
+        return match self {
+            Cell::Matrix(Matrix::Forces, ..) => (0.05, -10.0, 10.0),
+            Cell::Matrix(Matrix::MinDistances, ..) => (1.0, 0.0, 1000.0),
+            Cell::Matrix(Matrix::Radii, ..) => (5.0, 0.0, 1000.0),
+            Cell::Mass(_) => (0.05, 0.05, 100.0),
+            Cell::Drag(_) => (0.005, 0.01, 1.0),
+        };
+    }
+
+    /// `None` if the rules have fewer types than the cell needs.
+    fn value<'a>(&self, rules: &'a mut RuleSet) -> Option<&'a mut f64> {
+        return match *self {
+            Cell::Matrix(Matrix::Forces, a, b) => rules.forces.get_mut(a)?.get_mut(b),
+            Cell::Matrix(Matrix::MinDistances, a, b) => rules.min_distances.get_mut(a)?.get_mut(b),
+            Cell::Matrix(Matrix::Radii, a, b) => rules.radii.get_mut(a)?.get_mut(b),
+            Cell::Mass(t) => Some(&mut rules.types.get_mut(t)?.mass),
+            Cell::Drag(t) => Some(&mut rules.types.get_mut(t)?.drag),
+        };
+    }
+
+    fn format(&self, value: f64) -> String {
+        return match self {
+            Cell::Matrix(Matrix::Forces, ..) => format!("{value:+.2}"),
+            Cell::Matrix(..) => format!("{value:.0}"),
+            Cell::Mass(_) => format!("{value:.2}"),
+            Cell::Drag(_) => format!("{value:.3}"),
+        };
+    }
+}
+
+pub struct RulePanel {
+    pub visible: bool,
+    matrix: Matrix,
+    selected: Option<Cell>,
+    /// Type count `selected` was picked for. Undo, redo and reloads can
+    /// change it, which leaves the selection pointing elsewhere.
+    types_count: usize,
+    /// Cell the newest undo entry was taken for, so that repeated
+    /// adjustments of one cell undo together.
+    last_edited: Option<Cell>,
+    undo: Vec<RuleSet>,
+    redo: Vec<RuleSet>,
+    glyphs: GlyphCache<'static>,
+}
+
+impl RulePanel {
+    pub fn new() -> RulePanel {
+        return RulePanel {
+            visible: false,
+            matrix: Matrix::Forces,
+            selected: None,
+            types_count: 0,
+            last_edited: None,
+            undo: vec![],
+            redo: vec![],
+            glyphs: load_font(),
+        };
+    }
+
+    pub fn next_matrix(&mut self) {
+        self.matrix = self.matrix.next();
+        self.selected = None;
+    }
+
+    /// Drops the selection if the rules gained or lost types since it was
+    /// made.
+    fn track_types_count(&mut self, types_count: usize) {
+        if types_count != self.types_count {
+            self.types_count = types_count;
+            self.selected = None;
+            self.last_edited = None;
+        }
+    }
+
+    fn size(types_count: usize) -> Vec2d {
+        return [
+            LABEL_WIDTH + types_count as f64 * CELL_WIDTH + 2.0 * GAP,
+            TITLE_HEIGHT + (types_count + 3) as f64 * CELL_HEIGHT + 3.0 * GAP,
+        ];
+    }
+
+    /// Top left corner, with the panel along the right edge of the window.
+    fn origin(window_size: Vec2d, types_count: usize) -> Vec2d {
+        return [
+            window_size[0] - RulePanel::size(types_count)[0] - MARGIN,
+            MARGIN,
+        ];
+    }
+
+    /// Whether `window_pos` is over the visible panel.
+    pub fn contains(&self, window_pos: Vec2d, window_size: Vec2d, types_count: usize) -> bool {
+        let origin = RulePanel::origin(window_size, types_count);
+        let size = RulePanel::size(types_count);
+        return self.visible
+            && (0..2).all(|axis| {
+                window_pos[axis] >= origin[axis] && window_pos[axis] < origin[axis] + size[axis]
+            });
+    }
+
+    /// Top left corner of each row of cells.
+    fn rows(origin: Vec2d, types_count: usize) -> Vec<(Vec2d, Row)> {
+        let left = origin[0] + GAP + LABEL_WIDTH;
+        let top = origin[1] + TITLE_HEIGHT + CELL_HEIGHT;
+        let mut rows = (0..types_count)
+            .map(|row| ([left, top + row as f64 * CELL_HEIGHT], Row::Matrix(row)))
+            .collect::<Vec<_>>();
+        let properties_top = top + types_count as f64 * CELL_HEIGHT + GAP;
+        rows.push(([left, properties_top], Row::Mass));
+        rows.push(([left, properties_top + CELL_HEIGHT], Row::Drag));
+        return rows;
+    }
+
+    fn cell_at(&self, window_pos: Vec2d, window_size: Vec2d, types_count: usize) -> Option<Cell> {
+        let origin = RulePanel::origin(window_size, types_count);
+        return RulePanel::rows(origin, types_count)
+            .into_iter()
+            .find_map(|(corner, row)| {
+                let column = ((window_pos[0] - corner[0]) / CELL_WIDTH).floor();
+                let inside_row =
+                    window_pos[1] >= corner[1] && window_pos[1] < corner[1] + CELL_HEIGHT;
+                return (inside_row && column >= 0.0 && column < types_count as f64)
+                    .then(|| row.cell(self.matrix, column as usize));
+            });
+    }
+
+    /// Selects the cell under `window_pos`, if any, for [`Self::adjust`].
+    pub fn select_at(&mut self, window_pos: Vec2d, window_size: Vec2d, types_count: usize) {
+        self.track_types_count(types_count);
+        self.selected = self.cell_at(window_pos, window_size, types_count);
+    }
+
+    /// Changes the selected value by `steps` of its step size. Does nothing
+    /// while the panel is hidden, since the selection is not shown then.
+    pub fn adjust(&mut self, steps: f64, scene: &mut impl SceneLike) {
+        if !self.visible {
+            return;
+        }
+        let mut rules = scene.get_particle_types().to_rule_set();
+        self.track_types_count(rules.types.len());
+        let Some(cell) = self.selected else {
+            return;
+        };
+        let Some(value) = cell.value(&mut rules) else {
+            return;
+        };
+        if self.last_edited != Some(cell) {
+            self.record(scene);
+            self.last_edited = Some(cell);
+        }
+        let (step, min, max) = cell.step_and_range();
+        *value = (*value + steps * step).clamp(min, max);
+        // Edited rules no longer follow from the seed.
+        rules.seed = None;
+        apply(&rules, scene);
+    }
+
+    /// Saves the current rules for undo, before they are changed elsewhere.
+    pub fn record(&mut self, scene: &impl SceneLike) {
+        self.undo.push(scene.get_particle_types().to_rule_set());
+        self.redo.clear();
+        self.last_edited = None;
+    }
+
+    pub fn undo(&mut self, scene: &mut impl SceneLike) {
+        if let Some(rules) = self.undo.pop() {
+            self.redo.push(scene.get_particle_types().to_rule_set());
+            self.last_edited = None;
+            apply(&rules, scene);
+        }
+    }
+
+    pub fn redo(&mut self, scene: &mut impl SceneLike) {
+        if let Some(rules) = self.redo.pop() {
+            self.undo.push(scene.get_particle_types().to_rule_set());
+            self.last_edited = None;
+            apply(&rules, scene);
+        }
+    }
+
+    pub fn draw(
+        &mut self,
+        scene: &impl SceneLike,
+        window_size: Vec2d,
+        c: Context,
+        gl: &mut GlGraphics,
+    ) {
+        if !self.visible {
+            return;
+        }
+        let mut rules = scene.get_particle_types().to_rule_set();
+        let types_count = rules.types.len();
+        self.track_types_count(types_count);
+        let origin = RulePanel::origin(window_size, types_count);
+        let size = RulePanel::size(types_count);
+        rectangle(
+            PANEL_COLOR,
+            [origin[0], origin[1], size[0], size[1]],
+            c.transform,
+            gl,
+        );
+        self.text(
+            &format!("{} (M: next)", self.matrix.title()),
+            TEXT_COLOR,
+            [origin[0] + GAP, origin[1] + 16.0],
+            c,
+            gl,
+        );
+
+        let rows = RulePanel::rows(origin, types_count);
+        // Column headers and the row labels of the matrix show type colors.
+        let swatch = CELL_HEIGHT - 6.0;
+        for t in 0..types_count {
+            let color = rules.types[t].color;
+            let [left, top] = rows[0].0;
+            let header = [left + t as f64 * CELL_WIDTH + 3.0, top - CELL_HEIGHT + 3.0];
+            rectangle(
+                color,
+                [header[0], header[1], swatch, swatch],
+                c.transform,
+                gl,
+            );
+            let label = [origin[0] + GAP, rows[t].0[1] + 3.0];
+            rectangle(color, [label[0], label[1], swatch, swatch], c.transform, gl);
+        }
+        for (name, row) in [("mass", types_count), ("drag", types_count + 1)] {
+            let top = rows[row].0[1];
+            self.text(name, TEXT_COLOR, [origin[0] + GAP, top + 13.0], c, gl);
+        }
+
+        for (corner, row) in &rows {
+            for column in 0..types_count {
+                let cell = row.cell(self.matrix, column);
+                let Some(&mut value) = cell.value(&mut rules) else {
+                    continue;
+                };
+                let x = corner[0] + column as f64 * CELL_WIDTH;
+                let bounds = [
+                    x + 1.0,
+                    corner[1] + 1.0,
+                    CELL_WIDTH - 2.0,
+                    CELL_HEIGHT - 2.0,
+                ];
+                rectangle(cell_color(cell, value), bounds, c.transform, gl);
+                if self.selected == Some(cell) {
+                    graphics::Rectangle::new_border(SELECTED_COLOR, 1.0).draw(
+                        bounds,
+                        &c.draw_state,
+                        c.transform,
+                        gl,
+                    );
+                }
+                self.text(
+                    &cell.format(value),
+                    TEXT_COLOR,
+                    [x + 4.0, corner[1] + 13.0],
+                    c,
+                    gl,
+                );
+            }
+        }
+        let help = format!(
+            "wheel or -/+ edit, ctrl+z/y undo/redo ({}/{})",
+            self.undo.len(),
+            self.redo.len()
+        );
+        self.text(
+            &help,
+            [0.6, 0.6, 0.6, 1.0],
+            [origin[0] + GAP, origin[1] + size[1] - GAP + 2.0],
+            c,
+            gl,
+        );
+    }
+
+    fn text(&mut self, text: &str, color: [f32; 4], pos: Vec2d, c: Context, gl: &mut GlGraphics) {
+        // Rendering only fails for glyphs the font lacks.
+        let _ = Text::new_color(color, FONT_SIZE - 2).draw(
+            text,
+            &mut self.glyphs,
+            &c.draw_state,
+            c.transform.trans(pos[0], pos[1]),
+            gl,
+        );
+    }
+}
+
+/// Forces are green when attracting and red when repelling, other values
+/// are grey.
+fn cell_color(cell: Cell, value: f64) -> [f32; 4] {
+    return match cell {
+        Cell::Matrix(Matrix::Forces, ..) => {
+            let strength = value.abs().min(1.0) as f32 * 0.6;
+            if value >= 0.0 {
+                [0.1, 0.1 + strength, 0.1, 0.9]
+            } else {
+                [0.1 + strength, 0.1, 0.1, 0.9]
+            }
+        }
+        _ => [0.2, 0.2, 0.2, 0.9],
+    };
+}
+
+fn apply(rules: &RuleSet, scene: &mut impl SceneLike) {
+    match ParticleTypeManager::from_rule_set(rules) {
+        Ok(particle_types) => scene.set_particle_types(particle_types),
+        Err(err) => eprintln!("Could not apply the edited rules: {}", err),
+    }
+}
@@ -0,0 +1,194 @@
+//! Human-editable TOML representation of a [`ParticleTypeManager`].
+//!
+//! ```toml
+//! seed = 6
+//! forces = [[0.5, -0.2], [0.1, 0.9]]
+//! min_distances = [[20.0, 20.0], [20.0, 20.0]]
+//! radii = [[150.0, 200.0], [200.0, 150.0]]
+//!
+//! [[types]]
+//! name = "red"
+//! color = [1.0, 0.0, 0.0, 1.0]
+//! mass = 1.0
+//! drag = 0.95
+//!
+//! [[types]]
+//! name = "blue"
+//! color = [0.0, 0.0, 1.0, 1.0]
+//! mass = 0.5
+//! drag = 0.9
+//! ```
+//!
+//! Matrices are indexed `[type_a][type_b]` and hold the values applied to a
+//! particle of `type_a` by a particle of `type_b`.
+
+use std::{fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Error, Result},
+    particle_type::{ParticleType, ParticleTypeManager},
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TypeRule {
+    pub name: String,
+    pub color: [f32; 4],
+    pub mass: f64,
+    pub drag: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RuleSet {
+    /// Seed the rules were generated from. Informational only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    pub forces: Vec<Vec<f64>>,
+    pub min_distances: Vec<Vec<f64>>,
+    pub radii: Vec<Vec<f64>>,
+    pub types: Vec<TypeRule>,
+}
+
+impl RuleSet {
+    pub fn from_toml(source: &str) -> Result<RuleSet> {
+        return toml::from_str(source).map_err(|err| Error::Parse(err.to_string()));
+    }
+
+    pub fn to_toml(&self) -> String {
+        return toml::to_string(self).expect("Rule set should always be serializable to TOML");
+    }
+
+    pub fn load(path: &Path) -> Result<RuleSet> {
+        return fs::read_to_string(path)
+            .map_err(Error::from)
+            .and_then(|source| RuleSet::from_toml(&source))
+            .map_err(|err| err.in_file(path));
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_toml())?;
+        return Ok(());
+    }
+}
+
+impl ParticleTypeManager {
+    pub fn from_rule_set(rules: &RuleSet) -> Result<ParticleTypeManager> {
+        let particle_types = rules
+            .types
+            .iter()
+            .map(|t| ParticleType::new(t.name.clone(), t.color, t.mass, t.drag))
+            .collect();
+        let mut manager = ParticleTypeManager::from_parts(
+            particle_types,
+            rules.forces.clone(),
+            rules.min_distances.clone(),
+            rules.radii.clone(),
+        )?;
+        manager.seed = rules.seed;
+        return Ok(manager);
+    }
+
+    pub fn to_rule_set(&self) -> RuleSet {
+        return RuleSet {
+            seed: self.seed(),
+            forces: self.forces().to_vec(),
+            min_distances: self.min_distances().to_vec(),
+            radii: self.radii().to_vec(),
+            types: self
+                .particle_types()
+                .iter()
+                .map(|t| TypeRule {
+                    name: t.name.clone(),
+                    color: t.color,
+                    mass: t.mass,
+                    drag: t.drag,
+                })
+                .collect(),
+        };
+    }
+
+    pub fn load(path: &Path) -> Result<ParticleTypeManager> {
+        return ParticleTypeManager::from_rule_set(&RuleSet::load(path)?);
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        return self.to_rule_set().save(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_round_trip_through_toml() {
+        for (types_count, seed) in [(1, 0), (4, 1), (7, 12345)] {
+            let particle_types = ParticleTypeManager::new(types_count, seed).unwrap();
+            let source = particle_types.to_rule_set().to_toml();
+            let rules = RuleSet::from_toml(&source).expect("Written rules should parse");
+            let read =
+                ParticleTypeManager::from_rule_set(&rules).expect("Written rules should be valid");
+            assert_eq!(read.seed(), particle_types.seed());
+            assert_eq!(read.forces(), particle_types.forces());
+            assert_eq!(read.min_distances(), particle_types.min_distances());
+            assert_eq!(read.radii(), particle_types.radii());
+            let properties = |types: &ParticleTypeManager| {
+                types
+                    .particle_types()
+                    .iter()
+                    .map(|t| (t.name.clone(), t.color, t.mass, t.drag))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(properties(&read), properties(&particle_types));
+            assert_eq!(read.to_rule_set().to_toml(), source);
+        }
+    }
+
+    #[test]
+    fn rules_with_values_out_of_range_are_rejected() {
+        let valid = ParticleTypeManager::new(2, 1).unwrap().to_rule_set();
+        assert!(ParticleTypeManager::from_rule_set(&valid).is_ok());
+        let edits: [fn(&mut RuleSet); 9] = [
+            |rules| rules.types[0].drag = 0.0,
+            |rules| rules.types[1].drag = 1.5,
+            |rules| rules.types[0].mass = -1.0,
+            |rules| rules.types[1].mass = f64::INFINITY,
+            |rules| rules.forces[0][1] = f64::NAN,
+            |rules| rules.forces[1][0] = f64::NEG_INFINITY,
+            |rules| rules.min_distances[0][1] = -2.0,
+            |rules| rules.radii[1][0] = f64::INFINITY,
+            |rules| rules.radii[1][1] = f64::NAN,
+        ];
+        for edit in edits {
+            let mut rules = valid.clone();
+            edit(&mut rules);
+            assert!(matches!(
+                ParticleTypeManager::from_rule_set(&rules),
+                Err(Error::InvalidRules(_))
+            ));
+        }
+        assert!(matches!(
+            ParticleTypeManager::new(0, 1),
+            Err(Error::InvalidRules(_))
+        ));
+    }
+
+    #[test]
+    fn load_errors_keep_their_kind_and_name_the_file() {
+        let directory = std::env::temp_dir();
+        let missing = directory.join(format!("missing-rules-{}.toml", std::process::id()));
+        assert!(matches!(RuleSet::load(&missing), Err(Error::Io(_))));
+
+        let broken = directory.join(format!("broken-rules-{}.toml", std::process::id()));
+        fs::write(&broken, "forces = [[").unwrap();
+        let err = RuleSet::load(&broken);
+        fs::remove_file(&broken).unwrap();
+        let Err(err @ Error::Parse(_)) = err else {
+            panic!("Broken rules should be a parse error");
+        };
+        let message = err.to_string();
+        assert!(message.starts_with(&format!("parse error: {}: ", broken.display())));
+        assert!(!message.contains(": parse error:"));
+    }
+}
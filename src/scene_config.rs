@@ -0,0 +1,134 @@
+//! Scene settings that can change while a scene runs, stored as TOML.
+//!
+//! ```toml
+//! dt = 0.5
+//! substeps = 2
+//! integrator = "velocity-verlet"
+//! boundary = "reflective"
+//! restitution = 0.8
+//! ```
+//!
+//! Settings left out keep their current value. The world size, particle
+//! count, types and force law are fixed when a scene is created.
+
+use std::{fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    boundary::Boundary,
+    error::{Error, Result},
+    integrator::Integrator,
+    SceneSettings,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SceneConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dt: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub substeps: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub integrator: Option<Integrator>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boundary: Option<Boundary>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restitution: Option<f64>,
+}
+
+impl SceneConfig {
+    pub fn from_toml(source: &str) -> Result<SceneConfig> {
+        let config: SceneConfig =
+            toml::from_str(source).map_err(|err| Error::Parse(err.to_string()))?;
+        config.validate()?;
+        return Ok(config);
+    }
+
+    pub fn to_toml(&self) -> String {
+        return toml::to_string(self).expect("Scene config should always be serializable to TOML");
+    }
+
+    pub fn load(path: &Path) -> Result<SceneConfig> {
+        return fs::read_to_string(path)
+            .map_err(Error::from)
+            .and_then(|source| SceneConfig::from_toml(&source))
+            .map_err(|err| err.in_file(path));
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if let Some(dt) = self.dt {
+            if !(dt.is_finite() && dt > 0.0) {
+                return Err(Error::InvalidConfig(format!(
+                    "dt must be positive, got {dt}"
+                )));
+            }
+        }
+        if self.substeps == Some(0) {
+            return Err(Error::InvalidConfig(
+                "substeps must be at least 1".to_string(),
+            ));
+        }
+        if let Some(restitution) = self.restitution {
+            if !(0.0..=1.0).contains(&restitution) {
+                return Err(Error::InvalidConfig(format!(
+                    "restitution must be from 0 to 1, got {restitution}"
+                )));
+            }
+        }
+        return Ok(());
+    }
+
+    /// Sets everything `settings` has that a config can change.
+    pub fn from_settings(settings: &SceneSettings) -> SceneConfig {
+        return SceneConfig {
+            dt: Some(settings.dt),
+            substeps: Some(settings.substeps),
+            integrator: Some(settings.integrator),
+            boundary: Some(settings.boundary),
+            restitution: Some(settings.restitution),
+        };
+    }
+
+    /// Overwrites the settings this config sets.
+    pub fn apply(&self, settings: &mut SceneSettings) {
+        settings.dt = self.dt.unwrap_or(settings.dt);
+        settings.substeps = self.substeps.unwrap_or(settings.substeps);
+        settings.integrator = self.integrator.unwrap_or(settings.integrator);
+        settings.boundary = self.boundary.unwrap_or(settings.boundary);
+        settings.restitution = self.restitution.unwrap_or(settings.restitution);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_overwrites_only_the_settings_it_sets() {
+        let config = SceneConfig::from_toml("dt = 0.25\nboundary = \"reflective\"").unwrap();
+        let mut settings = toml::from_str::<SceneSettings>(
+            "screen_size = [400, 300]\nparticle_count = 10\nparticle_types_count = 2\nseed = 1\nsubsteps = 4",
+        )
+        .unwrap();
+        config.apply(&mut settings);
+        assert_eq!(settings.dt, 0.25);
+        assert_eq!(settings.boundary, Boundary::Reflective);
+        assert_eq!(settings.substeps, 4);
+        assert_eq!(SceneConfig::from_toml(&config.to_toml()).unwrap(), config);
+    }
+
+    #[test]
+    fn config_with_values_out_of_range_is_rejected() {
+        for source in ["dt = -1.0", "dt = nan", "substeps = 0", "restitution = 1.5"] {
+            assert!(matches!(
+                SceneConfig::from_toml(source),
+                Err(Error::InvalidConfig(_))
+            ));
+        }
+        assert!(matches!(
+            SceneConfig::from_toml("particle_count = 5"),
+            Err(Error::Parse(_))
+        ));
+    }
+}
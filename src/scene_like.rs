@@ -1,12 +1,135 @@
 use std::sync::Arc;
 
-use crate::{Particle, SceneSettings};
+use graphics::math::Vec2d;
 
+use crate::{
+    error::{Error, Result},
+    field::FieldSet,
+    obstacle::ObstacleSet,
+    particle_type::ParticleTypeManager,
+    scene_config::SceneConfig,
+    snapshot::Snapshot,
+    vector::{len, sub},
+    Particle, SceneSettings,
+};
+
+#[allow(async_fn_in_trait)]
 pub trait SceneLike {
     async fn new(settings: SceneSettings) -> Self;
     fn init(&mut self);
     async fn update(&mut self);
     fn get_particles(&self) -> Arc<Vec<Particle>>;
-    fn new_world(&mut self);
+    /// Replaces the particle type rules with ones generated from the next seed
+    /// and returns that seed. Particle positions are kept.
+    fn new_world(&mut self) -> u64;
     fn get_particle_color(&self, type_index: usize) -> [f32; 4];
+    fn get_particle_types(&self) -> &ParticleTypeManager;
+    /// Swaps in new rules without touching positions or velocities. Particles
+    /// whose type does not exist in the new rules are wrapped into range.
+    fn set_particle_types(&mut self, particle_types: ParticleTypeManager);
+    fn get_settings(&self) -> SceneSettings;
+    /// Replaces every particle, including their count. Type indexes have to
+    /// be valid for the current rules.
+    fn set_particles(&mut self, particles: &[Particle]);
+    fn get_obstacles(&self) -> &ObstacleSet;
+    /// Replaces the static obstacles, starting with the next step.
+    fn set_obstacles(&mut self, obstacles: ObstacleSet);
+    fn get_fields(&self) -> &FieldSet;
+    /// Replaces the external force fields, starting with the next step.
+    fn set_fields(&mut self, fields: FieldSet);
+    /// Changes the settings `config` sets, starting with the next step.
+    /// Particles are kept.
+    fn configure(&mut self, config: &SceneConfig);
+
+    /// Adds `particles` after the existing ones, which keep their state.
+    /// Type indexes have to be valid for the current rules.
+    fn spawn_particles(&mut self, particles: &[Particle]);
+    /// Removes the particles at `indexes` into [`Self::get_particles`].
+    /// The other particles keep their state and order. Indexes out of range
+    /// are ignored.
+    fn remove_particles(&mut self, indexes: &[usize]);
+    /// Sets the position and velocity of the particles at the given indexes
+    /// into [`Self::get_particles`], leaving the others alone. Indexes out of
+    /// range are ignored.
+    fn move_particles(&mut self, moves: &[(usize, Vec2d, Vec2d)]);
+
+    /// Removes every particle closer than `radius` to `center` and returns
+    /// how many were removed. The other particles keep their order.
+    fn erase_particles(&mut self, center: Vec2d, radius: f64) -> usize {
+        let indexes = particles_within(&self.get_particles(), center, radius);
+        if !indexes.is_empty() {
+            self.remove_particles(&indexes);
+        }
+        return indexes.len();
+    }
+
+    fn export_snapshot(&self) -> Snapshot
+    where
+        Self: Sized,
+    {
+        return Snapshot::capture(self);
+    }
+
+    /// Continues from `snapshot`, which may come from any backend, taking
+    /// over its step settings. The snapshot has to describe a world of the
+    /// same size, force law and neighbour search as this scene.
+    fn import_snapshot(&mut self, snapshot: &Snapshot) -> Result<()> {
+        let settings = self.get_settings();
+        if snapshot.settings.screen_size != settings.screen_size {
+            return Err(Error::Mismatch(format!(
+                "snapshot world size {:?} does not match the scene's {:?}",
+                snapshot.settings.screen_size, settings.screen_size
+            )));
+        }
+        if snapshot.settings.force_law != settings.force_law {
+            return Err(Error::Mismatch(format!(
+                "snapshot force law {:?} does not match the scene's {:?}",
+                snapshot.settings.force_law, settings.force_law
+            )));
+        }
+        if snapshot.settings.neighbor_search != settings.neighbor_search {
+            return Err(Error::Mismatch(format!(
+                "snapshot neighbor search {:?} does not match the scene's {:?}",
+                snapshot.settings.neighbor_search, settings.neighbor_search
+            )));
+        }
+        let config = SceneConfig::from_settings(&snapshot.settings);
+        config.validate()?;
+        self.configure(&config);
+        self.set_particle_types(snapshot.particle_types()?);
+        self.set_particles(&snapshot.particles);
+        self.set_obstacles(snapshot.obstacles.clone());
+        self.set_fields(snapshot.fields.clone());
+        return Ok(());
+    }
+}
+
+/// Indexes of the particles closer than `radius` to `center`.
+pub(crate) fn particles_within(particles: &[Particle], center: Vec2d, radius: f64) -> Vec<usize> {
+    return particles
+        .iter()
+        .enumerate()
+        .filter(|(_, p)| {
+            let mut offset = p.pos;
+            return len(sub(&mut offset, &center)) < radius;
+        })
+        .map(|(i, _)| i)
+        .collect();
+}
+
+/// Flags the entries of a `count` long list that are in `indexes`.
+pub(crate) fn removal_mask(count: usize, indexes: &[usize]) -> Vec<bool> {
+    let mut removed = vec![false; count];
+    for index in indexes {
+        if let Some(flag) = removed.get_mut(*index) {
+            *flag = true;
+        }
+    }
+    return removed;
+}
+
+/// Drops the entries of `values` flagged in `removed`.
+pub(crate) fn retain_unflagged<T>(values: &mut Vec<T>, removed: &[bool]) {
+    let mut flags = removed.iter();
+    values.retain(|_| !flags.next().copied().unwrap_or(false));
 }
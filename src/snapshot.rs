@@ -0,0 +1,381 @@
+//! Full scene state on disk, for checkpointing and resuming runs.
+//!
+//! A snapshot file starts with [`MAGIC`] and a little-endian `u32` format
+//! version, followed by a length-prefixed TOML header holding the
+//! [`SceneSettings`], the [`RuleSet`], the [`ObstacleSet`] and the
+//! [`FieldSet`]. The particles come last as `pos_x, pos_y, vel_x, vel_y`
+//! (`f64`) and `type_index` (`u32`) records, so large scenes stay compact and
+//! exact while the header remains editable.
+
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Error, Result},
+    field::FieldSet,
+    obstacle::ObstacleSet,
+    particle_type::ParticleTypeManager,
+    rule_set::RuleSet,
+    scene_like::SceneLike,
+    Particle, SceneSettings,
+};
+
+pub const MAGIC: &[u8; 8] = b"PSIMSNAP";
+pub const VERSION: u32 = 1;
+
+/// Most particles [`Snapshot::read`] reserves room for up front. Larger
+/// snapshots grow as their records are read.
+const PREALLOCATED_PARTICLES: u64 = 1 << 16;
+
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub settings: SceneSettings,
+    pub rules: RuleSet,
+    pub obstacles: ObstacleSet,
+    pub fields: FieldSet,
+    pub particles: Vec<Particle>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    settings: SceneSettings,
+    rules: RuleSet,
+    #[serde(default, skip_serializing_if = "ObstacleSet::is_empty")]
+    obstacles: ObstacleSet,
+    #[serde(default, skip_serializing_if = "FieldSet::is_empty")]
+    fields: FieldSet,
+}
+
+impl Snapshot {
+    pub fn capture(scene: &impl SceneLike) -> Snapshot {
+        let particles = scene.get_particles().to_vec();
+        let mut settings = scene.get_settings();
+        settings.particle_count = particles.len();
+        return Snapshot {
+            settings,
+            rules: scene.get_particle_types().to_rule_set(),
+            obstacles: scene.get_obstacles().clone(),
+            fields: scene.get_fields().clone(),
+            particles,
+        };
+    }
+
+    /// Creates a scene of any backend that continues from this snapshot.
+    pub async fn restore<S: SceneLike>(&self) -> Result<S> {
+        // Backends cannot be built without types, so the rules are checked
+        // before the scene exists.
+        self.particle_types()?;
+        let mut scene = S::new(self.settings).await;
+        scene.import_snapshot(self)?;
+        return Ok(scene);
+    }
+
+    pub fn write(&self, out: &mut impl Write) -> Result<()> {
+        let header = toml::to_string(&Header {
+            settings: self.settings,
+            rules: self.rules.clone(),
+            obstacles: self.obstacles.clone(),
+            fields: self.fields.clone(),
+        })
+        .expect("Snapshot header should always be serializable to TOML");
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        out.write_all(&(header.len() as u64).to_le_bytes())?;
+        out.write_all(header.as_bytes())?;
+        out.write_all(&(self.particles.len() as u64).to_le_bytes())?;
+        for p in &self.particles {
+            for value in [p.pos[0], p.pos[1], p.vel[0], p.vel[1]] {
+                out.write_all(&value.to_le_bytes())?;
+            }
+            out.write_all(&(p.type_index as u32).to_le_bytes())?;
+        }
+        return Ok(());
+    }
+
+    pub fn read(input: &mut impl Read) -> Result<Snapshot> {
+        let magic = read_bytes::<8>(input)?;
+        if &magic != MAGIC {
+            return Err(Error::Parse("not a snapshot file".into()));
+        }
+        let version = read_u32(input)?;
+        if version != VERSION {
+            return Err(Error::Parse(format!(
+                "unsupported snapshot version {version}"
+            )));
+        }
+        // Lengths come from the file, so nothing is allocated before the data
+        // backing it has been read.
+        let header_length = read_u64(input)?;
+        let mut header = Vec::new();
+        input
+            .by_ref()
+            .take(header_length)
+            .read_to_end(&mut header)?;
+        if header.len() as u64 != header_length {
+            return Err(truncated());
+        }
+        let header = String::from_utf8(header)
+            .map_err(|_| Error::Parse("snapshot header is not UTF-8".into()))?;
+        let header: Header =
+            toml::from_str(&header).map_err(|err| Error::Parse(err.to_string()))?;
+        ParticleTypeManager::from_rule_set(&header.rules)?;
+        for obstacle in &header.obstacles.obstacles {
+            obstacle.shape.validate()?;
+        }
+        for field in &header.fields.fields {
+            field.kind.validate()?;
+        }
+        // Backends divide by the world size from the moment they are built.
+        if header.settings.screen_size.contains(&0) {
+            return Err(Error::Parse(format!(
+                "snapshot world size {:?} is empty",
+                header.settings.screen_size
+            )));
+        }
+
+        let particle_count = read_u64(input)?;
+        let mut particles = Vec::with_capacity(particle_count.min(PREALLOCATED_PARTICLES) as usize);
+        for _ in 0..particle_count {
+            let pos = [read_f64(input)?, read_f64(input)?];
+            let vel = [read_f64(input)?, read_f64(input)?];
+            let type_index = read_u32(input)? as usize;
+            if type_index >= header.rules.types.len() {
+                return Err(Error::Parse(format!(
+                    "particle type {type_index} is not defined in the rules"
+                )));
+            }
+            particles.push(Particle {
+                pos,
+                vel,
+                type_index,
+            });
+        }
+        let mut settings = header.settings;
+        settings.particle_count = particles.len();
+        settings.particle_types_count = header.rules.types.len();
+        return Ok(Snapshot {
+            settings,
+            rules: header.rules,
+            obstacles: header.obstacles,
+            fields: header.fields,
+            particles,
+        });
+    }
+
+    /// Writes to a temporary file first, so a crash during a checkpoint never
+    /// leaves a truncated snapshot behind.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut out = BufWriter::new(File::create(&tmp_path)?);
+            self.write(&mut out)?;
+            out.flush()?;
+        }
+        fs::rename(&tmp_path, path)?;
+        return Ok(());
+    }
+
+    pub fn load(path: &Path) -> Result<Snapshot> {
+        return File::open(path)
+            .map_err(Error::from)
+            .and_then(|file| Snapshot::read(&mut BufReader::new(file)))
+            .map_err(|err| err.in_file(path));
+    }
+
+    pub fn particle_types(&self) -> Result<ParticleTypeManager> {
+        return ParticleTypeManager::from_rule_set(&self.rules);
+    }
+}
+
+fn truncated() -> Error {
+    return Error::Parse("snapshot is truncated".into());
+}
+
+fn read_bytes<const N: usize>(input: &mut impl Read) -> Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    input
+        .read_exact(&mut bytes)
+        .map_err(|err| match err.kind() {
+            ErrorKind::UnexpectedEof => truncated(),
+            _ => Error::Io(err),
+        })?;
+    return Ok(bytes);
+}
+
+fn read_u32(input: &mut impl Read) -> Result<u32> {
+    return Ok(u32::from_le_bytes(read_bytes(input)?));
+}
+
+fn read_u64(input: &mut impl Read) -> Result<u64> {
+    return Ok(u64::from_le_bytes(read_bytes(input)?));
+}
+
+fn read_f64(input: &mut impl Read) -> Result<f64> {
+    return Ok(f64::from_le_bytes(read_bytes(input)?));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{field::FieldKind, obstacle::Shape};
+
+    fn snapshot() -> Snapshot {
+        let settings = SceneSettings {
+            screen_size: [400, 300],
+            particle_count: 20,
+            particle_types_count: 3,
+            seed: 1,
+            force_law: Default::default(),
+            neighbor_search: Default::default(),
+            substeps: 2,
+            dt: 0.5,
+            integrator: Default::default(),
+            boundary: Default::default(),
+            restitution: 0.8,
+        };
+        let particles = (0..20)
+            .map(|i| Particle {
+                pos: [i as f64 * 19.5, 300.0 / (i + 1) as f64],
+                vel: [0.1 * i as f64, -1.0 / 3.0],
+                type_index: i % 3,
+            })
+            .collect();
+        return Snapshot {
+            settings,
+            rules: ParticleTypeManager::new(3, settings.seed).unwrap().to_rule_set(),
+            obstacles: ObstacleSet::from_toml(
+                "[[obstacles]]\nshape = \"circle\"\ncenter = [100.0, 100.0]\nradius = 40.0\npermeable_to = [1]",
+            )
+            .unwrap(),
+            fields: FieldSet::from_toml(
+                "[[fields]]\nkind = \"gravity\"\nacceleration = [0.0, 0.05]\nresponse = [1.0, -1.0]",
+            )
+            .unwrap(),
+            particles,
+        };
+    }
+
+    #[test]
+    fn snapshot_reads_back_what_it_wrote() {
+        let initial = snapshot();
+        let mut bytes = Vec::new();
+        initial.write(&mut bytes).unwrap();
+        let read = Snapshot::read(&mut bytes.as_slice()).expect("Snapshot should read back");
+        assert_eq!(
+            format!("{:?}", read.settings),
+            format!("{:?}", initial.settings)
+        );
+        assert_eq!(read.rules, initial.rules);
+        assert_eq!(read.obstacles, initial.obstacles);
+        assert_eq!(read.fields, initial.fields);
+        assert_eq!(read.particles.len(), initial.particles.len());
+        for (a, b) in read.particles.iter().zip(&initial.particles) {
+            assert_eq!((a.pos, a.vel, a.type_index), (b.pos, b.vel, b.type_index));
+        }
+    }
+
+    #[test]
+    fn lengths_beyond_the_data_are_errors() {
+        let initial = snapshot();
+        let mut bytes = Vec::new();
+        initial.write(&mut bytes).unwrap();
+
+        let header_length = MAGIC.len() + 4;
+        let mut huge_header = bytes[..header_length].to_vec();
+        huge_header.extend(u64::MAX.to_le_bytes());
+        assert!(matches!(
+            Snapshot::read(&mut huge_header.as_slice()),
+            Err(Error::Parse(_))
+        ));
+        let particles_start = bytes.len() - initial.particles.len() * 36 - 8;
+        let mut huge_count = bytes[..particles_start].to_vec();
+        huge_count.extend(u64::MAX.to_le_bytes());
+        assert!(matches!(
+            Snapshot::read(&mut huge_count.as_slice()),
+            Err(Error::Parse(_))
+        ));
+        assert!(matches!(
+            Snapshot::read(&mut &bytes[..bytes.len() - 1]),
+            Err(Error::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn empty_worlds_are_errors() {
+        for screen_size in [[0, 300], [400, 0]] {
+            let mut empty = snapshot();
+            empty.settings.screen_size = screen_size;
+            let mut bytes = Vec::new();
+            empty.write(&mut bytes).unwrap();
+            assert!(matches!(
+                Snapshot::read(&mut bytes.as_slice()),
+                Err(Error::Parse(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn degenerate_obstacles_are_errors() {
+        let mut degenerate = snapshot();
+        degenerate.obstacles = ObstacleSet::from_toml(
+            "[[obstacles]]\nshape = \"polygon\"\npoints = [[0.0, 0.0], [10.0, 0.0], [0.0, 10.0]]",
+        )
+        .unwrap();
+        // Only the file can bring in a polygon of two points.
+        let Shape::Polygon { points } = &mut degenerate.obstacles.obstacles[0].shape else {
+            unreachable!();
+        };
+        points.pop();
+        let mut bytes = Vec::new();
+        degenerate.write(&mut bytes).unwrap();
+        assert!(matches!(
+            Snapshot::read(&mut bytes.as_slice()),
+            Err(Error::InvalidObstacles(_))
+        ));
+    }
+
+    #[test]
+    fn flows_without_their_vectors_are_errors() {
+        let mut empty_flow = snapshot();
+        empty_flow.fields.fields[0].kind = FieldKind::Flow {
+            origin: [0.0, 0.0],
+            cell_size: 10.0,
+            width: 30,
+            height: 30,
+            vectors: vec![],
+            image: None,
+            strength: 1.0,
+        };
+        let mut bytes = Vec::new();
+        empty_flow.write(&mut bytes).unwrap();
+        assert!(matches!(
+            Snapshot::read(&mut bytes.as_slice()),
+            Err(Error::InvalidFields(_))
+        ));
+    }
+
+    #[test]
+    fn snapshots_without_types_are_errors() {
+        let mut empty = snapshot();
+        empty.rules.types.clear();
+        empty.rules.forces.clear();
+        empty.rules.min_distances.clear();
+        empty.rules.radii.clear();
+        empty.particles.clear();
+        let mut bytes = Vec::new();
+        empty.write(&mut bytes).unwrap();
+        assert!(matches!(
+            Snapshot::read(&mut bytes.as_slice()),
+            Err(Error::InvalidRules(_))
+        ));
+        assert!(matches!(
+            pollster::block_on(empty.restore::<crate::multithreaded_scene_v2::MultithreadedSceneV2>()),
+            Err(Error::InvalidRules(_))
+        ));
+    }
+}
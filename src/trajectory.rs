@@ -0,0 +1,421 @@
+//! Compact binary recording of particle positions over time.
+//!
+//! The file starts with [`MAGIC`], a little-endian `u32` format version and a
+//! header with the world size, recording stride and the colors of every type.
+//! Each frame that follows is the `u64` step number, the `u32` particle count,
+//! a `u8` flag and one quantized `[u16; 2]` position per particle, a quarter
+//! of the size of the `f64` positions it stores. The flag is set when the
+//! `u16` type index of every particle follows before the positions, which
+//! happens on the first frame and whenever particles were spawned, removed or
+//! absorbed since the last one. Other frames reuse the type indexes of the
+//! frame before. Only positions inside the world are stored, particles that
+//! left an open world are recorded at its edge.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use graphics::types::Color;
+
+use crate::{
+    error::{Error, Result},
+    Particle,
+};
+
+pub const MAGIC: &[u8; 8] = b"PSIMTRAJ";
+pub const VERSION: u32 = 2;
+
+const QUANTIZATION_STEPS: f64 = 65536.0;
+
+pub struct TrajectoryWriter<W: Write> {
+    out: W,
+    screen_size: [u32; 2],
+    record_every: usize,
+    /// Type indexes written with the last frame, if any.
+    type_indexes: Option<Vec<u16>>,
+}
+
+impl TrajectoryWriter<BufWriter<File>> {
+    pub fn create(
+        path: &Path,
+        screen_size: [u32; 2],
+        colors: &[Color],
+        record_every: usize,
+    ) -> Result<Self> {
+        let out = BufWriter::new(File::create(path)?);
+        return TrajectoryWriter::new(out, screen_size, colors, record_every);
+    }
+}
+
+impl<W: Write> TrajectoryWriter<W> {
+    /// Writes the header. No frame is recorded.
+    pub fn new(
+        mut out: W,
+        screen_size: [u32; 2],
+        colors: &[Color],
+        record_every: usize,
+    ) -> Result<Self> {
+        let Some(stride) = u32::try_from(record_every).ok().filter(|stride| *stride > 0) else {
+            return Err(Error::InvalidConfig(format!(
+                "cannot record every {record_every} steps"
+            )));
+        };
+        if colors.len() > u16::MAX as usize + 1 {
+            return Err(Error::InvalidConfig(format!(
+                "{} particle types do not fit in a trajectory",
+                colors.len()
+            )));
+        }
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        out.write_all(&screen_size[0].to_le_bytes())?;
+        out.write_all(&screen_size[1].to_le_bytes())?;
+        out.write_all(&stride.to_le_bytes())?;
+        out.write_all(&(colors.len() as u32).to_le_bytes())?;
+        for color in colors {
+            for channel in color {
+                out.write_all(&channel.to_le_bytes())?;
+            }
+        }
+        return Ok(TrajectoryWriter {
+            out,
+            screen_size,
+            record_every,
+            type_indexes: None,
+        });
+    }
+
+    /// Writes a frame if `step` falls on the recording stride. The particle
+    /// count may differ from the last frame.
+    pub fn record(&mut self, step: u64, particles: &[Particle]) -> Result<()> {
+        if !step.is_multiple_of(self.record_every as u64) {
+            return Ok(());
+        }
+        let particle_count = u32::try_from(particles.len()).map_err(|_| {
+            Error::InvalidConfig(format!(
+                "{} particles do not fit in a trajectory frame",
+                particles.len()
+            ))
+        })?;
+        let type_indexes = particles
+            .iter()
+            .map(|p| {
+                u16::try_from(p.type_index).map_err(|_| {
+                    Error::InvalidConfig(format!(
+                        "particle type {} does not fit in a trajectory",
+                        p.type_index
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let changed = self.type_indexes.as_ref() != Some(&type_indexes);
+        self.out.write_all(&step.to_le_bytes())?;
+        self.out.write_all(&particle_count.to_le_bytes())?;
+        self.out.write_all(&[changed as u8])?;
+        if changed {
+            for type_index in &type_indexes {
+                self.out.write_all(&type_index.to_le_bytes())?;
+            }
+            self.type_indexes = Some(type_indexes);
+        }
+        for p in particles {
+            for axis in 0..2 {
+                let q = quantize(p.pos[axis], self.screen_size[axis] as f64);
+                self.out.write_all(&q.to_le_bytes())?;
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        self.out.flush()?;
+        return Ok(self.out);
+    }
+}
+
+struct Frame {
+    /// Index into [`Trajectory::populations`].
+    population: usize,
+    positions: Vec<[u16; 2]>,
+}
+
+pub struct Trajectory {
+    pub screen_size: [u32; 2],
+    pub record_every: usize,
+    pub colors: Vec<Color>,
+    pub steps: Vec<u64>,
+    /// Type indexes of every population the recording went through.
+    populations: Vec<Vec<usize>>,
+    frames: Vec<Frame>,
+}
+
+impl Trajectory {
+    pub fn load(path: &Path) -> Result<Trajectory> {
+        return File::open(path)
+            .map_err(Error::from)
+            .and_then(|file| Trajectory::read(&mut BufReader::new(file)))
+            .map_err(|err| err.in_file(path));
+    }
+
+    /// Reads every frame. A truncated last frame, as left by an interrupted
+    /// recording, is dropped.
+    pub fn read(input: &mut impl Read) -> Result<Trajectory> {
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::Parse("not a trajectory file".into()));
+        }
+        let version = read_u32(input)?;
+        if version != VERSION {
+            return Err(Error::Parse(format!(
+                "unsupported trajectory version {version}"
+            )));
+        }
+        let screen_size = [read_u32(input)?, read_u32(input)?];
+        let record_every = read_u32(input)? as usize;
+        let colors = (0..read_u32(input)?)
+            .map(|_| {
+                let mut color = [0.0; 4];
+                for channel in &mut color {
+                    *channel = f32::from_le_bytes(read_array(input)?);
+                }
+                return Ok(color);
+            })
+            .collect::<Result<Vec<Color>>>()?;
+
+        let mut trajectory = Trajectory {
+            screen_size,
+            record_every,
+            colors,
+            steps: vec![],
+            populations: vec![],
+            frames: vec![],
+        };
+        loop {
+            match trajectory.read_frame(input) {
+                Ok(()) => {}
+                Err(Error::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            }
+        }
+        return Ok(trajectory);
+    }
+
+    fn read_frame(&mut self, input: &mut impl Read) -> Result<()> {
+        let step = u64::from_le_bytes(read_array(input)?);
+        let particle_count = read_u32(input)? as usize;
+        let [has_types] = read_array::<1>(input)?;
+        let mut type_indexes = None;
+        if has_types != 0 {
+            let types = read_bytes(input, particle_count as u64 * 2)?
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]) as usize)
+                .collect::<Vec<_>>();
+            if let Some(type_index) = types.iter().find(|t| **t >= self.colors.len()) {
+                return Err(Error::Parse(format!(
+                    "particle type {type_index} has no color"
+                )));
+            }
+            type_indexes = Some(types);
+        }
+        let frame_bytes = read_bytes(input, particle_count as u64 * 4)?;
+        if let Some(type_indexes) = type_indexes {
+            self.populations.push(type_indexes);
+        }
+        if self
+            .populations
+            .last()
+            .is_none_or(|types| types.len() != particle_count)
+        {
+            return Err(Error::Parse(format!(
+                "frame of step {step} has no type indexes for its {particle_count} particles"
+            )));
+        }
+        self.steps.push(step);
+        self.frames.push(Frame {
+            population: self.populations.len() - 1,
+            positions: frame_bytes
+                .chunks_exact(4)
+                .map(|c| {
+                    [
+                        u16::from_le_bytes([c[0], c[1]]),
+                        u16::from_le_bytes([c[2], c[3]]),
+                    ]
+                })
+                .collect(),
+        });
+        return Ok(());
+    }
+
+    pub fn frame_count(&self) -> usize {
+        return self.frames.len();
+    }
+
+    /// Decodes frame `index`. Velocities are not recorded and come back as zero.
+    pub fn frame(&self, index: usize) -> Vec<Particle> {
+        let frame = &self.frames[index];
+        return frame
+            .positions
+            .iter()
+            .zip(&self.populations[frame.population])
+            .map(|(q, type_index)| Particle {
+                pos: [
+                    dequantize(q[0], self.screen_size[0] as f64),
+                    dequantize(q[1], self.screen_size[1] as f64),
+                ],
+                vel: [0.0, 0.0],
+                type_index: *type_index,
+            })
+            .collect();
+    }
+}
+
+/// Positions outside the world, which open boundaries allow, are clamped to
+/// its edges.
+fn quantize(value: f64, size: f64) -> u16 {
+    return (value / size * QUANTIZATION_STEPS).clamp(0.0, QUANTIZATION_STEPS - 1.0) as u16;
+}
+
+fn dequantize(value: u16, size: f64) -> f64 {
+    return (value as f64 + 0.5) / QUANTIZATION_STEPS * size;
+}
+
+/// Reads `length` bytes, growing the buffer only as the data arrives, since
+/// lengths come from the file.
+fn read_bytes(input: &mut impl Read, length: u64) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    input.take(length).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < length {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    return Ok(bytes);
+}
+
+fn read_array<const N: usize>(input: &mut impl Read) -> Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    input.read_exact(&mut bytes)?;
+    return Ok(bytes);
+}
+
+fn read_u32(input: &mut impl Read) -> Result<u32> {
+    return Ok(u32::from_le_bytes(read_array(input)?));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Particles that drift along, with the first one removed every other
+    /// step from step 7 on.
+    fn particles_at(step: u64) -> Vec<Particle> {
+        let removed = step.saturating_sub(5) as usize / 2;
+        return (removed..30)
+            .map(|i| Particle {
+                pos: [
+                    (i as f64 * 13.7 + step as f64 * 3.1) % 400.0,
+                    (i as f64 * 9.3 + step as f64 * 1.7) % 300.0,
+                ],
+                vel: [0.0, 0.0],
+                type_index: i % 3,
+            })
+            .collect();
+    }
+
+    #[test]
+    fn trajectory_reads_back_what_it_recorded() {
+        let colors = vec![
+            [1.0, 0.0, 0.0, 1.0],
+            [0.0, 1.0, 0.0, 1.0],
+            [0.0, 0.0, 1.0, 1.0],
+        ];
+        let mut writer = TrajectoryWriter::new(Vec::new(), [400, 300], &colors, 2).unwrap();
+        for step in 0..12 {
+            writer.record(step, &particles_at(step)).unwrap();
+        }
+        let bytes = writer.finish().unwrap();
+        let trajectory = Trajectory::read(&mut bytes.as_slice()).unwrap();
+        assert_eq!(trajectory.screen_size, [400, 300]);
+        assert_eq!(trajectory.record_every, 2);
+        assert_eq!(trajectory.colors, colors);
+        assert_eq!(trajectory.steps, [0, 2, 4, 6, 8, 10]);
+
+        // Positions are stored as the middle of one of 2^16 steps per axis.
+        let tolerance = [400.0, 300.0].map(|size| 0.5 * size / QUANTIZATION_STEPS);
+        for (frame, step) in trajectory.steps.iter().enumerate() {
+            let read = trajectory.frame(frame);
+            let recorded = particles_at(*step);
+            assert_eq!(read.len(), recorded.len());
+            for (a, b) in read.iter().zip(&recorded) {
+                assert_eq!(a.type_index, b.type_index);
+                for (axis, tolerance) in tolerance.iter().enumerate() {
+                    assert!(
+                        (a.pos[axis] - b.pos[axis]).abs() <= *tolerance,
+                        "step {step}: {:?} read back as {:?}",
+                        b.pos,
+                        a.pos
+                    );
+                }
+            }
+        }
+
+        // An interrupted recording loses only its last frame.
+        let truncated = Trajectory::read(&mut &bytes[..bytes.len() - 3]).unwrap();
+        assert_eq!(truncated.steps, [0, 2, 4, 6, 8]);
+    }
+
+    #[test]
+    fn corrupt_counts_and_types_are_errors() {
+        let colors = vec![[1.0, 1.0, 1.0, 1.0]];
+        let header = TrajectoryWriter::new(Vec::new(), [400, 300], &colors, 1)
+            .unwrap()
+            .finish()
+            .unwrap();
+        // A frame claiming u32::MAX particles is a truncated frame, not an
+        // allocation of 16 GiB.
+        let mut huge = header.clone();
+        huge.extend(0u64.to_le_bytes());
+        huge.extend(u32::MAX.to_le_bytes());
+        huge.push(1);
+        huge.extend([0u8; 64]);
+        assert_eq!(
+            Trajectory::read(&mut huge.as_slice())
+                .unwrap()
+                .frame_count(),
+            0
+        );
+
+        let mut writer = TrajectoryWriter::new(Vec::new(), [400, 300], &colors, 1).unwrap();
+        let mut particle = Particle::new();
+        particle.type_index = 1;
+        writer.record(0, &[particle]).unwrap();
+        let bytes = writer.finish().unwrap();
+        assert!(matches!(
+            Trajectory::read(&mut bytes.as_slice()),
+            Err(Error::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn unrecordable_strides_and_types_are_rejected() {
+        let too_many_colors = vec![[1.0, 1.0, 1.0, 1.0]; u16::MAX as usize + 2];
+        assert!(matches!(
+            TrajectoryWriter::new(Vec::new(), [400, 300], &too_many_colors[..1], 0),
+            Err(Error::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            TrajectoryWriter::new(Vec::new(), [400, 300], &too_many_colors, 1),
+            Err(Error::InvalidConfig(_))
+        ));
+
+        let colors = vec![[1.0, 1.0, 1.0, 1.0]];
+        let mut writer = TrajectoryWriter::new(Vec::new(), [400, 300], &colors, 1).unwrap();
+        let mut particle = Particle::new();
+        particle.type_index = u16::MAX as usize + 1;
+        assert!(matches!(
+            writer.record(0, &[particle]),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+}
@@ -77,6 +77,11 @@ pub fn len(v: &Vec2d) -> f64 {
     return (v[0].powi(2) + v[1].powi(2)).sqrt();
 }
 
+#[inline(always)]
+pub fn dot(v1: &Vec2d, v2: &Vec2d) -> f64 {
+    return v1[0] * v2[0] + v1[1] * v2[1];
+}
+
 #[inline(always)]
 pub fn normalize(v: &mut Vec2d) -> &mut Vec2d {
     let length = len(v);
@@ -108,7 +113,7 @@ pub fn mul_scalar_simd(v: &mut Vec2d, a: f64) -> &mut Vec2d {
 #[inline(always)]
 #[allow(dead_code)]
 pub fn apply_forces(
-    mut total_force: &mut Vec2d,
+    total_force: &mut Vec2d,
     direction: &Vec2d,
     force_value: f64,
     distance_based_strength: f64,
@@ -125,7 +130,7 @@ pub fn apply_forces(
         let force_multiplied = x86_64::_mm_mul_pd(result, k_packed);
         x86_64::_mm_store_pd(dest_ptr, force_multiplied);
     }
-    add(&mut total_force, force);
+    add(total_force, force);
 }
 
 #[inline(always)]
@@ -143,6 +148,33 @@ pub fn mul_hadamard<'a>(v1: &'a mut Vec2d, v2: &Vec2d) -> &'a mut Vec2d {
     return v1;
 }
 
+/// Wraps a difference of two positions into the periodic world, so it
+/// points to the closest image of the other position.
+#[inline(always)]
+pub fn minimum_image<'a>(v: &'a mut Vec2d, screen_size: &Vec2d) -> &'a mut Vec2d {
+    if v[0] > 0.5 * screen_size[0] {
+        v[0] -= screen_size[0];
+    }
+    if v[0] < -0.5 * screen_size[0] {
+        v[0] += screen_size[0];
+    }
+    if v[1] > 0.5 * screen_size[1] {
+        v[1] -= screen_size[1];
+    }
+    if v[1] < -0.5 * screen_size[1] {
+        v[1] += screen_size[1];
+    }
+    return v;
+}
+
+/// Wraps a position back into the periodic world.
+#[inline(always)]
+pub fn wrap<'a>(v: &'a mut Vec2d, screen_size: &Vec2d) -> &'a mut Vec2d {
+    v[0] = v[0].rem_euclid(screen_size[0]);
+    v[1] = v[1].rem_euclid(screen_size[1]);
+    return v;
+}
+
 #[inline(always)]
 pub fn remap(x: f64, a: f64, b: f64, c: f64, d: f64) -> f64 {
     return c + (x - a) * (d - c) / (b - a);
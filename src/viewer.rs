@@ -0,0 +1,537 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use glutin_window::GlutinWindow as Window;
+use graphics::{
+    clear, ellipse, line, math::Vec2d, polygon, rectangle, Context, Ellipse, Rectangle,
+};
+use opengl_graphics::GlGraphics;
+use particle_simulation::{
+    obstacle::{ObstacleSet, Shape},
+    trajectory::Trajectory,
+    Particle, SceneLike,
+};
+use piston::{
+    AdvancedWindow, Button, EventSettings, Events, Key, MouseButton, MouseCursorEvent,
+    MouseScrollEvent, PressEvent, ReleaseEvent, RenderEvent, Window as _, WindowSettings,
+};
+
+use crate::{
+    camera::Camera,
+    hot_reload::{HotReload, Reloaded},
+    hud::Hud,
+    mouse_tools::{MouseTools, Tool},
+    rule_panel::RulePanel,
+};
+
+fn open_window(title: &str, window_size: [u32; 2]) -> (Window, GlGraphics) {
+    let window: Window = WindowSettings::new(title, window_size)
+        .exit_on_esc(true)
+        .build()
+        .unwrap();
+    let gl = GlGraphics::new(glutin_window::OpenGL::V3_2);
+    return (window, gl);
+}
+
+fn draw_particles(
+    particles: &[Particle],
+    color_of: impl Fn(usize) -> [f32; 4],
+    c: Context,
+    gl: &mut GlGraphics,
+) {
+    clear([0.0, 0.0, 0.0, 1.0], gl);
+    for particle in particles {
+        ellipse(
+            color_of(particle.type_index),
+            rectangle::centered_square(particle.pos[0], particle.pos[1], 3.0),
+            c.transform,
+            gl,
+        );
+    }
+}
+
+/// Solid obstacles are grey, ones that let some types through are dimmer.
+fn draw_obstacles(obstacles: &ObstacleSet, c: Context, gl: &mut GlGraphics) {
+    for obstacle in &obstacles.obstacles {
+        let color = if obstacle.permeable_to.is_empty() {
+            [0.5, 0.5, 0.5, 1.0]
+        } else {
+            [0.5, 0.5, 0.5, 0.4]
+        };
+        match &obstacle.shape {
+            Shape::Circle { center, radius } => {
+                ellipse(
+                    color,
+                    rectangle::centered_square(center[0], center[1], *radius),
+                    c.transform,
+                    gl,
+                );
+            }
+            Shape::Segment {
+                start,
+                end,
+                thickness,
+            } => {
+                graphics::Line::new_round(color, 0.5 * thickness).draw(
+                    [start[0], start[1], end[0], end[1]],
+                    &c.draw_state,
+                    c.transform,
+                    gl,
+                );
+            }
+            Shape::Polygon { points } => {
+                // Filled as a triangle fan, which is only right for convex
+                // polygons, so the outline is drawn on top.
+                polygon(
+                    [color[0], color[1], color[2], 0.5 * color[3]],
+                    points,
+                    c.transform,
+                    gl,
+                );
+                for (i, a) in points.iter().enumerate() {
+                    let b = &points[(i + 1) % points.len()];
+                    line(color, 1.0, [a[0], a[1], b[0], b[1]], c.transform, gl);
+                }
+            }
+            Shape::Mask {
+                origin,
+                cell_size,
+                rows,
+                ..
+            } => {
+                for (y, row) in rows.iter().enumerate() {
+                    for (x, cell) in row.bytes().enumerate() {
+                        if cell == b'#' {
+                            rectangle(
+                                color,
+                                [
+                                    origin[0] + x as f64 * cell_size,
+                                    origin[1] + y as f64 * cell_size,
+                                    *cell_size,
+                                    *cell_size,
+                                ],
+                                c.transform,
+                                gl,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn draw_brush(tools: &MouseTools, color: [f32; 4], c: Context, gl: &mut GlGraphics) {
+    let cursor = tools.cursor();
+    Ellipse::new_border([color[0], color[1], color[2], 0.6], 1.0).draw(
+        rectangle::centered_square(cursor[0], cursor[1], tools.brush_radius),
+        &c.draw_state,
+        c.transform,
+        gl,
+    );
+}
+
+/// Decides how many updates each frame runs. Speeds below 1 are slow motion:
+/// an update every few frames.
+pub struct SimulationClock {
+    pub paused: bool,
+    pub speed: f64,
+    /// Updates owed to the frames so far, below one.
+    owed: f64,
+    /// Single steps asked for while paused.
+    pending_steps: usize,
+    pub steps: u64,
+}
+
+impl SimulationClock {
+    pub fn new() -> SimulationClock {
+        return SimulationClock {
+            paused: false,
+            speed: 1.0,
+            owed: 0.0,
+            pending_steps: 0,
+            steps: 0,
+        };
+    }
+
+    /// Pauses and runs one more update on the next frame.
+    pub fn single_step(&mut self) {
+        self.paused = true;
+        self.pending_steps += 1;
+    }
+
+    pub fn faster(&mut self) {
+        self.speed = (self.speed * 2.0).min(64.0);
+    }
+
+    /// Halves the speed, down to slow motion at one update every 64 frames.
+    pub fn slower(&mut self) {
+        self.speed = (self.speed / 2.0).max(1.0 / 64.0);
+    }
+
+    pub fn updates_this_frame(&mut self) -> usize {
+        let updates = if self.paused {
+            self.owed = 0.0;
+            std::mem::take(&mut self.pending_steps)
+        } else {
+            self.owed += self.speed;
+            let updates = self.owed.floor();
+            self.owed -= updates;
+            updates as usize
+        };
+        self.steps += updates as u64;
+        return updates;
+    }
+}
+
+fn window_title(clock: &SimulationClock, tools: &MouseTools, camera: &Camera) -> String {
+    return format!(
+        "Simulation window - step {} - speed x{}{} - {} tool (type {}, radius {:.0}) - zoom {:.0}%{}",
+        clock.steps,
+        clock.speed,
+        if clock.paused { " - paused" } else { "" },
+        tools.tool,
+        tools.spawn_type,
+        tools.brush_radius,
+        camera.zoom() * 100.0,
+        match camera.following() {
+            Some(index) => format!(" - following #{index}"),
+            None => String::new(),
+        }
+    );
+}
+
+fn draw_world_border(world_size: [u32; 2], c: Context, gl: &mut GlGraphics) {
+    Rectangle::new_border([0.3, 0.3, 0.3, 1.0], 1.0).draw(
+        [0.0, 0.0, world_size[0] as f64, world_size[1] as f64],
+        &c.draw_state,
+        c.transform,
+        gl,
+    );
+}
+
+/// Index of the particle closest to `pos`, if there are any.
+fn closest_particle(particles: &[Particle], pos: Vec2d) -> Option<usize> {
+    return particles
+        .iter()
+        .map(|p| (p.pos[0] - pos[0]).hypot(p.pos[1] - pos[1]))
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(index, _)| index);
+}
+
+/// Writes the rules of `scene` back to `rules_path`, the file they were
+/// loaded from, or to a new timestamped file in the working directory.
+/// Returns the path written, if saving worked.
+pub fn save_rules(scene: &impl SceneLike, rules_path: Option<&Path>) -> Option<PathBuf> {
+    let path = match rules_path {
+        Some(path) => path.to_path_buf(),
+        None => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            PathBuf::from(format!("rules-{}.toml", now.as_secs()))
+        }
+    };
+    match scene.get_particle_types().save(&path) {
+        Ok(()) => {
+            println!("Rules saved to {}", path.display());
+            return Some(path);
+        }
+        Err(err) => {
+            eprintln!("Could not save rules to {}: {}", path.display(), err);
+            return None;
+        }
+    }
+}
+
+/// Prints the particle types of the current rules to stdout.
+pub fn print_particle_types(scene: &impl SceneLike) {
+    println!("=== Current settings ===");
+    println!(
+        "Particles: {:?}",
+        scene.get_particle_types().particle_types()
+    );
+    println!("========================");
+}
+
+/// Two bars in the top left corner while the simulation is paused.
+fn draw_pause_sign(c: Context, gl: &mut GlGraphics) {
+    for x in [10.0, 22.0] {
+        rectangle([1.0, 1.0, 1.0, 0.8], [x, 10.0, 8.0, 24.0], c.transform, gl);
+    }
+}
+
+/// Runs the scene in a window of `window_size`, which does not have to match
+/// the world.
+///
+/// Space pauses, Right runs a single update and pauses, Up/Down double or
+/// halve the updates per frame, from slow motion at one update every 64
+/// frames to 64 updates per frame.
+///
+/// The mouse wheel zooms, dragging with the right button pans, F follows the
+/// particle under the cursor or stops following and 0 fits the world into
+/// the window.
+///
+/// H shows or hides the statistics overlay, which names `backend`.
+///
+/// Files watched by `hot_reload` are applied as soon as they change on disk,
+/// keeping the particles. Errors in them are shown at the bottom of the
+/// window until the next reload.
+///
+/// Tab shows or hides the rule panel and M switches the matrix it shows.
+/// Clicking a value selects it, the mouse wheel over a value or -/= change
+/// it. Ctrl+Z and Ctrl+Y undo and redo rule changes, including new worlds.
+///
+/// The left mouse button uses the selected tool: 1 spawn, 2 erase, 3 push,
+/// 4 attract, 5 grab. T picks the next type to spawn, `[` and `]` resize
+/// the brush. N starts a new world and S saves the rules, see [`save_rules`].
+pub async fn display(
+    scene: &mut impl SceneLike,
+    window_size: [u32; 2],
+    backend: &str,
+    hot_reload: &mut HotReload,
+    rules_path: Option<&Path>,
+) {
+    let (mut window, mut gl) = open_window("Simulation window", window_size);
+    let mut events = Events::new(EventSettings::new());
+    let mut hud = Hud::new(backend);
+    let mut rule_panel = RulePanel::new();
+    let mut tools = MouseTools::new();
+    let mut clock = SimulationClock::new();
+    print_particle_types(scene);
+    let world_size = scene.get_settings().screen_size;
+    let mut window_size = window.size().into();
+    let mut camera = Camera::fitted(world_size, window_size);
+    let mut cursor = [0.0, 0.0];
+    let mut panning = false;
+    let mut ctrl = false;
+    while let Some(e) = events.next(&mut window) {
+        if let Some(position) = e.mouse_cursor_args() {
+            if panning {
+                camera.pan([position[0] - cursor[0], position[1] - cursor[1]]);
+            }
+            cursor = position;
+        }
+        let types_count = scene.get_settings().particle_types_count;
+        let over_panel = rule_panel.contains(cursor, window_size, types_count);
+        if let Some([_, scroll]) = e.mouse_scroll_args() {
+            if over_panel {
+                rule_panel.select_at(cursor, window_size, types_count);
+                rule_panel.adjust(scroll, scene);
+            } else {
+                camera.zoom_at(cursor, window_size, scroll);
+            }
+        }
+        match e.release_args() {
+            Some(Button::Mouse(MouseButton::Left)) => tools.release(),
+            Some(Button::Mouse(MouseButton::Right)) => panning = false,
+            Some(Button::Keyboard(Key::LCtrl | Key::RCtrl)) => ctrl = false,
+            _ => {}
+        }
+        match e.press_args() {
+            Some(Button::Mouse(MouseButton::Left)) if over_panel => {
+                rule_panel.select_at(cursor, window_size, types_count);
+            }
+            Some(Button::Mouse(MouseButton::Left)) => {
+                tools.move_to(camera.to_world(cursor, window_size));
+                tools.press(scene);
+            }
+            Some(Button::Mouse(MouseButton::Right)) => panning = true,
+            Some(Button::Mouse(_)) => {}
+            Some(Button::Keyboard(Key::Space)) => clock.paused = !clock.paused,
+            Some(Button::Keyboard(Key::Right)) => clock.single_step(),
+            Some(Button::Keyboard(Key::Up)) => clock.faster(),
+            Some(Button::Keyboard(Key::Down)) => clock.slower(),
+            Some(Button::Keyboard(Key::F)) => {
+                let target = match camera.following() {
+                    Some(_) => None,
+                    None => closest_particle(
+                        &scene.get_particles(),
+                        camera.to_world(cursor, window_size),
+                    ),
+                };
+                camera.follow(target);
+            }
+            Some(Button::Keyboard(Key::D0)) => camera.fit(window_size),
+            Some(Button::Keyboard(Key::H)) => hud.visible = !hud.visible,
+            Some(Button::Keyboard(Key::LCtrl | Key::RCtrl)) => ctrl = true,
+            Some(Button::Keyboard(Key::Z)) if ctrl => rule_panel.undo(scene),
+            Some(Button::Keyboard(Key::Y)) if ctrl => rule_panel.redo(scene),
+            Some(Button::Keyboard(Key::Tab)) => rule_panel.visible = !rule_panel.visible,
+            Some(Button::Keyboard(Key::M)) => rule_panel.next_matrix(),
+            Some(Button::Keyboard(key @ (Key::Minus | Key::Equals))) => {
+                rule_panel.adjust(if key == Key::Minus { -1.0 } else { 1.0 }, scene);
+            }
+            Some(Button::Keyboard(Key::S)) => {
+                if let Some(path) = save_rules(scene, rules_path) {
+                    hot_reload.saved(&path);
+                }
+            }
+            Some(Button::Keyboard(key @ (Key::D1 | Key::D2 | Key::D3 | Key::D4 | Key::D5))) => {
+                let tool = Tool::ALL[key as usize - Key::D1 as usize];
+                tools.select(tool);
+            }
+            Some(Button::Keyboard(Key::T)) => {
+                tools.next_spawn_type(scene.get_settings().particle_types_count);
+            }
+            Some(Button::Keyboard(key @ (Key::LeftBracket | Key::RightBracket))) => {
+                tools.resize_brush(if key == Key::LeftBracket { -1.0 } else { 1.0 });
+            }
+            Some(Button::Keyboard(Key::N)) => {
+                rule_panel.record(scene);
+                let seed = scene.new_world();
+                println!("New world! (seed {})", seed);
+                print_particle_types(scene);
+            }
+            _ => {}
+        }
+        if let Some(args) = e.render_args() {
+            window_size = args.window_size;
+            for file in hot_reload.changed() {
+                let name = file.path.file_name().unwrap_or_default().to_string_lossy();
+                match file.load() {
+                    Ok(reloaded) => {
+                        if let Reloaded::Rules(_) = reloaded {
+                            rule_panel.record(scene);
+                        }
+                        reloaded.apply(scene);
+                        hud.notify(format!("Reloaded {name}"), false);
+                    }
+                    Err(err) => {
+                        eprintln!("Could not reload {}: {}", file.path.display(), err);
+                        hud.notify(format!("Could not reload {name}: {err}"), true);
+                    }
+                }
+            }
+            // The camera may have moved under a resting cursor.
+            tools.move_to(camera.to_world(cursor, window_size));
+            // Erasing and absorbing walls shift the indexes of the particles
+            // after the removed ones, spawning only appends.
+            let before = scene.get_settings().particle_count;
+            tools.apply(scene);
+            let mut removed = scene.get_settings().particle_count < before;
+            for _ in 0..clock.updates_this_frame() {
+                let before = scene.get_settings().particle_count;
+                let start = Instant::now();
+                scene.update().await;
+                hud.record_step(start.elapsed());
+                removed |= scene.get_settings().particle_count < before;
+            }
+            if removed {
+                camera.follow(None);
+                tools.let_go();
+            }
+            let particles = scene.get_particles();
+            camera.track(&particles);
+            hud.record_frame(&particles, scene.get_settings().particle_types_count);
+            window.set_title(window_title(&clock, &tools, &camera));
+            gl.draw(args.viewport(), |c, gl| {
+                let world = camera.transform(c, window_size);
+                draw_particles(&particles, |t| scene.get_particle_color(t), world, gl);
+                draw_world_border(world_size, world, gl);
+                draw_obstacles(scene.get_obstacles(), world, gl);
+                draw_brush(
+                    &tools,
+                    scene.get_particle_color(tools.spawn_type),
+                    world,
+                    gl,
+                );
+                if clock.paused {
+                    draw_pause_sign(c, gl);
+                }
+                hud.draw(scene, &clock, c, gl);
+                rule_panel.draw(scene, window_size, c, gl);
+            });
+        }
+    }
+}
+
+/// Replays a recorded trajectory.
+///
+/// Space pauses, Left/Right step one frame, PageUp/PageDown jump by a tenth
+/// of the recording, Home rewinds, Up/Down double or halve the speed.
+pub fn playback(trajectory: &Trajectory) {
+    let frame_count = trajectory.frame_count();
+    if frame_count == 0 {
+        eprintln!("Trajectory has no frames");
+        return;
+    }
+    let (mut window, mut gl) = open_window("Playback", trajectory.screen_size);
+    let mut events = Events::new(EventSettings::new());
+    let mut position = 0.0_f64;
+    let mut speed = 1.0_f64;
+    let mut paused = false;
+    let last_frame = (frame_count - 1) as f64;
+    let jump = (frame_count as f64 / 10.0).max(1.0);
+    while let Some(e) = events.next(&mut window) {
+        if let Some(Button::Keyboard(key)) = e.press_args() {
+            match key {
+                Key::Space => paused = !paused,
+                Key::Right => position = (position.floor() + 1.0).min(last_frame),
+                Key::Left => position = (position.floor() - 1.0).max(0.0),
+                Key::PageUp => position = (position + jump).min(last_frame),
+                Key::PageDown => position = (position - jump).max(0.0),
+                Key::Home => position = 0.0,
+                Key::Up => speed = (speed * 2.0).min(64.0),
+                Key::Down => speed = (speed / 2.0).max(1.0 / 64.0),
+                _ => {}
+            }
+        }
+        if let Some(args) = e.render_args() {
+            let frame = position.floor() as usize;
+            window.set_title(format!(
+                "Playback - frame {}/{} (step {}) - speed x{}{}",
+                frame + 1,
+                frame_count,
+                trajectory.steps[frame],
+                speed,
+                if paused { " - paused" } else { "" }
+            ));
+            let particles = trajectory.frame(frame);
+            gl.draw(args.viewport(), |c, gl| {
+                draw_particles(&particles, |t| trajectory.colors[t], c, gl);
+            });
+            if !paused {
+                position = (position + speed).min(last_frame);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slow_motion_spreads_updates_over_frames() {
+        let mut clock = SimulationClock::new();
+        clock.slower();
+        clock.slower();
+        let updates = (0..8)
+            .map(|_| clock.updates_this_frame())
+            .collect::<Vec<_>>();
+        assert_eq!(updates, [0, 0, 0, 1, 0, 0, 0, 1]);
+        clock.faster();
+        clock.faster();
+        clock.faster();
+        assert_eq!(clock.updates_this_frame(), 2);
+        assert_eq!(clock.steps, 4);
+    }
+
+    #[test]
+    fn paused_clocks_only_run_single_steps() {
+        let mut clock = SimulationClock::new();
+        clock.single_step();
+        clock.single_step();
+        assert!(clock.paused);
+        assert_eq!(clock.updates_this_frame(), 2);
+        assert_eq!(clock.updates_this_frame(), 0);
+        for _ in 0..10 {
+            clock.faster();
+        }
+        assert_eq!(clock.speed, 64.0);
+    }
+}
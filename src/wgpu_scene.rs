@@ -1,177 +1,962 @@
-use std::sync::Arc;
+use std::{num::NonZeroU64, ops::Range, sync::Arc};
 
-use encase::{impl_vector, ShaderType, UniformBuffer};
+use encase::{ShaderType, UniformBuffer};
 use graphics::math::Vec2d;
 use rand::{rng, Rng};
 use wgpu::{
     util::{BufferInitDescriptor, DeviceExt},
-    BindGroupLayout, ComputePipeline, Device, Queue,
+    Adapter, BindGroup, BindGroupLayout, Buffer, ComputePipeline, Device, Instance, Queue,
 };
 
 use crate::{
-    particle_type::ParticleTypeManager, receive_into_slice::receive_into_slice,
-    scene_like::SceneLike, vector::random_vec, Particle, SceneSettings,
+    boundary::Boundary,
+    field::{FieldKind, FieldSet},
+    neighbor_grid::NeighborGrid,
+    obstacle::{signed_area, ObstacleSet, Shape},
+    particle_renderer::{ParticleInstances, ParticleRenderer, Viewport},
+    particle_type::ParticleTypeManager,
+    receive_into_slice::receive_into_slice,
+    scene_config::SceneConfig,
+    scene_like::{particles_within, removal_mask, retain_unflagged, SceneLike},
+    Particle, SceneSettings,
 };
 
 type Vec2df = Vec2d<f32>;
 
+/// Where compute.wgsl parks absorbed particles until [`WgpuScene::read_back`]
+/// removes them. Has to match `ABSORBED` in compute.wgsl and binning.wgsl.
+const ABSORBED_POSITION: f32 = -1.0e30;
+/// Smallest particle buffer allocation, in particles.
+const MIN_CAPACITY: usize = 256;
+/// Threads per workgroup of every entry point but the prefix sum.
+const WORKGROUP_SIZE: u64 = 64;
+/// Bits of the cell index each radix sort pass orders by. Has to match
+/// `RADIX_BITS` in binning.wgsl.
+const RADIX_BITS: u32 = 4;
+/// Enough radix sort passes for any `u32` cell index.
+const MAX_RADIX_PASSES: usize = (u32::BITS / RADIX_BITS) as usize;
+
 #[derive(ShaderType, Debug)]
 struct GlobalUniforms {
     screen_size_x: f32,
     screen_size_y: f32,
     particle_types_count: u32,
+    force_law: u32,
+    dt: f32,
+    integrator: u32,
+    boundary: u32,
+    restitution: f32,
+}
+
+/// Has to match `GridUniforms` in binning.wgsl and compute.wgsl.
+#[derive(ShaderType, Debug)]
+struct GridUniforms {
+    cells_x: u32,
+    cells_y: u32,
+    cell_size_x: f32,
+    cell_size_y: f32,
+}
+
+/// Has to match `StageUniforms` in compute.wgsl.
+#[derive(ShaderType, Debug)]
+struct StageUniforms {
+    stage: u32,
+}
+
+/// Has to match `RadixUniforms` in binning.wgsl.
+#[derive(ShaderType, Debug)]
+struct RadixUniforms {
+    shift: u32,
+}
+
+/// Entry points of binning.wgsl, run in this order. The last three run once
+/// per radix sort pass.
+struct BinningPipelines {
+    clear_counts: ComputePipeline,
+    count_cells: ComputePipeline,
+    prefix_sum: ComputePipeline,
+    count_digits: ComputePipeline,
+    scan_digits: ComputePipeline,
+    scatter_digits: ComputePipeline,
+}
+
+/// Device-resident particle state. Positions and velocities are doubled up:
+/// a step reads side `current` and writes side `1 - current`.
+///
+/// The buffers have room for `capacity` particles and only the first
+/// `particle_count` are bound, so particles can be added without
+/// reallocating every time.
+struct ParticleBuffers {
+    particle_count: usize,
+    capacity: usize,
+    positions: [Buffer; 2],
+    velocities: [Buffer; 2],
+    type_indexes: Buffer,
+    particle_cells: Buffer,
+    sorted_indices: Buffer,
+    /// The other side of the radix sort passes, next to `sorted_indices`.
+    scratch_indices: Buffer,
+    /// Particles per digit and block of the current radix sort pass.
+    digit_offsets: Buffer,
+    staging_positions: Buffer,
+    staging_velocities: Buffer,
+    /// Integrator state carried between the stages of a step.
+    base_states: Buffer,
+    accumulators: Buffer,
+}
+
+impl ParticleBuffers {
+    /// The part of a per-particle `buffer` in use, with a `T` per particle.
+    fn binding<'a, T>(&self, buffer: &'a Buffer) -> wgpu::BindingResource<'a> {
+        return wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+            buffer,
+            offset: 0,
+            size: NonZeroU64::new((self.particle_count * size_of::<T>()) as u64),
+        });
+    }
+}
+
+/// Buffers derived from the settings and the particle types.
+struct RuleBuffers {
+    global_uniforms: Buffer,
+    masses: Buffer,
+    drag: Buffer,
+    /// [`encode_obstacles`] output.
+    obstacles: Buffer,
+    /// [`encode_fields`] output.
+    fields: Buffer,
+    forces: Buffer,
+    radii: Buffer,
+    min_distances: Buffer,
+    /// Only read by [`ParticleRenderer`].
+    type_colors: Buffer,
+    grid_uniforms: Buffer,
+    cell_total: u32,
+    cell_counts: Buffer,
+    cell_start: Buffer,
+}
+
+/// Bind groups over [`ParticleBuffers`] and [`RuleBuffers`]. The arrays are
+/// indexed by the side that is read from.
+struct BindGroups {
+    storage: [BindGroup; 2],
+    uniform: BindGroup,
+    grid: BindGroup,
+    binning_storage: [BindGroup; 2],
+    binning_uniform: BindGroup,
+    /// Indexed by [`radix_side`]. Side 0 writes `sorted_indices`.
+    radix_indices: [BindGroup; 2],
 }
 
 pub struct WgpuScene {
     settings: SceneSettings,
-    pub particle_types: ParticleTypeManager,
+    particle_types: ParticleTypeManager,
+    obstacles: ObstacleSet,
+    fields: FieldSet,
 
     particles_pos: Vec<Vec2df>,
     particles_vel: Vec<Vec2df>,
     particles_type_indexes: Vec<u32>,
 
+    instance: Instance,
+    adapter: Adapter,
     device: Device,
     pipeline: ComputePipeline,
     queue: Queue,
 
     uniform_bind_group_layout: BindGroupLayout,
     storage_bind_group_layout: BindGroupLayout,
+    grid_bind_group_layout: BindGroupLayout,
+    /// Indexed by integrator stage.
+    stage_bind_groups: [BindGroup; 4],
+
+    binning: BinningPipelines,
+    binning_storage_bind_group_layout: BindGroupLayout,
+    binning_uniform_bind_group_layout: BindGroupLayout,
+    radix_indices_bind_group_layout: BindGroupLayout,
+    /// Indexed by radix sort pass.
+    radix_pass_bind_groups: [BindGroup; MAX_RADIX_PASSES],
+
+    particle_buffers: Option<ParticleBuffers>,
+    rule_buffers: Option<RuleBuffers>,
+    bind_groups: Option<BindGroups>,
+    /// Side of the ping-pong buffers holding the latest particle state.
+    current: usize,
+    /// The particles changed on the CPU side and have to be uploaded.
+    particles_dirty: bool,
+    /// Only the type indexes changed on the CPU side.
+    type_indexes_dirty: bool,
+    /// Copy the particles back to the CPU after every update.
+    readback: bool,
+    /// The device buffers hold updates the CPU copy has not seen yet.
+    unread: bool,
+    /// Most workgroups a dispatch may have along one dimension.
+    max_workgroups: u32,
+    /// The rules or settings changed and the rule buffers have to be rebuilt.
+    rules_dirty: bool,
 }
 
-impl SceneLike for WgpuScene {
-    async fn new(settings: SceneSettings) -> Self {
+impl WgpuScene {
+    /// Whether a GPU adapter can be found, so callers can skip this backend
+    /// instead of panicking in [`SceneLike::new`].
+    pub async fn is_available() -> bool {
         let instance = wgpu::Instance::new(&Default::default());
-        let adapter = instance.request_adapter(&Default::default()).await.unwrap();
-        let (device, queue) = adapter.request_device(&Default::default()).await.unwrap();
-        let shader = device.create_shader_module(wgpu::include_wgsl!("compute.wgsl"));
+        return instance.request_adapter(&Default::default()).await.is_ok();
+    }
 
-        let uniform_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: None,
+    fn grid_uniforms(&self) -> GridUniforms {
+        let screen_size = self.settings.screen_size.map(|v| v as f64);
+        let cutoff = self.settings.neighbor_search.cutoff(&self.particle_types);
+        let cells = NeighborGrid::dimensions(screen_size, cutoff);
+        return GridUniforms {
+            cells_x: cells[0] as u32,
+            cells_y: cells[1] as u32,
+            cell_size_x: (screen_size[0] / cells[0] as f64) as f32,
+            cell_size_y: (screen_size[1] / cells[1] as f64) as f32,
+        };
+    }
+
+    /// Whether [`SceneLike::update`] copies the particles back to the CPU.
+    /// Without it, [`SceneLike::get_particles`] keeps returning the last
+    /// state that was read back, until [`Self::read_back`] is called.
+    /// Indexes passed to the particle edits still refer to that state.
+    /// Absorbing worlds are always read back, since absorbed particles are
+    /// removed on the CPU.
+    pub fn set_readback(&mut self, readback: bool) {
+        self.readback = readback;
+    }
+
+    /// Copies the latest particle state from the device buffers and drops
+    /// the particles absorbed since the last read back.
+    pub async fn read_back(&mut self) {
+        self.unread = false;
+        if self.particles_dirty {
+            // The CPU copy is already the newest one.
+            return;
+        }
+        let Some(particle_buffers) = self.particle_buffers.as_ref() else {
+            return;
+        };
+        if particle_buffers.particle_count == 0 {
+            return;
+        }
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Readback encoder"),
+            });
+        let size = (particle_buffers.particle_count * size_of::<Vec2df>()) as u64;
+        encoder.copy_buffer_to_buffer(
+            &particle_buffers.positions[self.current],
+            0,
+            &particle_buffers.staging_positions,
+            0,
+            size,
+        );
+        encoder.copy_buffer_to_buffer(
+            &particle_buffers.velocities[self.current],
+            0,
+            &particle_buffers.staging_velocities,
+            0,
+            size,
+        );
+        self.queue.submit([encoder.finish()]);
+
+        receive_into_slice(
+            &self.device,
+            &particle_buffers.staging_positions,
+            &mut self.particles_pos,
+        )
+        .await;
+        receive_into_slice(
+            &self.device,
+            &particle_buffers.staging_velocities,
+            &mut self.particles_vel,
+        )
+        .await;
+        self.remove_absorbed();
+    }
+
+    /// Compacts the CPU copy, the device buffers follow on the next upload.
+    fn remove_absorbed(&mut self) {
+        if !self
+            .particles_pos
+            .iter()
+            .any(|pos| pos[0] <= ABSORBED_POSITION)
+        {
+            return;
+        }
+        let kept = (0..self.particles_pos.len())
+            .filter(|i| self.particles_pos[*i][0] > ABSORBED_POSITION)
+            .collect::<Vec<_>>();
+        self.particles_pos = kept.iter().map(|i| self.particles_pos[*i]).collect();
+        self.particles_vel = kept.iter().map(|i| self.particles_vel[*i]).collect();
+        self.particles_type_indexes = kept
+            .iter()
+            .map(|i| self.particles_type_indexes[*i])
+            .collect();
+        self.settings.particle_count = kept.len();
+        self.particles_dirty = true;
+    }
+
+    /// Draws the current particles into `view`, as `viewport` describes,
+    /// without reading them back.
+    pub fn render(
+        &mut self,
+        renderer: &ParticleRenderer,
+        view: &wgpu::TextureView,
+        viewport: &Viewport,
+    ) {
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Render encoder"),
+            });
+        let instances = if self.sync_buffers() {
+            let particle_buffers = self.particle_buffers.as_ref().unwrap();
+            Some(ParticleInstances {
+                positions: &particle_buffers.positions[self.current],
+                type_indexes: &particle_buffers.type_indexes,
+                type_colors: &self.rule_buffers.as_ref().unwrap().type_colors,
+                particle_count: particle_buffers.particle_count as u32,
+            })
+        } else {
+            None
+        };
+        renderer.draw(
+            &self.device,
+            &self.queue,
+            &mut encoder,
+            view,
+            viewport,
+            instances.as_ref(),
+        );
+        self.queue.submit([encoder.finish()]);
+    }
+
+    /// The instance the device was created from, e.g. to create a surface.
+    pub fn instance(&self) -> &Instance {
+        return &self.instance;
+    }
+
+    pub fn adapter(&self) -> &Adapter {
+        return &self.adapter;
+    }
+
+    pub fn device(&self) -> &Device {
+        return &self.device;
+    }
+
+    pub fn queue(&self) -> &Queue {
+        return &self.queue;
+    }
+
+    /// Brings the device buffers up to date with the CPU side. Returns
+    /// `false` when there are no particles.
+    fn sync_buffers(&mut self) -> bool {
+        if self.particles_type_indexes.is_empty() {
+            return false;
+        }
+        if self.rules_dirty || self.rule_buffers.is_none() {
+            self.upload_rules();
+        }
+        match &self.particle_buffers {
+            Some(particle_buffers) if !self.particles_dirty => {
+                if self.type_indexes_dirty {
+                    self.queue.write_buffer(
+                        &particle_buffers.type_indexes,
+                        0,
+                        bytemuck::cast_slice(&self.particles_type_indexes),
+                    );
+                    self.type_indexes_dirty = false;
+                }
+            }
+            _ => self.upload_particles(),
+        }
+        if self.bind_groups.is_none() {
+            self.bind_groups = Some(self.create_bind_groups());
+        }
+        return true;
+    }
+
+    /// Rebuilds the buffers that only depend on the settings and the rules.
+    fn upload_rules(&mut self) {
+        // The variants are numbered like the `FORCE_LAW_*`, `INTEGRATOR_*`
+        // and `BOUNDARY_*` constants of compute.wgsl.
+        let uniforms = GlobalUniforms {
+            screen_size_x: self.settings.screen_size[0] as f32,
+            screen_size_y: self.settings.screen_size[1] as f32,
+            particle_types_count: self.settings.particle_types_count as u32,
+            force_law: self.settings.force_law as u32,
+            dt: self.settings.dt as f32,
+            integrator: self.settings.integrator as u32,
+            boundary: self.settings.boundary as u32,
+            restitution: self.settings.restitution as f32,
+        };
+        let mut encase_uniform_buffer = UniformBuffer::new(Vec::new());
+        encase_uniform_buffer
+            .write(&uniforms)
+            .expect("Uniform buffer should contain uniforms");
+        let global_uniforms = self.device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Uniform buffer"),
+            contents: encase_uniform_buffer.into_inner().as_slice(),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let forces = self.device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Type forces"),
+            contents: bytemuck::cast_slice(&self.particle_types.get_forces_flattened()),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let radii = self.device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Type radii"),
+            contents: bytemuck::cast_slice(&self.particle_types.get_radii_flattened()),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let min_distances = self.device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Type min distances"),
+            contents: bytemuck::cast_slice(&self.particle_types.get_min_distance_flattened()),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let masses = self.device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Type masses"),
+            contents: bytemuck::cast_slice(&self.particle_types.get_masses()),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let drag = self.device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Type drag"),
+            contents: bytemuck::cast_slice(&self.particle_types.get_drag()),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let obstacles = self.device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Obstacles"),
+            contents: bytemuck::cast_slice(&encode_obstacles(
+                &self.obstacles,
+                self.settings.particle_types_count,
+            )),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let fields = self.device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Fields"),
+            contents: bytemuck::cast_slice(&encode_fields(
+                &self.fields,
+                self.settings.particle_types_count,
+            )),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let type_colors = self.device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Type colors"),
+            contents: bytemuck::cast_slice(&self.particle_types.get_colors()),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+
+        let grid_uniforms = self.grid_uniforms();
+        let cell_total = grid_uniforms.cells_x * grid_uniforms.cells_y;
+        let mut encase_grid_buffer = UniformBuffer::new(Vec::new());
+        encase_grid_buffer
+            .write(&grid_uniforms)
+            .expect("Uniform buffer should contain grid uniforms");
+        let grid_uniforms = self.device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Grid uniform buffer"),
+            contents: encase_grid_buffer.into_inner().as_slice(),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+
+        self.rule_buffers = Some(RuleBuffers {
+            global_uniforms,
+            masses,
+            drag,
+            obstacles,
+            fields,
+            forces,
+            radii,
+            min_distances,
+            type_colors,
+            grid_uniforms,
+            cell_total,
+            cell_counts: self.index_buffer("Cell counts", cell_total as u64),
+            cell_start: self.index_buffer("Cell start", cell_total as u64 + 1),
+        });
+        self.bind_groups = None;
+        self.rules_dirty = false;
+    }
+
+    /// Writes the CPU copy of the particles into side `current`, and only
+    /// reallocates when they do not fit.
+    fn upload_particles(&mut self) {
+        let particle_count = self.particles_pos.len();
+        let fits = self
+            .particle_buffers
+            .as_ref()
+            .is_some_and(|buffers| buffers.capacity >= particle_count);
+        if !fits {
+            self.particle_buffers = Some(self.allocate_particles(capacity_for(particle_count)));
+            self.bind_groups = None;
+        }
+        let buffers = self.particle_buffers.as_mut().unwrap();
+        self.queue.write_buffer(
+            &buffers.positions[self.current],
+            0,
+            bytemuck::cast_slice(&self.particles_pos),
+        );
+        self.queue.write_buffer(
+            &buffers.velocities[self.current],
+            0,
+            bytemuck::cast_slice(&self.particles_vel),
+        );
+        self.queue.write_buffer(
+            &buffers.type_indexes,
+            0,
+            bytemuck::cast_slice(&self.particles_type_indexes),
+        );
+        if buffers.particle_count != particle_count {
+            buffers.particle_count = particle_count;
+            self.bind_groups = None;
+        }
+        self.particles_dirty = false;
+        self.type_indexes_dirty = false;
+    }
+
+    /// Empty particle buffers with room for `capacity` particles.
+    fn allocate_particles(&self, capacity: usize) -> ParticleBuffers {
+        let buffer = |label: &str, size: usize, usage: wgpu::BufferUsages| {
+            self.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size: (capacity * size) as u64,
+                usage,
+                mapped_at_creation: false,
+            })
+        };
+        let vector_usage = wgpu::BufferUsages::STORAGE
+            | wgpu::BufferUsages::COPY_SRC
+            | wgpu::BufferUsages::COPY_DST;
+        let staging_usage = wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ;
+        let vector_size = size_of::<Vec2df>();
+        let state_size = size_of::<[f32; 4]>();
+        return ParticleBuffers {
+            particle_count: 0,
+            capacity,
+            positions: [
+                buffer("Positions 0", vector_size, vector_usage),
+                buffer("Positions 1", vector_size, vector_usage),
+            ],
+            velocities: [
+                buffer("Velocities 0", vector_size, vector_usage),
+                buffer("Velocities 1", vector_size, vector_usage),
+            ],
+            type_indexes: buffer(
+                "Type indexes",
+                size_of::<u32>(),
+                wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            ),
+            particle_cells: self.index_buffer("Particle cells", capacity as u64),
+            sorted_indices: self.index_buffer("Sorted indices", capacity as u64),
+            scratch_indices: self.index_buffer("Scratch indices", capacity as u64),
+            digit_offsets: self.index_buffer(
+                "Digit offsets",
+                (capacity as u64).div_ceil(WORKGROUP_SIZE) << RADIX_BITS,
+            ),
+            staging_positions: buffer("Staging positions", vector_size, staging_usage),
+            staging_velocities: buffer("Staging velocities", vector_size, staging_usage),
+            base_states: buffer("Base states", state_size, wgpu::BufferUsages::STORAGE),
+            accumulators: buffer("Accumulators", state_size, wgpu::BufferUsages::STORAGE),
+        };
+    }
+
+    /// Writes the particles from `start` on into the device buffers, behind
+    /// the ones already there. Grows the buffers on the device, since the
+    /// CPU copy of the existing particles may be older.
+    fn append_particles(&mut self, start: usize) {
+        let particle_count = self.particles_pos.len();
+        let buffers = self.particle_buffers.as_ref().unwrap();
+        if buffers.capacity < particle_count {
+            let grown = self.allocate_particles(capacity_for(particle_count));
+            let size = (start * size_of::<Vec2df>()) as u64;
+            if size > 0 {
+                let mut encoder =
+                    self.device
+                        .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                            label: Some("Growth encoder"),
+                        });
+                let side = self.current;
+                for (from, to) in [
+                    (&buffers.positions[side], &grown.positions[side]),
+                    (&buffers.velocities[side], &grown.velocities[side]),
+                ] {
+                    encoder.copy_buffer_to_buffer(from, 0, to, 0, size);
+                }
+                self.queue.submit([encoder.finish()]);
+            }
+            self.particle_buffers = Some(grown);
+        }
+        let buffers = self.particle_buffers.as_mut().unwrap();
+        let offset = (start * size_of::<Vec2df>()) as u64;
+        self.queue.write_buffer(
+            &buffers.positions[self.current],
+            offset,
+            bytemuck::cast_slice(&self.particles_pos[start..]),
+        );
+        self.queue.write_buffer(
+            &buffers.velocities[self.current],
+            offset,
+            bytemuck::cast_slice(&self.particles_vel[start..]),
+        );
+        // The CPU copy of the type indexes is never outdated.
+        self.queue.write_buffer(
+            &buffers.type_indexes,
+            0,
+            bytemuck::cast_slice(&self.particles_type_indexes),
+        );
+        buffers.particle_count = particle_count;
+        self.type_indexes_dirty = false;
+        self.bind_groups = None;
+    }
+
+    /// Moves the particles not flagged in `removed` to the front of the
+    /// other side of the ping-pong buffers, which becomes the current one.
+    fn compact_particles(&mut self, removed: &[bool]) {
+        let buffers = self.particle_buffers.as_mut().unwrap();
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Compaction encoder"),
+            });
+        let (from, to) = (self.current, 1 - self.current);
+        let stride = size_of::<Vec2df>() as u64;
+        let mut kept = 0;
+        for run in kept_runs(removed) {
+            let size = run.len() as u64 * stride;
+            for buffer in [&buffers.positions, &buffers.velocities] {
+                encoder.copy_buffer_to_buffer(
+                    &buffer[from],
+                    run.start as u64 * stride,
+                    &buffer[to],
+                    kept as u64 * stride,
+                    size,
+                );
+            }
+            kept += run.len();
+        }
+        self.queue.submit([encoder.finish()]);
+        buffers.particle_count = kept;
+        self.current = to;
+        self.type_indexes_dirty = true;
+        self.bind_groups = None;
+    }
+
+    fn index_buffer(&self, label: &str, len: u64) -> Buffer {
+        return self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: len * size_of::<u32>() as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+    }
+
+    fn create_bind_groups(&self) -> BindGroups {
+        let particles = self.particle_buffers.as_ref().unwrap();
+        let rules = self.rule_buffers.as_ref().unwrap();
+        let storage = |input: usize| {
+            let output = 1 - input;
+            self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Bind group"),
+                layout: &self.storage_bind_group_layout,
                 entries: &[
-                    wgpu::BindGroupLayoutEntry {
+                    wgpu::BindGroupEntry {
                         binding: 0,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        count: None,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Uniform,
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
+                        resource: particles.binding::<Vec2df>(&particles.positions[input]),
                     },
-                    wgpu::BindGroupLayoutEntry {
+                    wgpu::BindGroupEntry {
                         binding: 1,
-                        count: None,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Storage { read_only: true },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
+                        resource: particles.binding::<Vec2df>(&particles.velocities[input]),
                     },
-                    wgpu::BindGroupLayoutEntry {
+                    wgpu::BindGroupEntry {
                         binding: 2,
-                        count: None,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Storage { read_only: true },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
+                        resource: particles.binding::<u32>(&particles.type_indexes),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: particles.binding::<Vec2df>(&particles.positions[output]),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 4,
+                        resource: particles.binding::<Vec2df>(&particles.velocities[output]),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 5,
+                        resource: rules.forces.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 6,
+                        resource: rules.radii.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 7,
+                        resource: rules.min_distances.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 8,
+                        resource: particles.binding::<[f32; 4]>(&particles.base_states),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 9,
+                        resource: particles.binding::<[f32; 4]>(&particles.accumulators),
                     },
                 ],
-            });
-
-        let storage_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: None,
+            })
+        };
+        let binning_storage = |input: usize| {
+            self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Binning bind group"),
+                layout: &self.binning_storage_bind_group_layout,
                 entries: &[
-                    wgpu::BindGroupLayoutEntry {
+                    wgpu::BindGroupEntry {
                         binding: 0,
-                        count: None,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Storage { read_only: true },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
+                        resource: particles.binding::<Vec2df>(&particles.positions[input]),
                     },
-                    wgpu::BindGroupLayoutEntry {
+                    wgpu::BindGroupEntry {
                         binding: 1,
-                        count: None,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Storage { read_only: true },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
+                        resource: rules.cell_counts.as_entire_binding(),
                     },
-                    wgpu::BindGroupLayoutEntry {
+                    wgpu::BindGroupEntry {
                         binding: 2,
-                        count: None,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Storage { read_only: true },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
+                        resource: rules.cell_start.as_entire_binding(),
                     },
-                    wgpu::BindGroupLayoutEntry {
+                    wgpu::BindGroupEntry {
                         binding: 3,
-                        count: None,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Storage { read_only: false },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
+                        resource: particles.binding::<u32>(&particles.particle_cells),
                     },
-                    wgpu::BindGroupLayoutEntry {
+                    wgpu::BindGroupEntry {
                         binding: 4,
-                        count: None,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Storage { read_only: false },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
+                        resource: particles.digit_offsets.as_entire_binding(),
                     },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 5,
-                        count: None,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Storage { read_only: true },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
+                ],
+            })
+        };
+        let radix_indices = |from: &Buffer, to: &Buffer| {
+            self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Radix indices bind group"),
+                layout: &self.radix_indices_bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: particles.binding::<u32>(from),
                     },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 6,
-                        count: None,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Storage { read_only: true },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: particles.binding::<u32>(to),
                     },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 7,
-                        count: None,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Storage { read_only: true },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
+                ],
+            })
+        };
+        return BindGroups {
+            storage: [storage(0), storage(1)],
+            uniform: self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Uniform bind group"),
+                layout: &self.uniform_bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: rules.global_uniforms.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: rules.masses.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: rules.drag.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: rules.obstacles.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 4,
+                        resource: rules.fields.as_entire_binding(),
+                    },
+                ],
+            }),
+            grid: self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Grid bind group"),
+                layout: &self.grid_bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: rules.grid_uniforms.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: rules.cell_start.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: particles.binding::<u32>(&particles.sorted_indices),
                     },
                 ],
+            }),
+            binning_storage: [binning_storage(0), binning_storage(1)],
+            binning_uniform: self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Binning uniform bind group"),
+                layout: &self.binning_uniform_bind_group_layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: rules.grid_uniforms.as_entire_binding(),
+                }],
+            }),
+            radix_indices: [
+                radix_indices(&particles.scratch_indices, &particles.sorted_indices),
+                radix_indices(&particles.sorted_indices, &particles.scratch_indices),
+            ],
+        };
+    }
+}
+
+/// Radix sort passes that order particles by cell. Absorbed particles are
+/// keyed one past the last cell, so `cell_total` has to fit as well.
+fn radix_passes(cell_total: u32) -> usize {
+    return (u32::BITS - cell_total.leading_zeros()).div_ceil(RADIX_BITS) as usize;
+}
+
+/// Side of [`BindGroups::radix_indices`] radix sort pass `pass` of `passes`
+/// writes, so that the last one ends in `sorted_indices`.
+fn radix_side(pass: usize, passes: usize) -> usize {
+    return (passes - 1 - pass) % 2;
+}
+
+/// Room for `particle_count` particles and as many again.
+fn capacity_for(particle_count: usize) -> usize {
+    return (2 * particle_count).max(MIN_CAPACITY);
+}
+
+/// Workgroups along x and y that run at least `invocations` threads of
+/// [`WORKGROUP_SIZE`], with no more than `max_per_dimension` along either.
+/// The shaders number the threads row by row.
+fn workgroup_grid(invocations: u64, max_per_dimension: u32) -> [u32; 2] {
+    let groups = invocations.div_ceil(WORKGROUP_SIZE);
+    let x = groups.min(max_per_dimension as u64);
+    if x == 0 {
+        return [0, 0];
+    }
+    return [x as u32, groups.div_ceil(x) as u32];
+}
+
+/// The index ranges between the entries flagged in `removed`.
+fn kept_runs(removed: &[bool]) -> Vec<Range<usize>> {
+    let mut runs = Vec::new();
+    let mut start = 0;
+    for (i, flag) in removed.iter().chain([&true]).enumerate() {
+        if *flag {
+            if start < i {
+                runs.push(start..i);
+            }
+            start = i + 1;
+        }
+    }
+    return runs;
+}
+
+fn storage_layout_entry(binding: u32, read_only: bool) -> wgpu::BindGroupLayoutEntry {
+    return wgpu::BindGroupLayoutEntry {
+        binding,
+        count: None,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+    };
+}
+
+fn uniform_layout_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+    return wgpu::BindGroupLayoutEntry {
+        binding,
+        count: None,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+    };
+}
+
+impl SceneLike for WgpuScene {
+    async fn new(settings: SceneSettings) -> Self {
+        let instance = wgpu::Instance::new(&Default::default());
+        let adapter = instance.request_adapter(&Default::default()).await.unwrap();
+        let adapter_limits = adapter.limits();
+        let (device, queue) = adapter
+            .request_device(&wgpu::DeviceDescriptor {
+                // The force pass binds more storage buffers than the default
+                // limit of 8, and big scenes need big buffers.
+                required_limits: wgpu::Limits {
+                    max_storage_buffers_per_shader_stage: adapter_limits
+                        .max_storage_buffers_per_shader_stage,
+                    max_storage_buffer_binding_size: adapter_limits.max_storage_buffer_binding_size,
+                    max_buffer_size: adapter_limits.max_buffer_size,
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let shader = device.create_shader_module(wgpu::include_wgsl!("compute.wgsl"));
+        let binning_shader = device.create_shader_module(wgpu::include_wgsl!("binning.wgsl"));
+
+        let uniform_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: None,
+                entries: &[
+                    uniform_layout_entry(0),
+                    storage_layout_entry(1, true),
+                    storage_layout_entry(2, true),
+                    storage_layout_entry(3, true),
+                    storage_layout_entry(4, true),
+                ],
+            });
+
+        let storage_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: None,
+                entries: &[
+                    storage_layout_entry(0, true),
+                    storage_layout_entry(1, true),
+                    storage_layout_entry(2, true),
+                    storage_layout_entry(3, false),
+                    storage_layout_entry(4, false),
+                    storage_layout_entry(5, true),
+                    storage_layout_entry(6, true),
+                    storage_layout_entry(7, true),
+                    storage_layout_entry(8, false),
+                    storage_layout_entry(9, false),
+                ],
+            });
+
+        let grid_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Grid bind group layout"),
+                entries: &[
+                    uniform_layout_entry(0),
+                    storage_layout_entry(1, true),
+                    storage_layout_entry(2, true),
+                ],
+            });
+
+        let stage_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Stage bind group layout"),
+                entries: &[uniform_layout_entry(0)],
             });
 
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: None,
-            bind_group_layouts: &[&storage_bind_group_layout, &uniform_bind_group_layout],
+            bind_group_layouts: &[
+                &storage_bind_group_layout,
+                &uniform_bind_group_layout,
+                &grid_bind_group_layout,
+                &stage_bind_group_layout,
+            ],
             push_constant_ranges: &[],
         });
 
@@ -184,24 +969,153 @@ impl SceneLike for WgpuScene {
             cache: Default::default(),
         });
 
+        // The stage changes between dispatches of one command buffer, so every
+        // stage gets its own small uniform buffer.
+        let stage_bind_groups = [0u32, 1, 2, 3].map(|stage| {
+            let mut encase_stage_buffer = UniformBuffer::new(Vec::new());
+            encase_stage_buffer
+                .write(&StageUniforms { stage })
+                .expect("Uniform buffer should contain the stage");
+            let stage_buffer = device.create_buffer_init(&BufferInitDescriptor {
+                label: Some("Stage uniform buffer"),
+                contents: encase_stage_buffer.into_inner().as_slice(),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Stage bind group"),
+                layout: &stage_bind_group_layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: stage_buffer.as_entire_binding(),
+                }],
+            })
+        });
+
+        let binning_storage_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Binning storage bind group layout"),
+                entries: &[
+                    storage_layout_entry(0, true),
+                    storage_layout_entry(1, false),
+                    storage_layout_entry(2, false),
+                    storage_layout_entry(3, false),
+                    storage_layout_entry(4, false),
+                ],
+            });
+        let binning_uniform_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Binning uniform bind group layout"),
+                entries: &[uniform_layout_entry(0)],
+            });
+        let radix_indices_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Radix indices bind group layout"),
+                entries: &[storage_layout_entry(0, true), storage_layout_entry(1, false)],
+            });
+        let radix_pass_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Radix pass bind group layout"),
+                entries: &[uniform_layout_entry(0)],
+            });
+        let binning_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Binning pipeline layout"),
+                bind_group_layouts: &[
+                    &binning_storage_bind_group_layout,
+                    &binning_uniform_bind_group_layout,
+                    &radix_indices_bind_group_layout,
+                    &radix_pass_bind_group_layout,
+                ],
+                push_constant_ranges: &[],
+            });
+        let binning_pipeline = |entry_point: &str| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&binning_pipeline_layout),
+                module: &binning_shader,
+                entry_point: Some(entry_point),
+                compilation_options: Default::default(),
+                cache: Default::default(),
+            })
+        };
+        let binning = BinningPipelines {
+            clear_counts: binning_pipeline("clear_counts"),
+            count_cells: binning_pipeline("count_cells"),
+            prefix_sum: binning_pipeline("prefix_sum"),
+            count_digits: binning_pipeline("count_digits"),
+            scan_digits: binning_pipeline("scan_digits"),
+            scatter_digits: binning_pipeline("scatter_digits"),
+        };
+
+        // Like the stages, every radix sort pass gets its own uniform buffer.
+        let radix_pass_bind_groups = std::array::from_fn(|pass| {
+            let mut encase_radix_buffer = UniformBuffer::new(Vec::new());
+            encase_radix_buffer
+                .write(&RadixUniforms {
+                    shift: pass as u32 * RADIX_BITS,
+                })
+                .expect("Uniform buffer should contain the radix shift");
+            let radix_buffer = device.create_buffer_init(&BufferInitDescriptor {
+                label: Some("Radix pass uniform buffer"),
+                contents: encase_radix_buffer.into_inner().as_slice(),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Radix pass bind group"),
+                layout: &radix_pass_bind_group_layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: radix_buffer.as_entire_binding(),
+                }],
+            })
+        });
+        let max_workgroups = device.limits().max_compute_workgroups_per_dimension;
+
         Self {
             settings,
-            particle_types: ParticleTypeManager::new(settings.particle_types_count),
+            particle_types: ParticleTypeManager::new(settings.particle_types_count, settings.seed)
+                .expect("Scenes need at least one particle type"),
+            obstacles: ObstacleSet::default(),
+            fields: FieldSet::default(),
             particles_pos: vec![],
             particles_vel: vec![],
             particles_type_indexes: vec![],
+            instance,
+            adapter,
             device,
             pipeline,
+            stage_bind_groups,
             queue,
             uniform_bind_group_layout,
             storage_bind_group_layout,
+            grid_bind_group_layout,
+            binning,
+            binning_storage_bind_group_layout,
+            binning_uniform_bind_group_layout,
+            radix_indices_bind_group_layout,
+            radix_pass_bind_groups,
+            particle_buffers: None,
+            rule_buffers: None,
+            bind_groups: None,
+            current: 0,
+            particles_dirty: true,
+            type_indexes_dirty: false,
+            readback: true,
+            unread: false,
+            max_workgroups,
+            rules_dirty: true,
         }
     }
 
     fn init(&mut self) {
         let random_source = &mut rng();
         self.particles_pos = (0..self.settings.particle_count)
-            .map(|_| random_vec(random_source, 0.0..(self.settings.screen_size[0] as f32)))
+            .map(|_| {
+                [
+                    random_source.random_range(0.0..(self.settings.screen_size[0] as f32)),
+                    random_source.random_range(0.0..(self.settings.screen_size[1] as f32)),
+                ]
+            })
             .collect();
         self.particles_vel = (0..self.settings.particle_count)
             .map(|_| [0.0, 0.0])
@@ -209,192 +1123,91 @@ impl SceneLike for WgpuScene {
         self.particles_type_indexes = (0..self.settings.particle_count)
             .map(|_| random_source.random_range(0..self.settings.particle_types_count as u32))
             .collect();
+        self.particles_dirty = true;
     }
 
     async fn update(&mut self) {
+        if !self.sync_buffers() {
+            return;
+        }
+        let particle_buffers = self.particle_buffers.as_ref().unwrap();
+        let rule_buffers = self.rule_buffers.as_ref().unwrap();
+        let bind_groups = self.bind_groups.as_ref().unwrap();
+        let particle_count = particle_buffers.particle_count as u64;
+        let cell_total = rule_buffers.cell_total as u64;
+        let particle_groups = workgroup_grid(particle_count, self.max_workgroups);
+        let cell_groups = workgroup_grid(cell_total, self.max_workgroups);
+
         let mut encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                 label: Some("Compute encoder"),
             });
 
-        let input_positions_buffer = self.device.create_buffer_init(&BufferInitDescriptor {
-            label: Some("input"),
-            contents: bytemuck::cast_slice(&self.particles_pos),
-            usage: wgpu::BufferUsages::STORAGE,
-        });
-        let output_positions_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Output"),
-            size: input_positions_buffer.size(),
-            usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::STORAGE,
-            mapped_at_creation: false,
-        });
-        let temp_buffer_positions = self.device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("temp"),
-            size: input_positions_buffer.size(),
-            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
-            mapped_at_creation: false,
-        });
+        // Every substep and integrator stage is encoded into the same command
+        // buffer, with no readback in between. Each stage reads one side of
+        // the ping-pong buffers and writes the other.
+        let stages = self.settings.integrator.stages();
+        let mut input = self.current;
+        let radix_passes = radix_passes(rule_buffers.cell_total);
+        for _ in 0..self.settings.substeps {
+            for stage in 0..stages {
+                // A single cell is walked in index order without binning.
+                if cell_total > 1 {
+                    // Dispatches within one pass see each other's writes, so the
+                    // sort can run back to back.
+                    let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                        label: Some("Binning pass"),
+                        timestamp_writes: None,
+                    });
+                    pass.set_bind_group(0, &bind_groups.binning_storage[input], &[]);
+                    pass.set_bind_group(1, &bind_groups.binning_uniform, &[]);
+                    // The layout is shared by every entry point, so the radix
+                    // groups have to be bound before the first dispatch too.
+                    pass.set_bind_group(2, &bind_groups.radix_indices[0], &[]);
+                    pass.set_bind_group(3, &self.radix_pass_bind_groups[0], &[]);
+                    pass.set_pipeline(&self.binning.clear_counts);
+                    pass.dispatch_workgroups(cell_groups[0], cell_groups[1], 1);
+                    pass.set_pipeline(&self.binning.count_cells);
+                    pass.dispatch_workgroups(particle_groups[0], particle_groups[1], 1);
+                    pass.set_pipeline(&self.binning.prefix_sum);
+                    pass.dispatch_workgroups(1, 1, 1);
+                    for radix_pass in 0..radix_passes {
+                        let side = radix_side(radix_pass, radix_passes);
+                        pass.set_bind_group(2, &bind_groups.radix_indices[side], &[]);
+                        pass.set_bind_group(3, &self.radix_pass_bind_groups[radix_pass], &[]);
+                        pass.set_pipeline(&self.binning.count_digits);
+                        pass.dispatch_workgroups(particle_groups[0], particle_groups[1], 1);
+                        pass.set_pipeline(&self.binning.scan_digits);
+                        pass.dispatch_workgroups(1, 1, 1);
+                        pass.set_pipeline(&self.binning.scatter_digits);
+                        pass.dispatch_workgroups(particle_groups[0], particle_groups[1], 1);
+                    }
+                }
 
-        let input_velocities_buffer = self.device.create_buffer_init(&BufferInitDescriptor {
-            label: Some("input"),
-            contents: bytemuck::cast_slice(&self.particles_vel),
-            usage: wgpu::BufferUsages::STORAGE,
-        });
-        let output_velocities_buffers = self.device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Output"),
-            size: input_velocities_buffer.size(),
-            usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::STORAGE,
-            mapped_at_creation: false,
-        });
-        let temp_buffer_velocities = self.device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("temp"),
-            size: input_velocities_buffer.size(),
-            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
-            mapped_at_creation: false,
-        });
-        let input_type_indexes = self.device.create_buffer_init(&BufferInitDescriptor {
-            label: Some("input"),
-            contents: bytemuck::cast_slice(&self.particles_type_indexes),
-            usage: wgpu::BufferUsages::STORAGE,
-        });
-        let uniforms = GlobalUniforms {
-            screen_size_x: self.settings.screen_size[0] as f32,
-            screen_size_y: self.settings.screen_size[1] as f32,
-            particle_types_count: self.settings.particle_types_count as u32,
-        };
-        let mut encase_uniform_buffer = UniformBuffer::new(Vec::new());
-        encase_uniform_buffer
-            .write(&uniforms)
-            .expect("Uniform buffer should contain uniforms");
-        let global_uniforms_buffer = self.device.create_buffer_init(&BufferInitDescriptor {
-            label: Some("Uniform buffer"),
-            contents: encase_uniform_buffer.into_inner().as_slice(),
-            usage: wgpu::BufferUsages::UNIFORM,
-        });
-        let type_forces_buffer = self.device.create_buffer_init(&BufferInitDescriptor {
-            label: None,
-            contents: bytemuck::cast_slice(&self.particle_types.get_forces_flattened()),
-            usage: wgpu::BufferUsages::STORAGE,
-        });
-        let type_radii_buffer = self.device.create_buffer_init(&BufferInitDescriptor {
-            label: None,
-            contents: bytemuck::cast_slice(&self.particle_types.get_radii_flattened()),
-            usage: wgpu::BufferUsages::STORAGE,
-        });
-        let type_min_distance_buffer = self.device.create_buffer_init(&BufferInitDescriptor {
-            label: None,
-            contents: bytemuck::cast_slice(&self.particle_types.get_min_distance_flattened()),
-            usage: wgpu::BufferUsages::STORAGE,
-        });
-        let type_masses_buffer = self.device.create_buffer_init(&BufferInitDescriptor {
-            label: None,
-            contents: bytemuck::cast_slice(&self.particle_types.get_masses()),
-            usage: wgpu::BufferUsages::STORAGE,
-        });
-        let type_drag_buffer = self.device.create_buffer_init(&BufferInitDescriptor {
-            label: None,
-            contents: bytemuck::cast_slice(&self.particle_types.get_drag()),
-            usage: wgpu::BufferUsages::STORAGE,
-        });
-
-        let uniform_bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("Uniform bind group"),
-            layout: &self.uniform_bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: global_uniforms_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: type_masses_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: type_drag_buffer.as_entire_binding(),
-                },
-            ],
-        });
-
-        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("Bind group"),
-            layout: &self.storage_bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: input_positions_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: input_velocities_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: input_type_indexes.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 3,
-                    resource: output_positions_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 4,
-                    resource: output_velocities_buffers.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 5,
-                    resource: type_forces_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 6,
-                    resource: type_radii_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 7,
-                    resource: type_min_distance_buffer.as_entire_binding(),
-                },
-            ],
-        });
-
-        {
-            let mut pass = encoder.begin_compute_pass(&Default::default());
-            pass.set_pipeline(&self.pipeline);
-            let num_dispatches =
-                (self.particles_pos.len() / 64) as u32 + (self.particles_pos.len() / 64 > 0) as u32;
-            pass.set_bind_group(0, &bind_group, &[]);
-            pass.set_bind_group(1, &uniform_bind_group, &[]);
-            pass.dispatch_workgroups(num_dispatches, 1, 1);
+                {
+                    let mut pass = encoder.begin_compute_pass(&Default::default());
+                    pass.set_pipeline(&self.pipeline);
+                    pass.set_bind_group(0, &bind_groups.storage[input], &[]);
+                    pass.set_bind_group(1, &bind_groups.uniform, &[]);
+                    pass.set_bind_group(2, &bind_groups.grid, &[]);
+                    pass.set_bind_group(3, &self.stage_bind_groups[stage], &[]);
+                    pass.dispatch_workgroups(particle_groups[0], particle_groups[1], 1);
+                }
+                input = 1 - input;
+            }
         }
 
-        encoder.copy_buffer_to_buffer(
-            &output_positions_buffer,
-            0,
-            &temp_buffer_positions,
-            0,
-            output_positions_buffer.size(),
-        );
-
-        encoder.copy_buffer_to_buffer(
-            &output_velocities_buffers,
-            0,
-            &temp_buffer_velocities,
-            0,
-            output_velocities_buffers.size(),
-        );
-
         self.queue.submit([encoder.finish()]);
-
-        receive_into_slice(&self.device, temp_buffer_positions, &mut self.particles_pos).await;
-        receive_into_slice(
-            &self.device,
-            temp_buffer_velocities,
-            &mut self.particles_vel,
-        )
-        .await;
+        self.current = input;
+        self.unread = true;
+        if self.readback || self.settings.boundary == Boundary::Absorbing {
+            self.read_back().await;
+        }
     }
 
     fn get_particles(&self) -> std::sync::Arc<Vec<crate::Particle>> {
-        let particles = (0..self.settings.particle_count)
+        let particles = (0..self.particles_pos.len())
             .map(|i| Particle {
                 pos: self.particles_pos[i].map(|v| v as f64),
                 vel: self.particles_vel[i].map(|v| v as f64),
@@ -404,11 +1217,402 @@ impl SceneLike for WgpuScene {
         return Arc::new(particles);
     }
 
-    fn new_world(&mut self) {
-        todo!()
+    fn new_world(&mut self) -> u64 {
+        self.settings.seed = self.settings.seed.wrapping_add(1);
+        self.particle_types =
+            ParticleTypeManager::new(self.settings.particle_types_count, self.settings.seed)
+                .expect("Scenes need at least one particle type");
+        self.rules_dirty = true;
+        return self.settings.seed;
     }
 
     fn get_particle_color(&self, type_index: usize) -> [f32; 4] {
         return self.particle_types.get_particle_color(type_index);
     }
+
+    fn get_particle_types(&self) -> &ParticleTypeManager {
+        return &self.particle_types;
+    }
+
+    fn set_particle_types(&mut self, particle_types: ParticleTypeManager) {
+        self.settings.particle_types_count = particle_types.particle_types_count();
+        if let Some(seed) = particle_types.seed() {
+            self.settings.seed = seed;
+        }
+        let count = self.settings.particle_types_count as u32;
+        for type_index in &mut self.particles_type_indexes {
+            *type_index %= count;
+        }
+        self.particle_types = particle_types;
+        self.type_indexes_dirty = true;
+        self.rules_dirty = true;
+    }
+
+    fn get_settings(&self) -> SceneSettings {
+        return self.settings;
+    }
+
+    fn set_particles(&mut self, particles: &[Particle]) {
+        self.settings.particle_count = particles.len();
+        self.particles_pos = particles.iter().map(|p| p.pos.map(|v| v as f32)).collect();
+        self.particles_vel = particles.iter().map(|p| p.vel.map(|v| v as f32)).collect();
+        self.particles_type_indexes = particles.iter().map(|p| p.type_index as u32).collect();
+        self.particles_dirty = true;
+    }
+
+    fn spawn_particles(&mut self, particles: &[Particle]) {
+        let start = self.particles_pos.len();
+        self.particles_pos
+            .extend(particles.iter().map(|p| p.pos.map(|v| v as f32)));
+        self.particles_vel
+            .extend(particles.iter().map(|p| p.vel.map(|v| v as f32)));
+        self.particles_type_indexes
+            .extend(particles.iter().map(|p| p.type_index as u32));
+        self.settings.particle_count = self.particles_pos.len();
+        // Without readback the device holds newer state than the CPU copy,
+        // which must not be uploaded over it.
+        if !self.particles_dirty && self.particle_buffers.is_some() {
+            self.append_particles(start);
+        }
+    }
+
+    fn move_particles(&mut self, moves: &[(usize, Vec2d, Vec2d)]) {
+        let moves = moves
+            .iter()
+            .filter(|(i, ..)| *i < self.particles_pos.len())
+            .collect::<Vec<_>>();
+        for &&(i, pos, vel) in &moves {
+            self.particles_pos[i] = pos.map(|v| v as f32);
+            self.particles_vel[i] = vel.map(|v| v as f32);
+        }
+        // A pending full upload carries the moves along.
+        if self.particles_dirty {
+            return;
+        }
+        let Some(buffers) = &self.particle_buffers else {
+            return;
+        };
+        for &&(i, ..) in &moves {
+            let offset = (i * size_of::<Vec2df>()) as u64;
+            self.queue.write_buffer(
+                &buffers.positions[self.current],
+                offset,
+                bytemuck::cast_slice(&self.particles_pos[i..i + 1]),
+            );
+            self.queue.write_buffer(
+                &buffers.velocities[self.current],
+                offset,
+                bytemuck::cast_slice(&self.particles_vel[i..i + 1]),
+            );
+        }
+    }
+
+    fn remove_particles(&mut self, indexes: &[usize]) {
+        let removed = removal_mask(self.particles_pos.len(), indexes);
+        if !removed.contains(&true) {
+            return;
+        }
+        if !self.particles_dirty && self.particle_buffers.is_some() {
+            self.compact_particles(&removed);
+        }
+        retain_unflagged(&mut self.particles_pos, &removed);
+        retain_unflagged(&mut self.particles_vel, &removed);
+        retain_unflagged(&mut self.particles_type_indexes, &removed);
+        self.settings.particle_count = self.particles_pos.len();
+    }
+
+    fn erase_particles(&mut self, center: Vec2d, radius: f64) -> usize {
+        // Pick by where the particles are now, not where they were last read.
+        if self.unread {
+            pollster::block_on(self.read_back());
+        }
+        let indexes = particles_within(&self.get_particles(), center, radius);
+        if !indexes.is_empty() {
+            self.remove_particles(&indexes);
+        }
+        return indexes.len();
+    }
+
+    fn get_obstacles(&self) -> &ObstacleSet {
+        return &self.obstacles;
+    }
+
+    fn set_obstacles(&mut self, obstacles: ObstacleSet) {
+        self.obstacles = obstacles;
+        self.rules_dirty = true;
+    }
+
+    fn get_fields(&self) -> &FieldSet {
+        return &self.fields;
+    }
+
+    fn set_fields(&mut self, fields: FieldSet) {
+        self.fields = fields;
+        self.rules_dirty = true;
+    }
+
+    fn configure(&mut self, config: &SceneConfig) {
+        config.apply(&mut self.settings);
+        self.rules_dirty = true;
+    }
+}
+
+/// Packs `obstacles` into the words read by compute.wgsl: the obstacle count,
+/// the offset of every record, then the records. A record holds the kind,
+/// point count, size and mask dimensions, one permeability flag per particle
+/// type, the points and finally the mask cells. Floats are stored as bits.
+fn encode_obstacles(obstacles: &ObstacleSet, particle_types_count: usize) -> Vec<u32> {
+    let count = obstacles.obstacles.len();
+    let mut words = vec![0; 1 + count];
+    words[0] = count as u32;
+    for (k, obstacle) in obstacles.obstacles.iter().enumerate() {
+        words[1 + k] = words.len() as u32;
+        let mut cells = vec![];
+        let (kind, size, points, cells_x, cells_y) = match &obstacle.shape {
+            Shape::Circle { center, radius } => (0, *radius, vec![*center], 0, 0),
+            Shape::Segment {
+                start,
+                end,
+                thickness,
+            } => (1, 0.5 * thickness, vec![*start, *end], 0, 0),
+            Shape::Polygon { points } => (2, signed_area(points).signum(), points.clone(), 0, 0),
+            Shape::Mask {
+                origin,
+                cell_size,
+                rows,
+                ..
+            } => {
+                let cells_x = rows.iter().map(|row| row.len()).max().unwrap_or(0);
+                for row in rows {
+                    let row = row.as_bytes();
+                    cells.extend((0..cells_x).map(|x| (row.get(x) == Some(&b'#')) as u32));
+                }
+                (3, *cell_size, vec![*origin], cells_x, rows.len())
+            }
+        };
+        words.extend([
+            kind,
+            points.len() as u32,
+            (size as f32).to_bits(),
+            cells_x as u32,
+            cells_y as u32,
+        ]);
+        words.extend((0..particle_types_count).map(|t| !obstacle.blocks(t) as u32));
+        words.extend(
+            points
+                .iter()
+                .flat_map(|point| point.map(|v| (v as f32).to_bits())),
+        );
+        words.extend(cells);
+    }
+    return words;
+}
+
+/// Packs `fields` into the words read by compute.wgsl, laid out like
+/// [`encode_obstacles`]. A record holds the kind, the flow grid size, five
+/// parameters, one response per particle type and finally the flow vectors.
+/// Unlimited radii are stored as -1.
+fn encode_fields(fields: &FieldSet, particle_types_count: usize) -> Vec<u32> {
+    let count = fields.fields.len();
+    let mut words = vec![0; 1 + count];
+    words[0] = count as u32;
+    for (k, field) in fields.fields.iter().enumerate() {
+        words[1 + k] = words.len() as u32;
+        let mut vectors: &[Vec2d] = &[];
+        let (kind, parameters, width, height) = match &field.kind {
+            FieldKind::Gravity { acceleration } => {
+                (0, [acceleration[0], acceleration[1], 0.0, 0.0, 0.0], 0, 0)
+            }
+            FieldKind::Attractor {
+                center,
+                strength,
+                radius,
+                softening,
+            }
+            | FieldKind::Vortex {
+                center,
+                strength,
+                radius,
+                softening,
+            } => {
+                let kind = if let FieldKind::Vortex { .. } = field.kind {
+                    2
+                } else {
+                    1
+                };
+                let parameters = [
+                    center[0],
+                    center[1],
+                    *strength,
+                    radius.unwrap_or(-1.0),
+                    *softening,
+                ];
+                (kind, parameters, 0, 0)
+            }
+            FieldKind::Flow {
+                origin,
+                cell_size,
+                strength,
+                width,
+                height,
+                vectors: flow,
+                ..
+            } => {
+                vectors = flow;
+                let parameters = [origin[0], origin[1], *cell_size, *strength, 0.0];
+                (3, parameters, *width, *height)
+            }
+        };
+        words.extend([kind, width as u32, height as u32]);
+        words.extend(parameters.map(|v| (v as f32).to_bits()));
+        words.extend((0..particle_types_count).map(|t| (field.response(t) as f32).to_bits()));
+        words.extend(
+            vectors
+                .iter()
+                .flat_map(|vector| vector.map(|v| (v as f32).to_bits())),
+        );
+    }
+    return words;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kept_runs_skip_removed_entries() {
+        let flags = |removed: &[usize], count: usize| {
+            return (0..count).map(|i| removed.contains(&i)).collect::<Vec<_>>();
+        };
+        assert_eq!(kept_runs(&flags(&[], 4)), vec![0..4]);
+        assert_eq!(kept_runs(&flags(&[0, 3], 4)), vec![1..3]);
+        assert_eq!(kept_runs(&flags(&[1, 2, 5], 7)), [0..1, 3..5, 6..7]);
+        assert!(kept_runs(&flags(&[0, 1], 2)).is_empty());
+        assert!(kept_runs(&[]).is_empty());
+    }
+
+    #[test]
+    fn radix_passes_end_in_sorted_indices() {
+        // Keys run up to `cell_total`, the key of absorbed particles.
+        assert_eq!(radix_passes(2), 1);
+        assert_eq!(radix_passes(15), 1);
+        assert_eq!(radix_passes(16), 2);
+        assert_eq!(radix_passes(45), 2);
+        assert_eq!(radix_passes(1000), 3);
+        assert_eq!(radix_passes(u32::MAX), MAX_RADIX_PASSES);
+        for passes in 1..=MAX_RADIX_PASSES {
+            assert_eq!(radix_side(passes - 1, passes), 0);
+            for pass in 1..passes {
+                assert_ne!(radix_side(pass - 1, passes), radix_side(pass, passes));
+            }
+        }
+    }
+
+    /// Copies the first `len` entries of an index buffer back to the CPU.
+    async fn read_indices(scene: &WgpuScene, buffer: &Buffer, len: usize) -> Vec<u32> {
+        let staging = scene.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Test staging"),
+            size: buffer.size(),
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        let mut encoder = scene.device.create_command_encoder(&Default::default());
+        encoder.copy_buffer_to_buffer(buffer, 0, &staging, 0, buffer.size());
+        scene.queue.submit([encoder.finish()]);
+        let mut indices = vec![0u32; len];
+        receive_into_slice(&scene.device, &staging, &mut indices).await;
+        return indices;
+    }
+
+    #[test]
+    fn cells_hold_their_particles_by_ascending_index() {
+        let settings = SceneSettings {
+            screen_size: [2320, 1280],
+            particle_count: 3000,
+            particle_types_count: 4,
+            seed: 9,
+            force_law: Default::default(),
+            neighbor_search: Default::default(),
+            substeps: 1,
+            dt: 1.0,
+            integrator: crate::integrator::Integrator::SemiImplicitEuler,
+            boundary: Default::default(),
+            restitution: 1.0,
+        };
+        pollster::block_on(async {
+            if !WgpuScene::is_available().await {
+                return;
+            }
+            let mut scene = WgpuScene::new(settings).await;
+            scene.init();
+            scene.update().await;
+            let rules = scene.rule_buffers.as_ref().unwrap();
+            let particles = scene.particle_buffers.as_ref().unwrap();
+            let cell_total = rules.cell_total as usize;
+            assert!(cell_total > 1);
+            let cell_start = read_indices(&scene, &rules.cell_start, cell_total + 1).await;
+            let sorted = read_indices(&scene, &particles.sorted_indices, 3000).await;
+            assert_eq!(cell_start[cell_total], 3000);
+            for cell in 0..cell_total {
+                let members = &sorted[cell_start[cell] as usize..cell_start[cell + 1] as usize];
+                assert!(
+                    members.windows(2).all(|pair| pair[0] < pair[1]),
+                    "cell {cell} is out of order: {members:?}"
+                );
+            }
+            let mut every = sorted.clone();
+            every.sort();
+            assert!(every.iter().copied().eq(0..3000));
+        });
+    }
+
+    #[test]
+    fn large_dispatches_continue_along_y() {
+        assert_eq!(workgroup_grid(0, 65535), [0, 0]);
+        assert_eq!(workgroup_grid(65, 65535), [2, 1]);
+        assert_eq!(workgroup_grid(10_000_000, 65535), [65535, 3]);
+        assert_eq!(workgroup_grid(64 * 7, 3), [3, 3]);
+    }
+
+    #[test]
+    fn dispatches_split_along_y_match_single_rows() {
+        let mut settings = SceneSettings {
+            screen_size: [400, 300],
+            particle_count: 1000,
+            particle_types_count: 3,
+            seed: 4,
+            force_law: Default::default(),
+            neighbor_search: Default::default(),
+            substeps: 2,
+            dt: 0.5,
+            integrator: Default::default(),
+            boundary: Default::default(),
+            restitution: 1.0,
+        };
+        pollster::block_on(async {
+            if !WgpuScene::is_available().await {
+                return;
+            }
+            let mut single = WgpuScene::new(settings).await;
+            single.init();
+            settings.particle_count = 0;
+            let mut split = WgpuScene::new(settings).await;
+            split.set_particle_types(ParticleTypeManager::new(3, 4).unwrap());
+            split.set_particles(&single.get_particles());
+            // 1000 particles take 16 workgroups, here in rows of 3.
+            split.max_workgroups = 3;
+            for _ in 0..3 {
+                single.update().await;
+                split.update().await;
+            }
+            for (a, b) in single
+                .get_particles()
+                .iter()
+                .zip(split.get_particles().iter())
+            {
+                let distance = (a.pos[0] - b.pos[0]).hypot(a.pos[1] - b.pos[1]);
+                assert!(distance < 1e-3, "{a:?} vs {b:?}");
+            }
+        });
+    }
 }
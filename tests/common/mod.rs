@@ -0,0 +1,71 @@
+//! Scenes and checks shared by the integration tests.
+
+#![allow(clippy::needless_return, dead_code)]
+
+use particle_simulation::{
+    snapshot::Snapshot, Particle, ParticleTypeManager, SceneLike, SceneSettings,
+};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+// Not a multiple of the thread count or the workgroup size, so uneven
+// chunks are covered too.
+pub const PARTICLE_COUNT: usize = 203;
+pub const STEPS: usize = 5;
+
+pub fn initial_snapshot() -> Snapshot {
+    let settings = SceneSettings {
+        screen_size: [400, 300],
+        particle_count: PARTICLE_COUNT,
+        particle_types_count: 4,
+        seed: 1,
+        force_law: Default::default(),
+        neighbor_search: Default::default(),
+        substeps: 2,
+        dt: 0.5,
+        integrator: Default::default(),
+        boundary: Default::default(),
+        restitution: 1.0,
+    };
+    let mut random_source = ChaCha8Rng::seed_from_u64(42);
+    let particles = (0..PARTICLE_COUNT)
+        .map(|_| Particle {
+            pos: [
+                random_source.random_range(0.0..400.0),
+                random_source.random_range(0.0..300.0),
+            ],
+            vel: [
+                random_source.random_range(-1.0..1.0),
+                random_source.random_range(-1.0..1.0),
+            ],
+            type_index: random_source.random_range(0..4),
+        })
+        .collect();
+    return Snapshot {
+        settings,
+        rules: ParticleTypeManager::new(4, settings.seed)
+            .unwrap()
+            .to_rule_set(),
+        obstacles: Default::default(),
+        fields: Default::default(),
+        particles,
+    };
+}
+
+/// Checks that the next update of `scene` gives exactly the particles of a
+/// scene restarted from `expected`.
+pub async fn assert_continues_like<S: SceneLike>(scene: &mut S, expected: &Snapshot) {
+    let mut restarted: S = expected.restore().await.expect("Snapshot should restore");
+    scene.update().await;
+    restarted.update().await;
+    let state = |particles: &[Particle]| {
+        particles
+            .iter()
+            .map(|p| (p.pos, p.vel, p.type_index))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        state(&scene.get_particles()),
+        state(&restarted.get_particles())
+    );
+}
@@ -0,0 +1,427 @@
+//! Every backend stepped alongside the reference implementation of
+//! [`particle_simulation::conformance`].
+
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use clap::ValueEnum;
+use common::{initial_snapshot, PARTICLE_COUNT, STEPS};
+use particle_simulation::{
+    boundary::Boundary,
+    conformance::{check_backend, BackendReport, Tolerances},
+    field::FieldSet,
+    force_law::ForceLawKind,
+    integrator::Integrator,
+    neighbor_grid::{NeighborGrid, NeighborSearch},
+    obstacle::ObstacleSet,
+    snapshot::Snapshot,
+    MultithreadedScene, MultithreadedSceneV2, Particle, SceneLike, WgpuScene,
+};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+fn assert_passed(report: &BackendReport) {
+    assert!(
+        report.passed(),
+        "{} diverged from the reference: count mismatch {:?}, max position {}, max velocity {}, worst {:?}",
+        report.backend,
+        report.count_mismatch,
+        report.max_position(),
+        report.max_velocity(),
+        report.worst_particles(3)
+    );
+}
+
+const CPU_TOLERANCES: Tolerances = Tolerances {
+    position: 1e-9,
+    velocity: 1e-9,
+};
+
+#[test]
+fn multithreaded_scene_matches_reference() {
+    let report = pollster::block_on(check_backend::<MultithreadedScene>(
+        "multithreaded",
+        &initial_snapshot(),
+        STEPS,
+        CPU_TOLERANCES,
+    ));
+    assert_passed(&report);
+}
+
+#[test]
+fn multithreaded_scene_v2_matches_reference() {
+    let report = pollster::block_on(check_backend::<MultithreadedSceneV2>(
+        "multithreaded-v2",
+        &initial_snapshot(),
+        STEPS,
+        CPU_TOLERANCES,
+    ));
+    assert_passed(&report);
+}
+
+#[test]
+fn wgpu_scene_matches_reference() {
+    if !pollster::block_on(WgpuScene::is_available()) {
+        eprintln!("No GPU adapter, skipping");
+        return;
+    }
+    let report = pollster::block_on(check_backend::<WgpuScene>(
+        "wgpu",
+        &initial_snapshot(),
+        STEPS,
+        Tolerances::default(),
+    ));
+    assert_passed(&report);
+}
+
+/// Resizes the world to `cells` interaction cutoffs and scatters
+/// `particle_count` particles over it, most of them close to an edge so
+/// their neighbours wrap around it.
+fn spread_across_edges(initial: &mut Snapshot, cells: [f64; 2], particle_count: usize) {
+    let cutoff = initial
+        .particle_types()
+        .unwrap()
+        .get_max_interaction_distance();
+    let size = cells.map(|v| (v * cutoff).ceil());
+    initial.settings.screen_size = size.map(|v| v as u32);
+    initial.settings.particle_count = particle_count;
+    let mut random_source = ChaCha8Rng::seed_from_u64(7);
+    initial.particles = (0..particle_count)
+        .map(|i| {
+            let mut pos = size.map(|v| random_source.random_range(0.0..v));
+            // Two thirds hug a vertical or horizontal edge, some of them both.
+            for (axis, &length) in size.iter().enumerate() {
+                if i % 3 == axis || i % 9 == 2 {
+                    let depth = random_source.random_range(0.0..0.5 * cutoff);
+                    pos[axis] = match random_source.random_bool(0.5) {
+                        true => depth,
+                        false => length - depth,
+                    };
+                }
+            }
+            return Particle {
+                pos,
+                vel: [0, 1].map(|_| random_source.random_range(-1.0..1.0)),
+                type_index: random_source.random_range(0..4),
+            };
+        })
+        .collect();
+}
+
+/// A world six cutoffs wide and five high, so the grid has cells to skip.
+fn wide_snapshot() -> Snapshot {
+    let mut initial = initial_snapshot();
+    spread_across_edges(&mut initial, [6.0, 5.0], PARTICLE_COUNT);
+    return initial;
+}
+
+/// Steps the same scene with the grid and with brute force and compares the
+/// particles after every update.
+async fn grid_against_brute_force<S: SceneLike>(initial: &Snapshot, tolerance: f64) {
+    let restore = |search: NeighborSearch| {
+        let mut snapshot = initial.clone();
+        snapshot.settings.neighbor_search = search;
+        return async move { snapshot.restore::<S>().await.unwrap() };
+    };
+    let mut grid = restore(NeighborSearch::Grid).await;
+    let mut brute_force = restore(NeighborSearch::BruteForce).await;
+    for step in 0..STEPS {
+        grid.update().await;
+        brute_force.update().await;
+        let (a, b) = (grid.get_particles(), brute_force.get_particles());
+        assert_eq!(a.len(), b.len());
+        for (i, (a, b)) in a.iter().zip(b.iter()).enumerate() {
+            let position = (a.pos[0] - b.pos[0]).hypot(a.pos[1] - b.pos[1]);
+            let velocity = (a.vel[0] - b.vel[0]).hypot(a.vel[1] - b.vel[1]);
+            assert!(
+                position <= tolerance && velocity <= tolerance,
+                "grid and brute force diverged at step {step}, particle {i}: {a:?} vs {b:?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn grid_matches_brute_force_across_wrapped_edges() {
+    let initial = wide_snapshot();
+    let cutoff = initial
+        .particle_types()
+        .unwrap()
+        .get_max_interaction_distance();
+    let screen_size = initial.settings.screen_size.map(|v| v as f64);
+    assert!(NeighborGrid::dimensions(screen_size, cutoff)
+        .iter()
+        .all(|&cells| cells >= 3));
+    pollster::block_on(grid_against_brute_force::<MultithreadedScene>(
+        &initial, 1e-9,
+    ));
+    pollster::block_on(grid_against_brute_force::<MultithreadedSceneV2>(
+        &initial, 1e-9,
+    ));
+    assert_passed(&pollster::block_on(check_backend::<MultithreadedSceneV2>(
+        "wide grid",
+        &initial,
+        STEPS,
+        CPU_TOLERANCES,
+    )));
+    if pollster::block_on(WgpuScene::is_available()) {
+        pollster::block_on(grid_against_brute_force::<WgpuScene>(&initial, 1e-2));
+        assert_passed(&pollster::block_on(check_backend::<WgpuScene>(
+            "wide grid",
+            &initial,
+            STEPS,
+            Tolerances::default(),
+        )));
+    }
+}
+
+/// Rules scaled down to a 20 pixel cutoff on a 40x25 cell world. That is more
+/// cells than `prefix_sum` in binning.wgsl has threads, so each thread scans
+/// several cells.
+fn fine_grid_snapshot() -> Snapshot {
+    let mut initial = initial_snapshot();
+    let scale = 20.0
+        / initial
+            .particle_types()
+            .unwrap()
+            .get_max_interaction_distance();
+    for row in initial
+        .rules
+        .min_distances
+        .iter_mut()
+        .chain(&mut initial.rules.radii)
+    {
+        row.iter_mut().for_each(|distance| *distance *= scale);
+    }
+    spread_across_edges(&mut initial, [40.0, 25.0], 1000);
+    return initial;
+}
+
+#[test]
+fn wgpu_grid_with_many_cells_matches_reference() {
+    if !pollster::block_on(WgpuScene::is_available()) {
+        eprintln!("No GPU adapter, skipping");
+        return;
+    }
+    let initial = fine_grid_snapshot();
+    let cutoff = initial
+        .particle_types()
+        .unwrap()
+        .get_max_interaction_distance();
+    let screen_size = initial.settings.screen_size.map(|v| v as f64);
+    let [cells_x, cells_y] = NeighborGrid::dimensions(screen_size, cutoff);
+    assert!(cells_x * cells_y > 256);
+    pollster::block_on(grid_against_brute_force::<WgpuScene>(&initial, 1e-2));
+    assert_passed(&pollster::block_on(check_backend::<WgpuScene>(
+        "wgpu many cells",
+        &initial,
+        STEPS,
+        Tolerances::default(),
+    )));
+}
+
+#[test]
+fn wgpu_runs_are_reproducible() {
+    if !pollster::block_on(WgpuScene::is_available()) {
+        eprintln!("No GPU adapter, skipping");
+        return;
+    }
+    for search in [NeighborSearch::Grid, NeighborSearch::BruteForce] {
+        let mut initial = fine_grid_snapshot();
+        initial.settings.neighbor_search = search;
+        let run = || async {
+            let mut scene = initial.restore::<WgpuScene>().await.unwrap();
+            for _ in 0..STEPS {
+                scene.update().await;
+            }
+            return scene
+                .get_particles()
+                .iter()
+                .map(|p| (p.pos, p.vel))
+                .collect::<Vec<_>>();
+        };
+        let first = pollster::block_on(run());
+        let second = pollster::block_on(run());
+        assert!(
+            first == second,
+            "two {search:?} runs from the same snapshot diverged"
+        );
+    }
+}
+
+#[test]
+fn every_integrator_matches_reference() {
+    let gpu_available = pollster::block_on(WgpuScene::is_available());
+    for &integrator in Integrator::value_variants() {
+        let mut initial = initial_snapshot();
+        initial.settings.integrator = integrator;
+        assert_passed(&pollster::block_on(check_backend::<MultithreadedSceneV2>(
+            &format!("{integrator:?}"),
+            &initial,
+            STEPS,
+            CPU_TOLERANCES,
+        )));
+        if gpu_available {
+            assert_passed(&pollster::block_on(check_backend::<WgpuScene>(
+                &format!("{integrator:?}"),
+                &initial,
+                STEPS,
+                Tolerances::default(),
+            )));
+        }
+    }
+}
+
+#[test]
+fn every_force_law_matches_reference() {
+    let gpu_available = pollster::block_on(WgpuScene::is_available());
+    for &force_law in ForceLawKind::value_variants() {
+        let mut initial = initial_snapshot();
+        initial.settings.force_law = force_law;
+        assert_passed(&pollster::block_on(check_backend::<MultithreadedSceneV2>(
+            &format!("{force_law:?}"),
+            &initial,
+            STEPS,
+            CPU_TOLERANCES,
+        )));
+        if gpu_available {
+            assert_passed(&pollster::block_on(check_backend::<WgpuScene>(
+                &format!("{force_law:?}"),
+                &initial,
+                STEPS,
+                Tolerances::default(),
+            )));
+        }
+    }
+}
+
+#[test]
+fn every_boundary_matches_reference() {
+    let gpu_available = pollster::block_on(WgpuScene::is_available());
+    for &boundary in Boundary::value_variants() {
+        let mut initial = initial_snapshot();
+        initial.settings.boundary = boundary;
+        initial.settings.restitution = 0.8;
+        assert_passed(&pollster::block_on(check_backend::<MultithreadedSceneV2>(
+            &format!("{boundary:?}"),
+            &initial,
+            STEPS,
+            CPU_TOLERANCES,
+        )));
+        if gpu_available {
+            assert_passed(&pollster::block_on(check_backend::<WgpuScene>(
+                &format!("{boundary:?}"),
+                &initial,
+                STEPS,
+                Tolerances::default(),
+            )));
+        }
+    }
+}
+
+const OBSTACLES: &str = r#####"
+[[obstacles]]
+shape = "circle"
+center = [100.0, 100.0]
+radius = 40.0
+
+[[obstacles]]
+shape = "segment"
+start = [200.0, 0.0]
+end = [200.0, 300.0]
+thickness = 6.0
+permeable_to = [1, 3]
+
+[[obstacles]]
+shape = "polygon"
+points = [[250.0, 180.0], [350.0, 180.0], [300.0, 280.0], [300.0, 220.0]]
+
+[[obstacles]]
+shape = "mask"
+origin = [20.0, 200.0]
+cell_size = 10.0
+rows = ["####....##", "##......##", "##..######"]
+"#####;
+
+#[test]
+fn obstacles_match_reference() {
+    let mut initial = initial_snapshot();
+    initial.obstacles = ObstacleSet::from_toml(OBSTACLES).expect("Obstacles should parse");
+    initial.settings.restitution = 0.8;
+    assert_passed(&pollster::block_on(check_backend::<MultithreadedScene>(
+        "multithreaded",
+        &initial,
+        STEPS,
+        CPU_TOLERANCES,
+    )));
+    assert_passed(&pollster::block_on(check_backend::<MultithreadedSceneV2>(
+        "multithreaded-v2",
+        &initial,
+        STEPS,
+        CPU_TOLERANCES,
+    )));
+    if pollster::block_on(WgpuScene::is_available()) {
+        assert_passed(&pollster::block_on(check_backend::<WgpuScene>(
+            "wgpu",
+            &initial,
+            STEPS,
+            Tolerances::default(),
+        )));
+    }
+}
+
+const FIELDS: &str = r#"
+[[fields]]
+kind = "gravity"
+acceleration = [0.02, 0.05]
+response = [1.0, 0.0, -1.0]
+
+[[fields]]
+kind = "attractor"
+center = [380.0, 20.0]
+strength = 400.0
+radius = 150.0
+
+[[fields]]
+kind = "vortex"
+center = [150.0, 150.0]
+strength = -300.0
+softening = 5.0
+response = [2.0, 1.0, 1.0, 0.5]
+
+[[fields]]
+kind = "flow"
+origin = [50.0, 50.0]
+cell_size = 60.0
+strength = 0.2
+width = 3
+height = 2
+vectors = [[1.0, 0.0], [0.0, 1.0], [-1.0, 0.5], [0.5, 0.5], [0.0, -1.0], [1.0, 1.0]]
+"#;
+
+#[test]
+fn fields_match_reference() {
+    let mut initial = initial_snapshot();
+    initial.fields = FieldSet::from_toml(FIELDS).expect("Fields should parse");
+    assert_passed(&pollster::block_on(check_backend::<MultithreadedScene>(
+        "multithreaded",
+        &initial,
+        STEPS,
+        CPU_TOLERANCES,
+    )));
+    assert_passed(&pollster::block_on(check_backend::<MultithreadedSceneV2>(
+        "multithreaded-v2",
+        &initial,
+        STEPS,
+        CPU_TOLERANCES,
+    )));
+    if pollster::block_on(WgpuScene::is_available()) {
+        assert_passed(&pollster::block_on(check_backend::<WgpuScene>(
+            "wgpu",
+            &initial,
+            STEPS,
+            Tolerances::default(),
+        )));
+    }
+}
@@ -0,0 +1,253 @@
+//! Changes made to running scenes, checked on every backend against a scene
+//! restarted from the expected state.
+
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use common::{assert_continues_like, initial_snapshot, PARTICLE_COUNT, STEPS};
+use particle_simulation::{
+    boundary::Boundary, error::Error, force_law::ForceLawKind, integrator::Integrator,
+    scene_config::SceneConfig, MultithreadedScene, MultithreadedSceneV2, Particle, SceneLike,
+    WgpuScene,
+};
+
+async fn spawn_and_erase<S: SceneLike>() {
+    let initial = initial_snapshot();
+    let mut scene: S = initial.restore().await.expect("Snapshot should restore");
+    let center = [200.0, 150.0];
+    let inside = initial
+        .particles
+        .iter()
+        .filter(|p| (p.pos[0] - center[0]).hypot(p.pos[1] - center[1]) < 60.0)
+        .count();
+    assert!(inside > 0);
+    assert_eq!(scene.erase_particles(center, 60.0), inside);
+    let spawned = (0..10)
+        .map(|i| Particle {
+            pos: [10.0 * i as f64, 20.0],
+            vel: [0.0, 0.0],
+            type_index: i % 4,
+        })
+        .collect::<Vec<_>>();
+    scene.spawn_particles(&spawned);
+    scene.update().await;
+    let particles = scene.get_particles();
+    assert_eq!(particles.len(), PARTICLE_COUNT - inside + spawned.len());
+    assert_eq!(scene.get_settings().particle_count, particles.len());
+    let types = particles[particles.len() - spawned.len()..]
+        .iter()
+        .map(|p| p.type_index)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        types,
+        spawned.iter().map(|p| p.type_index).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn every_backend_spawns_and_erases() {
+    pollster::block_on(spawn_and_erase::<MultithreadedScene>());
+    pollster::block_on(spawn_and_erase::<MultithreadedSceneV2>());
+    if !pollster::block_on(WgpuScene::is_available()) {
+        return;
+    }
+    pollster::block_on(async {
+        spawn_and_erase::<WgpuScene>().await;
+        // Without readback the particles are picked where they are on the
+        // device, not where they were last read.
+        let mut drifting = initial_snapshot();
+        for particle in &mut drifting.particles {
+            particle.vel = [20.0, 0.0];
+        }
+        let mut scene = drifting.restore::<WgpuScene>().await.unwrap();
+        scene.set_readback(false);
+        for _ in 0..STEPS {
+            scene.update().await;
+        }
+        let center = [200.0, 150.0];
+        let erased = scene.erase_particles(center, 60.0);
+        scene.read_back().await;
+        let particles = scene.get_particles();
+        assert_eq!(particles.len(), PARTICLE_COUNT - erased);
+        assert!(particles
+            .iter()
+            .all(|p| (p.pos[0] - center[0]).hypot(p.pos[1] - center[1]) >= 60.0));
+    });
+}
+
+#[test]
+fn absorbed_particles_leave_the_gpu_scene_without_readback() {
+    if !pollster::block_on(WgpuScene::is_available()) {
+        return;
+    }
+    let mut initial = initial_snapshot();
+    initial.settings.boundary = Boundary::Absorbing;
+    for particle in &mut initial.particles {
+        particle.vel = [100.0, 0.0];
+    }
+    pollster::block_on(async {
+        let mut scene = initial.restore::<WgpuScene>().await.unwrap();
+        scene.set_readback(false);
+        scene.update().await;
+        let count = scene.get_settings().particle_count;
+        assert!(count < PARTICLE_COUNT);
+        assert_eq!(scene.get_particles().len(), count);
+    });
+}
+
+async fn configure<S: SceneLike>() {
+    let initial = initial_snapshot();
+    let config = SceneConfig::from_toml(
+        "dt = 0.25\nsubsteps = 3\nboundary = \"reflective\"\nrestitution = 0.5",
+    )
+    .expect("Config should parse");
+    let mut configured: S = initial.restore().await.expect("Snapshot should restore");
+    configured.configure(&config);
+    assert_eq!(configured.get_settings().boundary, Boundary::Reflective);
+    assert_eq!(configured.get_settings().substeps, 3);
+    let mut expected = initial_snapshot();
+    config.apply(&mut expected.settings);
+    assert_continues_like(&mut configured, &expected).await;
+}
+
+#[test]
+fn every_backend_applies_config_without_resetting() {
+    pollster::block_on(configure::<MultithreadedScene>());
+    pollster::block_on(configure::<MultithreadedSceneV2>());
+    if pollster::block_on(WgpuScene::is_available()) {
+        pollster::block_on(configure::<WgpuScene>());
+    }
+}
+
+async fn import_settings<S: SceneLike>() {
+    let initial = initial_snapshot();
+    let mut scene: S = initial.restore().await.expect("Snapshot should restore");
+    let mut expected = initial_snapshot();
+    expected.settings.dt = 0.25;
+    expected.settings.substeps = 3;
+    expected.settings.integrator = Integrator::VelocityVerlet;
+    expected.settings.boundary = Boundary::Reflective;
+    expected.settings.restitution = 0.5;
+    scene
+        .import_snapshot(&expected)
+        .expect("Snapshot should import");
+    assert_eq!(
+        SceneConfig::from_settings(&scene.get_settings()),
+        SceneConfig::from_settings(&expected.settings)
+    );
+    assert_continues_like(&mut scene, &expected).await;
+
+    let mut other_law = initial_snapshot();
+    other_law.settings.force_law = ForceLawKind::Gaussian;
+    assert!(matches!(
+        scene.import_snapshot(&other_law),
+        Err(Error::Mismatch(_))
+    ));
+}
+
+#[test]
+fn every_backend_imports_snapshot_settings() {
+    pollster::block_on(import_settings::<MultithreadedScene>());
+    pollster::block_on(import_settings::<MultithreadedSceneV2>());
+    if pollster::block_on(WgpuScene::is_available()) {
+        pollster::block_on(import_settings::<WgpuScene>());
+    }
+}
+
+/// Adds enough particles to outgrow the initial buffers and removes some
+/// after an update.
+async fn add_and_remove<S: SceneLike>(scene: &mut S) {
+    scene.update().await;
+    let mut expected = scene.get_particles().to_vec();
+    let added = (0..300)
+        .map(|i| Particle {
+            pos: [(i % 20) as f64 * 20.0, (i / 20) as f64 * 20.0],
+            vel: [0.5, -0.5],
+            type_index: i % 4,
+        })
+        .collect::<Vec<_>>();
+    scene.spawn_particles(&added);
+    expected.extend_from_slice(&added);
+    let removed = [0, 1, 2, 50, 202, 203, 400, 502, 9999];
+    scene.remove_particles(&removed);
+    let mut index = 0;
+    expected.retain(|_| {
+        index += 1;
+        return !removed.contains(&(index - 1));
+    });
+    assert_eq!(scene.get_settings().particle_count, expected.len());
+
+    let mut snapshot = initial_snapshot();
+    snapshot.particles = expected;
+    assert_continues_like(scene, &snapshot).await;
+}
+
+#[test]
+fn every_backend_adds_and_removes_particles() {
+    let initial = initial_snapshot();
+    pollster::block_on(async {
+        add_and_remove(&mut initial.restore::<MultithreadedScene>().await.unwrap()).await;
+        add_and_remove(&mut initial.restore::<MultithreadedSceneV2>().await.unwrap()).await;
+        if !WgpuScene::is_available().await {
+            return;
+        }
+        add_and_remove(&mut initial.restore::<WgpuScene>().await.unwrap()).await;
+        // Without readback, the particles only change on the device.
+        let mut scene = initial.restore::<WgpuScene>().await.unwrap();
+        scene.set_readback(false);
+        scene.update().await;
+        scene.read_back().await;
+        let before = scene.get_particles();
+        scene.spawn_particles(&[Particle::new(); 500]);
+        scene.remove_particles(&[0]);
+        scene.read_back().await;
+        let after = scene.get_particles();
+        assert_eq!(after.len(), before.len() + 499);
+        assert_eq!(after[0].pos, before[1].pos);
+        assert_eq!(after[before.len() - 2].pos, before[before.len() - 1].pos);
+    });
+}
+
+async fn move_some<S: SceneLike>(scene: &mut S) {
+    scene.update().await;
+    let moves = [
+        (3, [12.5, 40.0], [0.25, -0.5]),
+        (150, [380.0, 0.5], [-1.0, 1.0]),
+        (9999, [1.0, 1.0], [0.0, 0.0]),
+    ];
+    scene.move_particles(&moves);
+    let mut snapshot = initial_snapshot();
+    snapshot.particles = scene.get_particles().to_vec();
+    for (i, pos, vel) in &moves[..2] {
+        assert_eq!(
+            (snapshot.particles[*i].pos, snapshot.particles[*i].vel),
+            (*pos, *vel)
+        );
+    }
+    assert_continues_like(scene, &snapshot).await;
+}
+
+#[test]
+fn every_backend_moves_particles() {
+    let initial = initial_snapshot();
+    pollster::block_on(async {
+        move_some(&mut initial.restore::<MultithreadedScene>().await.unwrap()).await;
+        move_some(&mut initial.restore::<MultithreadedSceneV2>().await.unwrap()).await;
+        if !WgpuScene::is_available().await {
+            return;
+        }
+        move_some(&mut initial.restore::<WgpuScene>().await.unwrap()).await;
+        // Without readback only the moved particles are written to the device.
+        let mut scene = initial.restore::<WgpuScene>().await.unwrap();
+        scene.set_readback(false);
+        scene.update().await;
+        scene.read_back().await;
+        let before = scene.get_particles();
+        scene.move_particles(&[(7, [10.0, 20.0], [0.5, 0.5])]);
+        scene.read_back().await;
+        let after = scene.get_particles();
+        assert_eq!((after[7].pos, after[7].vel), ([10.0, 20.0], [0.5, 0.5]));
+        assert_eq!(after[8].pos, before[8].pos);
+    });
+}